@@ -1,7 +1,6 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvsEngine, LsmKvsEngine, SledKvsEngine};
 use rand::prelude::*;
-use sled;
 use tempfile::TempDir;
 
 
@@ -13,8 +12,8 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
            },
-           |(mut store, _temp_dir)| {
-               for i in i..(1<<12) {
+           |(store, _temp_dir)| {
+               for i in 1..(1<<12) {
                    store.set(format!("key{}",i), "value".to_string()).unwrap();
                }
            },
@@ -28,7 +27,7 @@ fn set_bench(c: &mut Criterion) {
                let temp_dir = TempDir::new().unwrap();
                (SledKvsEngine::new(sled::open(&temp_dir).unwrap()), temp_dir)
            },
-           |(mut db, _temp_dir)| {
+           |(db, _temp_dir)| {
                for i in 1..(1 << 12) {
                    db.set(format!("key{}", i), "value".to_string()).unwrap();
 
@@ -39,31 +38,46 @@ fn set_bench(c: &mut Criterion) {
 
     });
 
+    group.bench_function("lsm", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (LsmKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(engine, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    engine.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
     group.finish();
 }
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-    for i in &vec![8,12,16,20] {
+    for i in &[8,12,16,20] {
         group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
            let temp_dir = TempDir::new().unwrap();
-           let mut store = KvStore::open(temp_dir.path()).unwrap();
+           let store = KvStore::open(temp_dir.path()).unwrap();
 
             for key_i in 1..(1<<i) {
                 store.set(format!("key{}", key_i), "value".to_string()).unwrap();
             }
 
-            let mut rng = SmallRng::from_seed([0, 16]);
+            let mut rng = SmallRng::from_seed([0; 16]);
             b.iter(|| {
                 store.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
             })
         });
     }
 
-    for i in &vec![9,12,16,20] {
+    for i in &[9,12,16,20] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
+            let db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
             for key_i in  1..(1<<i) {
                 db.set(format!("key{}", key_i), "value".to_string()).unwrap()
             }
@@ -73,10 +87,23 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
+    for i in &[8, 12, 16, 20] {
+        group.bench_with_input(format!("lsm_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let engine = LsmKvsEngine::open(temp_dir.path()).unwrap();
+            for key_i in 1..(1 << i) {
+                engine.set(format!("key{}", key_i), "value".to_string()).unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                engine.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
+            })
+        });
+    }
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench());
+criterion_group!(benches, set_bench, get_bench);
 criterion_main!(benches);
 
 
@@ -0,0 +1,5 @@
+//! Replays arbitrary bytes as a log file.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|log: &[u8]| kvs::fuzzing::replay_log(log));
@@ -0,0 +1,16 @@
+//! Reads a record at an arbitrary position of an arbitrary log file. The
+//! first 16 bytes give the position and the length of the record.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryInto;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 16 {
+        return;
+    }
+    let (pos, rest) = data.split_at(8);
+    let (len, log) = rest.split_at(8);
+    let pos = u64::from_le_bytes(pos.try_into().unwrap());
+    let len = u64::from_le_bytes(len.try_into().unwrap());
+    kvs::fuzzing::read_record(log, pos, len);
+});
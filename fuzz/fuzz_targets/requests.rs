@@ -0,0 +1,5 @@
+//! Serves arbitrary bytes as the requests of a connection.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: &[u8]| kvs::fuzzing::serve_requests(input));
@@ -0,0 +1,5 @@
+//! Decodes arbitrary bytes as the responses of a connection.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: &[u8]| kvs::fuzzing::decode_responses(input));
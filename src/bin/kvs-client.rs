@@ -1,6 +1,10 @@
 
-use kvs::{KvsClient, Result};
+use clap::AppSettings;
+use kvs::{ChangeKind, KvsClient, KvsError, Result, VersionSpec};
+use serde_json::Value;
+use std::ops::Bound;
 use structopt::StructOpt;
+use std::io::{self, BufRead};
 use std::net::SocketAddr;
 use std::process::exit;
 
@@ -9,11 +13,11 @@ const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const ADDRESS_FORMAT: &str = "IP:PORT";
 
 
-#[derive(Structopt, Debug)]
+#[derive(StructOpt, Debug)]
 #[structopt(
     name = "kvs-client",
     raw(global_settings = "&[\
-                               AppSettings:DisableHelpSubcommand,\
+                               AppSettings::DisableHelpSubcommand,\
                                AppSettings::VersionlessSubcommands]")
 )]
 struct Opt {
@@ -21,9 +25,9 @@ struct Opt {
     command: Command,
 }
 
-#[derive(Structopt, Debug)]
+#[derive(StructOpt, Debug)]
 enum Command {
-    #[structopt(name = "get"), about = "Get the string value of a given string key"]
+    #[structopt(name = "get", about = "Get the string value of a given string key")]
     Get {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
@@ -35,8 +39,23 @@ enum Command {
             parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the key")]
+        keyspace: Option<String>,
+        #[structopt(
+            long,
+            value_name = "NUMBER",
+            conflicts_with = "at",
+            help = "Gets a kept version of the value"
+        )]
+        version: Option<u64>,
+        #[structopt(
+            long,
+            value_name = "MILLIS",
+            help = "Gets the value at a time, in milliseconds since the Unix epoch"
+        )]
+        at: Option<u64>,
     },
-    #[structopt(name = "set"), about = "Set the value of a string key to a string"]
+    #[structopt(name = "set", about = "Set the value of a string key to a string")]
     Set {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
@@ -50,6 +69,8 @@ enum Command {
             parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the key")]
+        keyspace: Option<String>,
     },
     #[structopt(name = "rm", about = "Remove a given string key")]
     Remove {
@@ -63,6 +84,280 @@ enum Command {
             parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the key")]
+        keyspace: Option<String>,
+    },
+    #[structopt(name = "rm-range", about = "Remove the keys of a range")]
+    RemoveRange {
+        #[structopt(name = "START", help = "The first key of the range")]
+        start: String,
+        #[structopt(name = "END", help = "The key the range ends before")]
+        end: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the keys")]
+        keyspace: Option<String>,
+    },
+    #[structopt(name = "rm-prefix", about = "Remove the keys starting with a prefix")]
+    RemovePrefix {
+        #[structopt(name = "PREFIX", help = "A key prefix")]
+        prefix: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the keys")]
+        keyspace: Option<String>,
+    },
+    #[structopt(
+        name = "incr",
+        about = "Add an integer to the integer value of a key",
+        raw(setting = "AppSettings::AllowNegativeNumbers")
+    )]
+    Incr {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            name = "DELTA",
+            help = "The integer to add, may be negative",
+            default_value = "1"
+        )]
+        delta: i64,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the key")]
+        keyspace: Option<String>,
+    },
+    #[structopt(name = "versions", about = "List the kept versions of a key, newest first")]
+    Versions {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the key")]
+        keyspace: Option<String>,
+    },
+    #[structopt(
+        name = "watch",
+        about = "Print the changes of the keys starting with a prefix as they happen",
+        after_help = "Each change is printed as `SEQ\tset\tKEY\tVALUE` or `SEQ\trm\tKEY`."
+    )]
+    Watch {
+        #[structopt(name = "PREFIX", help = "A key prefix", default_value = "")]
+        prefix: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the keys")]
+        keyspace: Option<String>,
+    },
+    #[structopt(
+        name = "changes",
+        about = "Print the changes written after a sequence number",
+        after_help = "Each change is printed as `SEQ\tset\tKEY\tVALUE`, `SEQ\trm\tKEY`, \
+                      `SEQ\tmerge\tKEY\tOPERAND` or `SEQ\trm-range\tSTART\tEND`, where \
+                      the bounds of a range are written as `[KEY`, `(KEY`, `KEY]`, `KEY)` \
+                      or `*`."
+    )]
+    Changes {
+        #[structopt(
+            long,
+            value_name = "SEQ",
+            help = "Sets the sequence number to start after",
+            default_value = "0"
+        )]
+        since: u64,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the keys")]
+        keyspace: Option<String>,
+    },
+    #[structopt(
+        name = "txn",
+        about = "Run the commands read from stdin in a transaction",
+        after_help = "Each line holds one of `get KEY`, `set KEY VALUE`, `rm KEY`, \
+                      `commit` and `rollback`. The transaction commits at the end of \
+                      the input."
+    )]
+    Transaction {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the keys")]
+        keyspace: Option<String>,
+    },
+    #[structopt(
+        name = "create-index",
+        about = "Create a secondary index over a field of the JSON values"
+    )]
+    CreateIndex {
+        #[structopt(name = "NAME", help = "The name of the index")]
+        name: String,
+        #[structopt(name = "POINTER", help = "The JSON pointer to the field, like /address/city")]
+        pointer: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the keys")]
+        keyspace: Option<String>,
+    },
+    #[structopt(name = "drop-index", about = "Drop a secondary index")]
+    DropIndex {
+        #[structopt(name = "NAME", help = "The name of the index")]
+        name: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the keys")]
+        keyspace: Option<String>,
+    },
+    #[structopt(
+        name = "indexes",
+        about = "List the secondary indexes",
+        after_help = "Each index is printed as `NAME\tPOINTER`."
+    )]
+    Indexes {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the keys")]
+        keyspace: Option<String>,
+    },
+    #[structopt(
+        name = "index-get",
+        about = "List the keys whose indexed field equals a value",
+        after_help = "VALUE is read as JSON, or as a string if it is not JSON. Each key is \
+                      printed as `KEY\tVALUE`."
+    )]
+    IndexGet {
+        #[structopt(name = "NAME", help = "The name of the index")]
+        name: String,
+        #[structopt(name = "VALUE", help = "The value of the field")]
+        value: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the keys")]
+        keyspace: Option<String>,
+    },
+    #[structopt(
+        name = "index-range",
+        about = "List the keys whose indexed field lies in a range",
+        after_help = "Values are read as JSON, or as strings if they are not JSON. Each key is \
+                      printed as `KEY\tVALUE`, ordered by field."
+    )]
+    IndexRange {
+        #[structopt(name = "NAME", help = "The name of the index")]
+        name: String,
+        #[structopt(long, value_name = "VALUE", help = "Sets the first field of the range")]
+        start: Option<String>,
+        #[structopt(long, value_name = "VALUE", help = "Sets the field the range ends before")]
+        end: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(long, value_name = "NAME", help = "Sets the keyspace of the keys")]
+        keyspace: Option<String>,
+    },
+    #[structopt(name = "create-keyspace", about = "Create a keyspace")]
+    CreateKeyspace {
+        #[structopt(name = "NAME", help = "The name of the keyspace")]
+        name: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "drop-keyspace", about = "Drop a keyspace and all its keys")]
+    DropKeyspace {
+        #[structopt(name = "NAME", help = "The name of the keyspace")]
+        name: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "keyspaces", about = "List the keyspaces")]
+    Keyspaces {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
     },
 }
 
@@ -77,30 +372,174 @@ fn main() {
 
 fn run(opt: Opt) -> Result<()> {
     match opt.command {
-        Command::Get {key, addr} => {
+        Command::Get {key, addr, keyspace, version, at} => {
             let mut client = KvsClient::connect(addr)?;
-            if let Some(value) = client.get(key)? {
+            client.set_keyspace(keyspace);
+            let at = version.map(VersionSpec::Version).or(at.map(VersionSpec::Timestamp));
+            let value = match at {
+                Some(at) => client.get_at(key, at)?,
+                None => client.get(key)?,
+            };
+            if let Some(value) = value {
                 println!("{}",value);
             } else {
-                println!("key not found");
+                println!("Key not found");
             }
         }
-        Command::Set {key, value, addr} => {
+        Command::Set {key, value, addr, keyspace} => {
             let mut client = KvsClient::connect(addr)?;
-
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
-            }
+            client.set_keyspace(keyspace);
+            client.set(key, value)?;
         }
-        Command::Remove { key, addr} => {
+        Command::Remove { key, addr, keyspace} => {
             let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
             client.remove(key)?;
         }
+        Command::RemoveRange { start, end, addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            client.remove_range(start, end)?;
+        }
+        Command::RemovePrefix { prefix, addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            client.remove_prefix(prefix)?;
+        }
+        Command::Incr { key, delta, addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            println!("{}", client.incr(key, delta)?);
+        }
+        Command::Versions { key, addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            for version in client.get_versions(key)? {
+                println!("{}\t{}\t{}", version.version, version.timestamp, version.value);
+            }
+        }
+        Command::Watch { prefix, addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            for event in client.watch(prefix)? {
+                let event = event?;
+                match event.value {
+                    Some(value) => println!("{}\tset\t{}\t{}", event.seq, event.key, value),
+                    None => println!("{}\trm\t{}", event.seq, event.key),
+                }
+            }
+        }
+        Command::Changes { since, addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            for change in client.changes_since(since)? {
+                let change = change?;
+                match change.kind {
+                    ChangeKind::Set { key, value } => println!("{}\tset\t{}\t{}", change.seq, key, value),
+                    ChangeKind::Remove { key } => println!("{}\trm\t{}", change.seq, key),
+                    ChangeKind::Merge { key, operand } => {
+                        println!("{}\tmerge\t{}\t{}", change.seq, key, operand)
+                    }
+                    ChangeKind::RemoveRange { start, end } => {
+                        let start = match start {
+                            Bound::Included(key) => format!("[{}", key),
+                            Bound::Excluded(key) => format!("({}", key),
+                            Bound::Unbounded => "*".to_owned(),
+                        };
+                        let end = match end {
+                            Bound::Included(key) => format!("{}]", key),
+                            Bound::Excluded(key) => format!("{})", key),
+                            Bound::Unbounded => "*".to_owned(),
+                        };
+                        println!("{}\trm-range\t{}\t{}", change.seq, start, end)
+                    }
+                }
+            }
+        }
+        Command::Transaction { addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            run_transaction(&mut client, io::stdin().lock())?;
+        }
+        Command::CreateIndex { name, pointer, addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            client.create_index(name, pointer)?;
+        }
+        Command::DropIndex { name, addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            client.drop_index(name)?;
+        }
+        Command::Indexes { addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            for index in client.indexes()? {
+                println!("{}\t{}", index.name, index.pointer);
+            }
+        }
+        Command::IndexGet { name, value, addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            for (key, value) in client.index_get(name, parse_field(&value))? {
+                println!("{}\t{}", key, value);
+            }
+        }
+        Command::IndexRange { name, start, end, addr, keyspace } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_keyspace(keyspace);
+            let start = start.map_or(Bound::Unbounded, |start| Bound::Included(parse_field(&start)));
+            let end = end.map_or(Bound::Unbounded, |end| Bound::Excluded(parse_field(&end)));
+            for (key, value) in client.index_scan(name, start, end)? {
+                println!("{}\t{}", key, value);
+            }
+        }
+        Command::CreateKeyspace { name, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.create_keyspace(name)?;
+        }
+        Command::DropKeyspace { name, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.drop_keyspace(name)?;
+        }
+        Command::Keyspaces { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            for name in client.keyspaces()? {
+                println!("{}", name);
+            }
+        }
     }
     Ok(())
 }
 
+/// Reads a field value given on the command line as JSON, falling back to a
+/// string.
+fn parse_field(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_owned()))
+}
 
-
+/// Runs the commands of `input` in a transaction, committing at the end
+/// unless one of them ends it first.
+fn run_transaction(client: &mut KvsClient, input: impl BufRead) -> Result<()> {
+    client.begin()?;
+    for line in input.lines() {
+        let line = line?;
+        let mut words = line.trim().splitn(3, ' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some(""), None, None) => {}
+            (Some("get"), Some(key), None) => match client.get(key.to_owned())? {
+                Some(value) => println!("{}", value),
+                None => println!("Key not found"),
+            },
+            (Some("set"), Some(key), Some(value)) => client.set(key.to_owned(), value.to_owned())?,
+            (Some("rm"), Some(key), None) => client.remove(key.to_owned())?,
+            (Some("commit"), None, None) => return client.commit(),
+            (Some("rollback"), None, None) => return client.rollback(),
+            _ => {
+                client.rollback()?;
+                return Err(KvsError::StringError(format!("Invalid command: {}", line)));
+            }
+        }
+    }
+    client.commit()
+}
@@ -6,7 +6,9 @@ use log::{error, info, warn};
 use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
 
 
@@ -31,6 +33,38 @@ struct Opt {
     raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+    long = "compaction-rate",
+    help = "Limits compaction I/O of the kvs engine, 0 (the default) means unlimited",
+    value_name = "BYTES-PER-SEC"
+    )]
+    compaction_rate: Option<u64>,
+    #[structopt(
+    long = "keep-versions",
+    help = "Keeps the newest versions of every key with the kvs engine, 1 by default",
+    value_name = "COUNT"
+    )]
+    keep_versions: Option<usize>,
+    #[structopt(
+    long,
+    help = "Splits the keys by hash across engines in subdirectories; transactions need a single shard",
+    value_name = "COUNT",
+    default_value = "1"
+    )]
+    shards: usize,
+    #[structopt(
+    long,
+    help = "Keeps the data of the memory engine in FILE of its directory across restarts",
+    value_name = "FILE",
+    parse(from_os_str)
+    )]
+    snapshot: Option<PathBuf>,
+    #[structopt(
+    long = "max-bytes",
+    help = "Limits the size of the keys and values the memory engine holds",
+    value_name = "BYTES"
+    )]
+    max_bytes: Option<u64>,
 }
 
 arg_enum! {
@@ -38,7 +72,9 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        memory,
+        lsm
     }
 }
 
@@ -68,17 +104,111 @@ fn run(opt: Opt) -> Result<()> {
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
+    check_engine_options(&opt, engine)?;
 
     fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
 
     match engine {
-        Engine::kvs => run_with_engine(KvStore::open(current_dir()?)?, opt.addr),
-        Engine::sled => run_with_engine(SledKvsEngine::new(sled::open(current_dir()?)?), opt.addr),
+        Engine::kvs => {
+            let options = KvStoreOptions::default()
+                .rate_limiter(RateLimiter::new(opt.compaction_rate.unwrap_or(0)))
+                .keep_versions(opt.keep_versions.unwrap_or(1));
+            run_with_shards(
+                |path| KvStore::open_with_options(path, options.clone()),
+                opt.shards,
+                opt.addr,
+            )
+        }
+        Engine::sled => run_with_shards(
+            |path| Ok(SledKvsEngine::new(sled::open(path)?)),
+            opt.shards,
+            opt.addr,
+        ),
+        Engine::memory => {
+            let snapshots = Arc::new(Mutex::new(Vec::new()));
+            if opt.snapshot.is_some() {
+                snapshot_on_shutdown(Arc::clone(&snapshots))?;
+            }
+            let open = |path: &Path| {
+                let engine = match &opt.snapshot {
+                    Some(file) => {
+                        let path = path.join(file);
+                        let engine = MemoryKvsEngine::with_snapshot(&path, opt.max_bytes)?;
+                        snapshots.lock().unwrap().push((engine.clone(), path));
+                        engine
+                    }
+                    None => match opt.max_bytes {
+                        Some(bytes) => MemoryKvsEngine::with_size_limit(bytes),
+                        None => MemoryKvsEngine::new(),
+                    },
+                };
+                Ok(engine)
+            };
+            run_with_shards(open, opt.shards, opt.addr)
+        }
+        Engine::lsm => run_with_shards(|path| LsmKvsEngine::open(path), opt.shards, opt.addr),
+    }
+}
+
+/// Fails if `opt` sets an option of another engine than `engine`, rather
+/// than ignoring it.
+fn check_engine_options(opt: &Opt, engine: Engine) -> Result<()> {
+    let options = [
+        ("--compaction-rate", opt.compaction_rate.is_some(), Engine::kvs),
+        ("--keep-versions", opt.keep_versions.is_some(), Engine::kvs),
+        ("--snapshot", opt.snapshot.is_some(), Engine::memory),
+        ("--max-bytes", opt.max_bytes.is_some(), Engine::memory),
+    ];
+    for &(option, set, owner) in &options {
+        if set && engine != owner {
+            return Err(KvsError::StringError(format!(
+                "{} only applies to the {} engine, not {}",
+                option, owner, engine
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the snapshots of the memory engines in `snapshots` and exits once
+/// the server is interrupted or terminated, as the engines are never dropped
+/// then.
+fn snapshot_on_shutdown(snapshots: Arc<Mutex<Vec<(MemoryKvsEngine, PathBuf)>>>) -> Result<()> {
+    let handler = move || {
+        let mut failed = false;
+        for (engine, path) in snapshots.lock().unwrap().iter() {
+            if let Err(e) = engine.snapshot(path) {
+                error!("Failed to snapshot to {}: {}", path.display(), e);
+                failed = true;
+            }
+        }
+        info!("Shutting down");
+        exit(if failed { 1 } else { 0 });
+    };
+    ctrlc::set_handler(handler).map_err(|e| KvsError::StringError(e.to_string()))
+}
+
+/// Opens the engine in the current directory, or one engine per shard if
+/// more than one shard is requested or the directory is already sharded.
+fn run_with_shards<E, F>(open: F, shards: usize, addr: SocketAddr) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = current_dir()?;
+    // the shard count file written by `ShardedEngine`
+    let sharded = dir.join("shards").exists();
+    if shards > 1 || sharded {
+        info!("Shards: {}", shards);
+        run_with_engine(ShardedEngine::open(dir, shards, open)?, addr)
+    } else {
+        run_with_engine(open(&dir)?, addr)
     }
 }
 
 fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = kvs::KvsServer::new(engine);
+    let pool = RayonThreadPool::new(num_cpus::get() as u32)?;
+    let server = KvsServer::new(engine, pool);
     server.run(addr)
 }
 
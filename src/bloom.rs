@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+
+/// A bloom filter over byte string keys.
+///
+/// The hash function is part of the persisted format, so it is implemented
+/// here instead of relying on `std`'s unspecified `DefaultHasher`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Builds a filter holding the keys of `hashes`, sized so that lookups of
+    /// absent keys are false positives with about `false_positive_rate`.
+    pub(crate) fn build(hashes: &[u64], false_positive_rate: f64) -> BloomFilter {
+        let false_positive_rate = false_positive_rate.clamp(1e-9, 0.5);
+        let items = hashes.len().max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-items * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let num_hashes = ((num_bits / items) * ln2).round().clamp(1.0, 30.0) as u32;
+
+        let mut filter = BloomFilter {
+            bits: vec![0; (num_bits as usize).div_ceil(64)],
+            num_hashes,
+        };
+        for &hash in hashes {
+            for bit in filter.bit_positions(hash) {
+                filter.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    /// Returns `false` if the key with `hash` was definitely not added.
+    pub(crate) fn may_contain(&self, hash: u64) -> bool {
+        self.bit_positions(hash)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Returns how many bytes the bits of the filter take.
+    pub(crate) fn memory_usage(&self) -> u64 {
+        (self.bits.len() * 8) as u64
+    }
+
+    /// Hashes a key for `build` and `may_contain`.
+    pub(crate) fn hash(key: &[u8]) -> u64 {
+        // 64-bit FNV-1a
+        key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = u64> {
+        // double hashing, the second hash is derived with the splitmix64 finalizer
+        let num_bits = self.bits.len() as u64 * 64;
+        let mut delta = hash;
+        delta = (delta ^ (delta >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        delta = (delta ^ (delta >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        delta = (delta ^ (delta >> 31)) | 1;
+        (0..u64::from(self.num_hashes))
+            .map(move |i| hash.wrapping_add(i.wrapping_mul(delta)) % num_bits)
+    }
+}
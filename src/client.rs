@@ -1,15 +1,27 @@
-use crate::{KvsError, Result};
+use crate::{Change, IndexDefinition, KeyVersion, KvsError, Result, VersionSpec, WatchEvent};
 
 
 use serde_json::de::IoRead;
-use std::io::{BufRead, BufWriter, BufReader, Write};
+use std::io::{BufWriter, BufReader, Write};
 use std::net::{TcpStream, ToSocketAddrs};
 use serde_json::Deserializer;
-use crate::common::{Request, GetResponse, RemoveResponse};
+use crate::common::{
+    GetResponse, IncrResponse, KeyspaceResponse, ListKeyspacesResponse, RemoveResponse, Request, SetResponse,
+    TransactionResponse, VersionsResponse, WatchResponse, ChangesResponse, IndexResponse,
+    IndexScanResponse, ListIndexesResponse, LimitedReader, MessageLimit,
+};
+use serde_json::Value;
+use std::ops::Bound;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufRead<TcpStream>>>,
+    reader: Deserializer<IoRead<LimitedReader<BufReader<TcpStream>>>>,
+    // starts each response over with the full length allowed
+    limit: MessageLimit,
     writer: BufWriter<TcpStream>,
+    // the keyspace of get, set and remove requests, the default one if `None`
+    keyspace: Option<String>,
 }
 
 
@@ -17,32 +29,48 @@ impl KvsClient {
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
         let tcp_reader = TcpStream::connect(addr)?;
         let tcp_writer = tcp_reader.try_clone()?;
+        let (tcp_reader, limit) = LimitedReader::new(BufReader::new(tcp_reader));
 
         Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
+            reader: Deserializer::from_reader(tcp_reader),
+            limit,
             writer: BufWriter::new(tcp_writer),
+            keyspace: None,
         })
     }
 
+    /// Reads the next response from the server.
+    fn read_response<T: DeserializeOwned>(&mut self) -> Result<T> {
+        self.limit.reset();
+        Ok(T::deserialize(&mut self.reader)?)
+    }
+
+    /// Directs the following get, set and remove requests to the keyspace
+    /// `keyspace`, or to the default keyspace if it is `None`.
+    pub fn set_keyspace(&mut self, keyspace: Option<String>) {
+        self.keyspace = keyspace;
+    }
+
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get {key})?;
+        serde_json::to_writer(&mut self.writer, &Request::Get { key, keyspace: self.keyspace.clone() })?;
         self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+        let resp = self.read_response::<GetResponse>()?;
         match resp {
             GetResponse::Ok(value) => Ok(value),
+            GetResponse::Conflict(msg) => Err(KvsError::TransactionConflict(msg)),
             GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value})?;
+        serde_json::to_writer(&mut self.writer, &Request::Set { key, value, keyspace: self.keyspace.clone() })?;
         self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+        let resp = self.read_response::<SetResponse>()?;
 
         match resp {
-            GetResponse::Ok(val) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
 
     }
@@ -50,18 +78,242 @@ impl KvsClient {
 
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self, &Request::Remove {key})?;
+        serde_json::to_writer(&mut self.writer, &Request::Remove { key, keyspace: self.keyspace.clone() })?;
         self.writer.flush()?;
 
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
+        let resp = self.read_response::<RemoveResponse>()?;
         match resp {
             RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Conflict(msg) => Err(KvsError::TransactionConflict(msg)),
+            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Removes the keys from `start` up to, not including, `end`.
+    pub fn remove_range(&mut self, start: String, end: String) -> Result<()> {
+        let keyspace = self.keyspace.clone();
+        serde_json::to_writer(&mut self.writer, &Request::RemoveRange { start, end, keyspace })?;
+        self.writer.flush()?;
+        match self.read_response::<RemoveResponse>()? {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Conflict(msg) => Err(KvsError::TransactionConflict(msg)),
+            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Removes the keys starting with `prefix`.
+    pub fn remove_prefix(&mut self, prefix: String) -> Result<()> {
+        let keyspace = self.keyspace.clone();
+        serde_json::to_writer(&mut self.writer, &Request::RemovePrefix { prefix, keyspace })?;
+        self.writer.flush()?;
+        match self.read_response::<RemoveResponse>()? {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Conflict(msg) => Err(KvsError::TransactionConflict(msg)),
             RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
 
+    /// Atomically adds `delta` to the integer value of `key` and returns the
+    /// new value.
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let keyspace = self.keyspace.clone();
+        serde_json::to_writer(&mut self.writer, &Request::Incr { key, delta, keyspace })?;
+        self.writer.flush()?;
+        match self.read_response::<IncrResponse>()? {
+            IncrResponse::Ok(value) => Ok(value),
+            IncrResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Atomically subtracts `delta` from the integer value of `key` and
+    /// returns the new value.
+    pub fn decr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let keyspace = self.keyspace.clone();
+        serde_json::to_writer(&mut self.writer, &Request::Decr { key, delta, keyspace })?;
+        self.writer.flush()?;
+        match self.read_response::<IncrResponse>()? {
+            IncrResponse::Ok(value) => Ok(value),
+            IncrResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Returns the versions of `key` the server keeps, newest first.
+    pub fn get_versions(&mut self, key: String) -> Result<Vec<KeyVersion>> {
+        serde_json::to_writer(&mut self.writer, &Request::GetVersions { key, keyspace: self.keyspace.clone() })?;
+        self.writer.flush()?;
+        match self.read_response::<VersionsResponse>()? {
+            VersionsResponse::Ok(versions) => Ok(versions),
+            VersionsResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Returns the value of `key` at `at`.
+    pub fn get_at(&mut self, key: String, at: VersionSpec) -> Result<Option<String>> {
+        serde_json::to_writer(&mut self.writer, &Request::GetAt { key, at, keyspace: self.keyspace.clone() })?;
+        self.writer.flush()?;
+        match self.read_response::<GetResponse>()? {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Conflict(msg) => Err(KvsError::TransactionConflict(msg)),
+            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Begins a transaction on the current keyspace. The following get, set
+    /// and remove requests go to it until `commit` or `rollback`, and fail if
+    /// the keyspace is changed meanwhile. Any other request fails until then.
+    ///
+    /// Gets see the keyspace as of `begin`, and `commit` fails with
+    /// `KvsError::TransactionConflict` if a key read has been written since.
+    pub fn begin(&mut self) -> Result<()> {
+        self.transaction_request(&Request::Begin { keyspace: self.keyspace.clone() })
+    }
+
+    /// Commits the open transaction.
+    pub fn commit(&mut self) -> Result<()> {
+        self.transaction_request(&Request::Commit)
+    }
+
+    /// Discards the writes of the open transaction.
+    pub fn rollback(&mut self) -> Result<()> {
+        self.transaction_request(&Request::Rollback)
+    }
+
+    fn transaction_request(&mut self, request: &Request) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, request)?;
+        self.writer.flush()?;
+        match self.read_response::<TransactionResponse>()? {
+            TransactionResponse::Ok(_) => Ok(()),
+            TransactionResponse::Conflict(msg) => Err(KvsError::TransactionConflict(msg)),
+            TransactionResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Watches the keys of the current keyspace starting with `prefix`.
+    ///
+    /// The connection is taken over by the watch, whose events the returned
+    /// iterator yields as the server pushes them. It ends when the server
+    /// closes the connection.
+    pub fn watch(mut self, prefix: String) -> Result<impl Iterator<Item = Result<WatchEvent>>> {
+        serde_json::to_writer(&mut self.writer, &Request::Watch { prefix, keyspace: self.keyspace.clone() })?;
+        self.writer.flush()?;
+        match self.read_response::<WatchResponse>()? {
+            WatchResponse::Ok(_) => {}
+            WatchResponse::Event(_) => return Err(KvsError::UnexpectedCommandType),
+            WatchResponse::Err(msg) => return Err(KvsError::StringError(msg)),
+        }
+        Ok(std::iter::from_fn(move || {
+            self.limit.reset();
+            match WatchResponse::deserialize(&mut self.reader) {
+                Ok(WatchResponse::Event(event)) => Some(Ok(event)),
+                Ok(WatchResponse::Ok(_)) => Some(Err(KvsError::UnexpectedCommandType)),
+                Ok(WatchResponse::Err(msg)) => Some(Err(KvsError::StringError(msg))),
+                Err(e) if e.is_eof() => None,
+                Err(e) => Some(Err(e.into())),
+            }
+        }))
+    }
+
+    /// Returns the changes of the current keyspace written after `seq`, as the
+    /// server sends them.
+    ///
+    /// The iterator must be read to its end before the next request. It fails
+    /// with `KvsError::HistoryCompacted` if the changes are no longer kept.
+    pub fn changes_since(&mut self, seq: u64) -> Result<impl Iterator<Item = Result<Change>> + '_> {
+        serde_json::to_writer(&mut self.writer, &Request::Changes { since: seq, keyspace: self.keyspace.clone() })?;
+        self.writer.flush()?;
+        let mut done = false;
+        Ok(std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            self.limit.reset();
+            let change = match ChangesResponse::deserialize(&mut self.reader) {
+                Ok(ChangesResponse::Change(change)) => return Some(Ok(change)),
+                Ok(ChangesResponse::End) => None,
+                Ok(ChangesResponse::Compacted(msg)) => Some(Err(KvsError::HistoryCompacted(msg))),
+                Ok(ChangesResponse::Err(msg)) => Some(Err(KvsError::StringError(msg))),
+                Err(e) => Some(Err(e.into())),
+            };
+            done = true;
+            change
+        }))
+    }
 
+    /// Creates the secondary index `name` over the field at the JSON
+    /// `pointer` of the values of the current keyspace.
+    pub fn create_index(&mut self, name: String, pointer: String) -> Result<()> {
+        self.index_request(&Request::CreateIndex { name, pointer, keyspace: self.keyspace.clone() })
+    }
 
+    pub fn drop_index(&mut self, name: String) -> Result<()> {
+        self.index_request(&Request::DropIndex { name, keyspace: self.keyspace.clone() })
+    }
 
+    fn index_request(&mut self, request: &Request) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, request)?;
+        self.writer.flush()?;
+        match self.read_response::<IndexResponse>()? {
+            IndexResponse::Ok(_) => Ok(()),
+            IndexResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
 
-}
\ No newline at end of file
+    pub fn indexes(&mut self) -> Result<Vec<IndexDefinition>> {
+        serde_json::to_writer(&mut self.writer, &Request::ListIndexes { keyspace: self.keyspace.clone() })?;
+        self.writer.flush()?;
+        match self.read_response::<ListIndexesResponse>()? {
+            ListIndexesResponse::Ok(indexes) => Ok(indexes),
+            ListIndexesResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Returns the key/value pairs whose field indexed by `name` lies between
+    /// `start` and `end`, see `KvsEngine::index_scan`.
+    pub fn index_scan(
+        &mut self,
+        name: String,
+        start: Bound<Value>,
+        end: Bound<Value>,
+    ) -> Result<Vec<(String, String)>> {
+        let request = Request::IndexScan { name, start, end, keyspace: self.keyspace.clone() };
+        serde_json::to_writer(&mut self.writer, &request)?;
+        self.writer.flush()?;
+        match self.read_response::<IndexScanResponse>()? {
+            IndexScanResponse::Ok(pairs) => Ok(pairs),
+            IndexScanResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Returns the key/value pairs whose field indexed by `name` equals
+    /// `value`.
+    pub fn index_get(&mut self, name: String, value: Value) -> Result<Vec<(String, String)>> {
+        self.index_scan(name, Bound::Included(value.clone()), Bound::Included(value))
+    }
+
+    pub fn create_keyspace(&mut self, name: String) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &Request::CreateKeyspace { name })?;
+        self.writer.flush()?;
+        match self.read_response::<KeyspaceResponse>()? {
+            KeyspaceResponse::Ok(_) => Ok(()),
+            KeyspaceResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    pub fn drop_keyspace(&mut self, name: String) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &Request::DropKeyspace { name })?;
+        self.writer.flush()?;
+        match self.read_response::<KeyspaceResponse>()? {
+            KeyspaceResponse::Ok(_) => Ok(()),
+            KeyspaceResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    pub fn keyspaces(&mut self) -> Result<Vec<String>> {
+        serde_json::to_writer(&mut self.writer, &Request::ListKeyspaces)?;
+        self.writer.flush()?;
+        match self.read_response::<ListKeyspacesResponse>()? {
+            ListKeyspacesResponse::Ok(names) => Ok(names),
+            ListKeyspacesResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+}
@@ -1,15 +1,172 @@
+use crate::{Change, IndexDefinition, KeyVersion, VersionSpec, WatchEvent};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{self, Read};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// the longest request or response a peer may send, so that one that never
+// ends cannot make the other side buffer it all
+pub const MAX_MESSAGE_LEN: u64 = 64 * 1024 * 1024;
+
+/// Reads the messages of a peer, failing once a message grows longer than
+/// `MAX_MESSAGE_LEN` bytes. The `MessageLimit` returned along starts the
+/// next message.
+pub struct LimitedReader<R> {
+    inner: R,
+    left: Arc<AtomicU64>,
+}
+
+#[derive(Clone)]
+pub struct MessageLimit(Arc<AtomicU64>);
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R) -> (LimitedReader<R>, MessageLimit) {
+        let left = Arc::new(AtomicU64::new(MAX_MESSAGE_LEN));
+        let limit = MessageLimit(Arc::clone(&left));
+        (LimitedReader { inner, left }, limit)
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.left.load(Ordering::Relaxed);
+        if left == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message longer than {} bytes", MAX_MESSAGE_LEN),
+            ));
+        }
+        let len = buf.len().min(left as usize);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.left.store(left - read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl MessageLimit {
+    /// Starts the next message.
+    pub fn reset(&self) {
+        self.0.store(MAX_MESSAGE_LEN, Ordering::Relaxed);
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+        // `None` addresses the default keyspace
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    Remove {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    // removes the keys from `start` up to, not including, `end`
+    RemoveRange {
+        start: String,
+        end: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    RemovePrefix {
+        prefix: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    Incr {
+        key: String,
+        delta: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    Decr {
+        key: String,
+        delta: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    GetVersions {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    GetAt {
+        key: String,
+        at: VersionSpec,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    // get, set and remove requests go to the transaction until it commits or
+    // rolls back; the server refuses any other request meanwhile
+    Begin {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    Commit,
+    Rollback,
+    // the server answers, then pushes the changes of the keys with the prefix
+    // until the client hangs up, taking no more requests on the connection
+    Watch {
+        prefix: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    // the server sends the changes written after `since` up to the end of the
+    // log, then `End`
+    Changes {
+        since: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    CreateIndex {
+        name: String,
+        pointer: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    DropIndex {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    ListIndexes {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    // looks up the keys whose indexed field lies between the bounds
+    IndexScan {
+        name: String,
+        start: Bound<Value>,
+        end: Bound<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyspace: Option<String>,
+    },
+    CreateKeyspace { name: String },
+    DropKeyspace { name: String },
+    ListKeyspaces,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
+    // the transaction must be retried
+    Conflict(String),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum VersionsResponse {
+    Ok(Vec<KeyVersion>),
     Err(String),
 }
 
@@ -22,5 +179,65 @@ pub enum SetResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
+    Conflict(String),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IncrResponse {
+    Ok(i64),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TransactionResponse {
+    Ok(()),
+    Conflict(String),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponse {
+    Ok(()),
+    Event(WatchEvent),
     Err(String),
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ChangesResponse {
+    Change(Change),
+    End,
+    // the changes asked for are no longer kept
+    Compacted(String),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IndexResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ListIndexesResponse {
+    Ok(Vec<IndexDefinition>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IndexScanResponse {
+    Ok(Vec<(String, String)>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KeyspaceResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ListKeyspacesResponse {
+    Ok(Vec<String>),
+    Err(String),
+}
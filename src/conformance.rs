@@ -0,0 +1,912 @@
+//! A conformance suite that every `KvsEngine` is expected to pass.
+//!
+//! Each check takes a function opening the engine under test in a given
+//! directory. Opening the same directory again must give back the persisted
+//! data. The `kvs_engine_conformance!` macro generates one test per check:
+//!
+//! ```rust,ignore
+//! kvs::kvs_engine_conformance!(my_engine, |path| MyEngine::open(path));
+//! ```
+
+use crate::{IndexDefinition, KvsEngine, KvsError, Result, WatchEvent, Watcher};
+
+use std::collections::BTreeMap;
+use fs2::FileExt;
+use serde_json::json;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary directory that is removed when dropped.
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    /// Creates a new empty directory under the system temporary directory.
+    pub fn new() -> Result<ScratchDir> {
+        let path = env::temp_dir().join(format!(
+            "kvs-conformance-{}-{}",
+            process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        Ok(ScratchDir(path))
+    }
+
+    /// Returns the path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Opens the sled database in `path`, as the checks do for sled.
+///
+/// sled's I/O threads can keep the database file, and the lock sled holds
+/// on it, open for a moment after the last handle is dropped. So this
+/// waits for the lock first, as sled's own test builds do, and a reopen
+/// right after a drop does not fail.
+pub fn open_sled(path: &Path) -> Result<sled::Db> {
+    match OpenOptions::new().read(true).write(true).open(path.join("db")) {
+        // the lock is released as `file` is closed
+        Ok(file) => file.lock_exclusive()?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(sled::open(path)?)
+}
+
+/// Values can be set, read back and removed.
+pub fn crud<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Setting an existing key replaces its value.
+pub fn overwrite<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    engine.remove("key1".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+/// Removing a missing key fails with `KvsError::KeyNotFound`.
+pub fn remove_missing_key<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    match engine.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    match engine.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    Ok(())
+}
+
+/// Data survives dropping the engine and opening the directory again.
+pub fn reopen<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    engine.set("key3".to_owned(), "value4".to_owned())?;
+    engine.remove("key3".to_owned())?;
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    engine.set("key3".to_owned(), "value5".to_owned())?;
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key3".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+/// Clones of an engine can be used from many threads at once.
+pub fn concurrent<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    const THREADS: usize = 8;
+    const KEYS: usize = 100;
+
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..KEYS {
+                    engine.set(format!("key{}_{}", thread_id, key_id), format!("{}", key_id))?;
+                    engine.set("shared".to_owned(), format!("{}", thread_id))?;
+                    // Keys of the other threads are either missing or complete
+                    let other = (thread_id + 1) % THREADS;
+                    if let Some(value) = engine.get(format!("key{}_{}", other, key_id))? {
+                        assert_eq!(value, format!("{}", key_id));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+
+    for thread_id in 0..THREADS {
+        for key_id in 0..KEYS {
+            assert_eq!(
+                engine.get(format!("key{}_{}", thread_id, key_id))?,
+                Some(format!("{}", key_id))
+            );
+        }
+    }
+    let shared = engine
+        .get("shared".to_owned())?
+        .expect("shared key missing")
+        .parse::<usize>()
+        .expect("shared value corrupted");
+    assert!(shared < THREADS);
+    Ok(())
+}
+
+/// Large keys and values are stored intact.
+pub fn large_values<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    let key = "k".repeat(64 * 1024);
+    let value: String = (0..1024 * 1024)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    engine.set(key.clone(), value.clone())?;
+    engine.set("key\n\"quoted\"".to_owned(), "\u{1f980}\0\t".to_owned())?;
+    assert_eq!(engine.get(key.clone())?, Some(value.clone()));
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get(key)?, Some(value));
+    assert_eq!(
+        engine.get("key\n\"quoted\"".to_owned())?,
+        Some("\u{1f980}\0\t".to_owned())
+    );
+    Ok(())
+}
+
+/// Scans return the live pairs of a key range in key order.
+pub fn scan<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    for i in (0..100).rev() {
+        engine.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    for i in (0..100).step_by(10) {
+        engine.remove(format!("key{:03}", i))?;
+    }
+    engine.set("key001".to_owned(), "new".to_owned())?;
+    let expected = |range: std::ops::Range<usize>| -> Vec<(String, String)> {
+        range
+            .filter(|i| i % 10 != 0)
+            .map(|i| {
+                let value = if i == 1 {
+                    "new".to_owned()
+                } else {
+                    format!("value{}", i)
+                };
+                (format!("key{:03}", i), value)
+            })
+            .collect()
+    };
+
+    assert_eq!(engine.scan(..)?, expected(0..100));
+    assert_eq!(
+        engine.scan("key015".to_owned().."key042".to_owned())?,
+        expected(15..42)
+    );
+    assert_eq!(
+        engine.scan("key015".to_owned()..="key042".to_owned())?,
+        expected(15..43)
+    );
+    assert_eq!(engine.scan("key095".to_owned()..)?, expected(95..100));
+    assert_eq!(engine.scan(.."a".to_owned())?, vec![]);
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.scan(..)?, expected(0..100));
+    Ok(())
+}
+
+/// Range and prefix deletions remove exactly the keys they cover.
+pub fn delete_range<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    for i in 0..100 {
+        engine.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    let prefixed = [
+        "tenant1/a",
+        "tenant1/b",
+        "tenant10/a",
+        "tenant2/a",
+        "\u{10ffff}",
+        "\u{10ffff}a",
+    ];
+    for key in &prefixed {
+        engine.set((*key).to_owned(), "value".to_owned())?;
+    }
+    let keys = |engine: &E| -> Result<Vec<String>> {
+        Ok(engine.scan(..)?.into_iter().map(|(key, _)| key).collect())
+    };
+
+    engine.delete_range("key010".to_owned().."key020".to_owned())?;
+    engine.delete_range("key030".to_owned()..="key039".to_owned())?;
+    // empty or reversed ranges remove nothing
+    engine.delete_range("key050".to_owned().."key050".to_owned())?;
+    engine.delete_range("key060".to_owned().."key055".to_owned())?;
+    engine.delete_range("x".to_owned().."y".to_owned())?;
+    engine.delete_prefix("tenant1/")?;
+    engine.delete_prefix("\u{10ffff}")?;
+    let expected: Vec<String> = (0..100)
+        .filter(|i| !(10..20).contains(i) && !(30..40).contains(i))
+        .map(|i| format!("key{:03}", i))
+        .chain(vec!["tenant10/a".to_owned(), "tenant2/a".to_owned()])
+        .collect();
+    assert_eq!(keys(&engine)?, expected);
+    assert_eq!(engine.get("key015".to_owned())?, None);
+    assert_eq!(engine.get("key020".to_owned())?, Some("value20".to_owned()));
+
+    // keys set again after the deletion are back
+    engine.set("key015".to_owned(), "new".to_owned())?;
+    engine.set("tenant1/b".to_owned(), "new".to_owned())?;
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key015".to_owned())?, Some("new".to_owned()));
+    assert_eq!(engine.get("key016".to_owned())?, None);
+    assert_eq!(engine.get("tenant1/a".to_owned())?, None);
+    assert_eq!(engine.get("tenant1/b".to_owned())?, Some("new".to_owned()));
+    engine.delete_range(..)?;
+    assert_eq!(keys(&engine)?, Vec::<String>::new());
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(keys(&engine)?, Vec::<String>::new());
+    Ok(())
+}
+
+/// Concurrent increments of one counter are never lost, and values that are
+/// not integers or would overflow are left alone.
+pub fn incr_by<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    const THREADS: i64 = 8;
+    const INCREMENTS: i64 = 100;
+
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    assert_eq!(engine.incr_by("counter".to_owned(), 5)?, 5);
+    assert_eq!(engine.incr_by("counter".to_owned(), -7)?, -2);
+    assert_eq!(engine.get("counter".to_owned())?, Some("-2".to_owned()));
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..INCREMENTS {
+                    engine.incr_by("counter".to_owned(), 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+    assert_eq!(engine.incr_by("counter".to_owned(), 0)?, THREADS * INCREMENTS - 2);
+
+    engine.set("text".to_owned(), "ten".to_owned())?;
+    match engine.incr_by("text".to_owned(), 1) {
+        Err(KvsError::NotAnInteger(_)) => {}
+        res => panic!("expected NotAnInteger, got {:?}", res.map_err(|e| e.to_string())),
+    }
+    assert_eq!(engine.get("text".to_owned())?, Some("ten".to_owned()));
+
+    engine.set("max".to_owned(), i64::MAX.to_string())?;
+    match engine.incr_by("max".to_owned(), 1) {
+        Err(KvsError::IntegerOverflow(_)) => {}
+        res => panic!("expected IntegerOverflow, got {:?}", res.map_err(|e| e.to_string())),
+    }
+    assert_eq!(engine.get("max".to_owned())?, Some(i64::MAX.to_string()));
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(
+        engine.get("counter".to_owned())?,
+        Some((THREADS * INCREMENTS - 2).to_string())
+    );
+    Ok(())
+}
+
+/// The merge operator `merge` expects engines to be opened with: it appends
+/// the operand to the value, separated by a comma.
+pub fn append(_key: &str, existing: Option<&str>, operand: &str) -> String {
+    match existing {
+        Some(existing) => format!("{},{}", existing, operand),
+        None => operand.to_owned(),
+    }
+}
+
+/// Merges fold into the value in order, also concurrently and across
+/// reopening. Not part of `kvs_engine_conformance!`, as engines need not
+/// support merges. The engine must be opened with the `append` operator.
+pub fn merge<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    const THREADS: usize = 8;
+    const MERGES: usize = 50;
+
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    engine.merge("list".to_owned(), "a".to_owned())?;
+    engine.merge("list".to_owned(), "b".to_owned())?;
+    assert_eq!(engine.get("list".to_owned())?, Some("a,b".to_owned()));
+    engine.set("list".to_owned(), "x".to_owned())?;
+    engine.merge("list".to_owned(), "c".to_owned())?;
+    assert_eq!(engine.get("list".to_owned())?, Some("x,c".to_owned()));
+    engine.remove("list".to_owned())?;
+    assert_eq!(engine.get("list".to_owned())?, None);
+    engine.merge("list".to_owned(), "d".to_owned())?;
+    assert_eq!(engine.get("list".to_owned())?, Some("d".to_owned()));
+    engine.merge("other".to_owned(), "e".to_owned())?;
+    assert_eq!(
+        engine.scan(..)?,
+        vec![
+            ("list".to_owned(), "d".to_owned()),
+            ("other".to_owned(), "e".to_owned()),
+        ]
+    );
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..MERGES {
+                    engine.merge("shared".to_owned(), format!("{}-{}", thread_id, i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+    let check_shared = |value: String| {
+        let operands: Vec<_> = value.split(',').collect();
+        assert_eq!(operands.len(), THREADS * MERGES);
+        // the operands of each thread stay in order
+        for thread_id in 0..THREADS {
+            let prefix = format!("{}-", thread_id);
+            let own: Vec<_> = operands
+                .iter()
+                .filter(|operand| operand.starts_with(&prefix))
+                .map(|operand| operand[prefix.len()..].parse::<usize>().unwrap())
+                .collect();
+            assert_eq!(own, (0..MERGES).collect::<Vec<_>>());
+        }
+    };
+    check_shared(engine.get("shared".to_owned())?.expect("no merged value"));
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("list".to_owned())?, Some("d".to_owned()));
+    check_shared(engine.get("shared".to_owned())?.expect("no merged value"));
+    engine.merge("list".to_owned(), "f".to_owned())?;
+    assert_eq!(engine.get("list".to_owned())?, Some("d,f".to_owned()));
+    Ok(())
+}
+
+/// Keyspaces hold their keys apart and can be dropped. Not part of
+/// `kvs_engine_conformance!`, as engines need not support keyspaces.
+pub fn keyspaces<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    assert_eq!(engine.keyspaces()?, Vec::<String>::new());
+    match engine.keyspace("users") {
+        Err(KvsError::KeyspaceNotFound(_)) => {}
+        Err(e) => return Err(e),
+        Ok(_) => panic!("opened a keyspace that does not exist"),
+    }
+    for name in &["", "a/b", "..", &"x".repeat(65), "__sled__default", "__sled__users"] {
+        assert!(engine.create_keyspace(name).is_err(), "accepted {:?}", name);
+    }
+    // sled's default tree must not be reachable as a keyspace
+    assert!(engine.keyspace("__sled__default").is_err());
+    assert!(engine.drop_keyspace("__sled__default").is_err());
+
+    engine.create_keyspace("users")?;
+    engine.create_keyspace("orders")?;
+    // creating it again keeps its keys
+    let users = engine.keyspace("users")?;
+    users.set("key1".to_owned(), "user1".to_owned())?;
+    engine.create_keyspace("users")?;
+    let orders = engine.keyspace("orders")?;
+    orders.set("key1".to_owned(), "order1".to_owned())?;
+    engine.set("key1".to_owned(), "default1".to_owned())?;
+    assert_eq!(engine.keyspaces()?, vec!["orders".to_owned(), "users".to_owned()]);
+
+    assert_eq!(users.get("key1".to_owned())?, Some("user1".to_owned()));
+    assert_eq!(orders.get("key1".to_owned())?, Some("order1".to_owned()));
+    assert_eq!(engine.get("key1".to_owned())?, Some("default1".to_owned()));
+    users.set("key2".to_owned(), "user2".to_owned())?;
+    assert_eq!(orders.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(
+        users.scan(..)?,
+        vec![
+            ("key1".to_owned(), "user1".to_owned()),
+            ("key2".to_owned(), "user2".to_owned()),
+        ]
+    );
+    assert_eq!(orders.scan(..)?, vec![("key1".to_owned(), "order1".to_owned())]);
+    orders.remove("key1".to_owned())?;
+    assert_eq!(users.get("key1".to_owned())?, Some("user1".to_owned()));
+    drop((users, orders));
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.keyspaces()?, vec!["orders".to_owned(), "users".to_owned()]);
+    let users = engine.keyspace("users")?;
+    assert_eq!(users.get("key2".to_owned())?, Some("user2".to_owned()));
+    assert_eq!(engine.keyspace("orders")?.get("key1".to_owned())?, None);
+    drop(users);
+
+    engine.drop_keyspace("users")?;
+    assert_eq!(engine.keyspaces()?, vec!["orders".to_owned()]);
+    assert!(engine.keyspace("users").is_err());
+    assert!(engine.drop_keyspace("users").is_err());
+    assert_eq!(engine.get("key1".to_owned())?, Some("default1".to_owned()));
+    // a keyspace created again under a dropped name starts empty
+    engine.create_keyspace("users")?;
+    assert_eq!(engine.keyspace("users")?.get("key2".to_owned())?, None);
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.keyspaces()?, vec!["orders".to_owned(), "users".to_owned()]);
+    assert_eq!(engine.keyspace("users")?.scan(..)?, vec![]);
+    Ok(())
+}
+
+/// Transactions read a snapshot, see their own writes, commit them all at
+/// once and fail with `KvsError::TransactionConflict` if a key they read is
+/// written meanwhile.
+/// Not part of `kvs_engine_conformance!`, as engines need not support
+/// transactions.
+pub fn transactions<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    const THREADS: i64 = 4;
+    const INCREMENTS: i64 = 25;
+
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+
+    let mut txn = engine.begin()?;
+    assert_eq!(txn.get("a".to_owned())?, None);
+    txn.set("a".to_owned(), "1".to_owned());
+    txn.set("b".to_owned(), "2".to_owned());
+    assert_eq!(txn.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(engine.get("a".to_owned())?, None);
+    txn.commit()?;
+    assert_eq!(engine.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(engine.get("b".to_owned())?, Some("2".to_owned()));
+
+    // dropping a transaction discards its writes
+    let mut txn = engine.begin()?;
+    txn.set("c".to_owned(), "3".to_owned());
+    drop(txn);
+    assert_eq!(engine.get("c".to_owned())?, None);
+
+    // a key set and removed again by a transaction is not written by it
+    let mut other = engine.begin()?;
+    assert_eq!(other.get("f".to_owned())?, None);
+    other.set("f".to_owned(), "6".to_owned());
+    let mut txn = engine.begin()?;
+    txn.set("f".to_owned(), "3".to_owned());
+    txn.remove("f".to_owned())?;
+    txn.set("g".to_owned(), "7".to_owned());
+    txn.commit()?;
+    other.commit()?;
+    assert_eq!(engine.get("f".to_owned())?, Some("6".to_owned()));
+
+    // transactions writing the same key conflict, even without reading it
+    let mut other = engine.begin()?;
+    let mut txn = engine.begin()?;
+    txn.set("h".to_owned(), "8".to_owned());
+    other.set("h".to_owned(), "9".to_owned());
+    txn.commit()?;
+    match other.commit() {
+        Err(KvsError::TransactionConflict(_)) => {}
+        res => panic!("expected TransactionConflict, got {:?}", res.map_err(|e| e.to_string())),
+    }
+    assert_eq!(engine.get("h".to_owned())?, Some("8".to_owned()));
+
+    let mut txn = engine.begin()?;
+    match txn.remove("c".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("expected KeyNotFound, got {:?}", res.map_err(|e| e.to_string())),
+    }
+    txn.remove("a".to_owned())?;
+    assert_eq!(txn.get("a".to_owned())?, None);
+    txn.commit()?;
+    assert_eq!(engine.get("a".to_owned())?, None);
+
+    // reads see the engine as of `begin`
+    let mut other = engine.begin()?;
+    let mut txn = engine.begin()?;
+    engine.set("b".to_owned(), "changed".to_owned())?;
+    engine.set("e".to_owned(), "5".to_owned())?;
+    assert_eq!(txn.get("b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(txn.get("e".to_owned())?, None);
+    txn.set("d".to_owned(), "4".to_owned());
+    match txn.commit() {
+        Err(KvsError::TransactionConflict(_)) => {}
+        res => panic!("expected TransactionConflict, got {:?}", res.map_err(|e| e.to_string())),
+    }
+    assert_eq!(engine.get("d".to_owned())?, None);
+    // a commit that fails writes nothing other transactions conflict with
+    assert_eq!(other.get("d".to_owned())?, None);
+    other.set("d".to_owned(), "4".to_owned());
+    other.commit()?;
+
+    // transfers between two keys retried on conflicts keep their sum
+    engine.set("from".to_owned(), (THREADS * INCREMENTS).to_string())?;
+    engine.set("to".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut done = 0;
+                while done < INCREMENTS {
+                    let res = (|| {
+                        let mut txn = engine.begin()?;
+                        let value = |txn: &mut crate::Transaction, key: &str| -> Result<i64> {
+                            let value = txn.get(key.to_owned())?.expect("no value");
+                            Ok(value.parse().expect("not an integer"))
+                        };
+                        let from = value(&mut txn, "from")?;
+                        let to = value(&mut txn, "to")?;
+                        txn.set("from".to_owned(), (from - 1).to_string());
+                        txn.set("to".to_owned(), (to + 1).to_string());
+                        txn.commit()
+                    })();
+                    match res {
+                        Ok(()) => done += 1,
+                        Err(KvsError::TransactionConflict(_)) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+    assert_eq!(engine.get("from".to_owned())?, Some("0".to_owned()));
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("to".to_owned())?, Some((THREADS * INCREMENTS).to_string()));
+    assert_eq!(engine.get("b".to_owned())?, Some("changed".to_owned()));
+    Ok(())
+}
+
+/// Watchers receive the changes of the keys with their prefix, in order for
+/// every key, and only once they are made. Not part of
+/// `kvs_engine_conformance!`, as engines need not support watches.
+pub fn watch_prefix<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    const THREADS: usize = 4;
+    const WRITES: usize = 25;
+
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+    engine.set("a:old".to_owned(), "before".to_owned())?;
+
+    let mut watcher = engine.watch_prefix("a:")?;
+    let mut everything = engine.watch_prefix("")?;
+    engine.set("a:1".to_owned(), "x".to_owned())?;
+    engine.set("b:1".to_owned(), "ignored".to_owned())?;
+    engine.set("a:2".to_owned(), "y".to_owned())?;
+    engine.set("a:1".to_owned(), "z".to_owned())?;
+    engine.remove("a:2".to_owned())?;
+    engine.incr_by("a:n".to_owned(), 5)?;
+    engine.delete_prefix("a:")?;
+
+    let events = by_key(&mut watcher, 8)?;
+    let expected: BTreeMap<_, _> = vec![
+        ("a:1", vec![Some("x"), Some("z"), None]),
+        ("a:2", vec![Some("y"), None]),
+        ("a:n", vec![Some("5"), None]),
+        ("a:old", vec![None]),
+    ]
+    .into_iter()
+    .map(|(key, values)| {
+        let values = values.into_iter().map(|value| value.map(str::to_owned)).collect();
+        (key.to_owned(), values)
+    })
+    .collect();
+    assert_eq!(events, expected);
+    assert_eq!(watcher.next_timeout(Duration::from_millis(100))?, None);
+    assert_eq!(by_key(&mut everything, 9)?["b:1"], vec![Some("ignored".to_owned())]);
+
+    // writes from many threads reach the watcher in order for every key, also
+    // with a dropped watcher around
+    drop(watcher);
+    let mut watcher = engine.watch_prefix("c:")?;
+    let handles: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for j in 0..WRITES {
+                    engine.set(format!("c:{}", i), j.to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+    let events = by_key(&mut watcher, THREADS * WRITES)?;
+    for i in 0..THREADS {
+        let values: Vec<_> = (0..WRITES).map(|j| Some(j.to_string())).collect();
+        assert_eq!(events[&format!("c:{}", i)], values);
+    }
+    Ok(())
+}
+
+/// Secondary indexes find keys by a field of their JSON values, sorted by
+/// field and then by key, and follow the writes. Not part of
+/// `kvs_engine_conformance!`, as engines need not support secondary indexes.
+pub fn secondary_indexes<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let keys = |found: Vec<(String, String)>| -> Vec<String> {
+        found.into_iter().map(|(key, _)| key).collect()
+    };
+    let dir = ScratchDir::new()?;
+    let engine = open(dir.path())?;
+    assert!(engine.indexes()?.is_empty());
+    for i in 0..20 {
+        let city = if i % 2 == 0 { "Oslo" } else { "Paris" };
+        let value = json!({ "city": city, "age": 40 - i as i64 });
+        engine.set(format!("u{:02}", i), value.to_string())?;
+    }
+    engine.set("v".to_owned(), "not json".to_owned())?;
+
+    // existing values are indexed on creation
+    engine.create_index("city", "/city")?;
+    engine.create_index("age", "/age")?;
+    engine.create_index("city", "/city")?;
+    match engine.create_index("city", "/age") {
+        Err(KvsError::IndexExists(_)) => {}
+        res => panic!("expected IndexExists, got {:?}", res.map_err(|e| e.to_string())),
+    }
+    assert!(engine.create_index("bad", "city").is_err());
+    assert_eq!(
+        engine.indexes()?,
+        vec![
+            IndexDefinition { name: "age".to_owned(), pointer: "/age".to_owned() },
+            IndexDefinition { name: "city".to_owned(), pointer: "/city".to_owned() },
+        ]
+    );
+    let oslo: Vec<String> = (0..20).step_by(2).map(|i| format!("u{:02}", i)).collect();
+    assert_eq!(keys(engine.index_get("city", &json!("Oslo"))?), oslo);
+    // sorted by field first, the youngest are the last keys
+    let young: Vec<String> = (15..20).rev().map(|i| format!("u{:02}", i)).collect();
+    assert_eq!(keys(engine.index_scan("age", json!(21)..json!(26))?), young);
+    assert_eq!(engine.index_scan("age", ..)?.len(), 20);
+    match engine.index_get("missing", &json!(1)) {
+        Err(KvsError::IndexNotFound(_)) => {}
+        res => panic!("expected IndexNotFound, got {:?}", res.map_err(|e| e.to_string())),
+    }
+
+    // writes keep the indexes up to date
+    engine.set("u00".to_owned(), json!({ "city": "Rome", "age": 1 }).to_string())?;
+    engine.remove("u02".to_owned())?;
+    engine.delete_range("u10".to_owned().."u20".to_owned())?;
+    assert_eq!(keys(engine.index_get("city", &json!("Rome"))?), vec!["u00"]);
+    assert_eq!(
+        keys(engine.index_get("city", &json!("Oslo"))?),
+        vec!["u04", "u06", "u08"]
+    );
+    assert_eq!(
+        keys(engine.index_scan("age", ..json!(35))?),
+        vec!["u00", "u09", "u08", "u07", "u06"]
+    );
+    drop(engine);
+
+    // indexes survive a reopen, and can be dropped and created again
+    let engine = open(dir.path())?;
+    assert_eq!(engine.indexes()?.len(), 2);
+    assert_eq!(keys(engine.index_get("city", &json!("Rome"))?), vec!["u00"]);
+    engine.drop_index("city")?;
+    match engine.drop_index("city") {
+        Err(KvsError::IndexNotFound(_)) => {}
+        res => panic!("expected IndexNotFound, got {:?}", res.map_err(|e| e.to_string())),
+    }
+    assert!(engine.index_get("city", &json!("Rome")).is_err());
+    engine.create_index("city", "/city")?;
+    assert_eq!(keys(engine.index_get("city", &json!("Rome"))?), vec!["u00"]);
+    Ok(())
+}
+
+/// Reads `count` events from `watcher` and returns the values they carry
+/// by key, checking that sequence numbers grow for every key.
+fn by_key(watcher: &mut Watcher, count: usize) -> Result<BTreeMap<String, Vec<Option<String>>>> {
+    let mut events: BTreeMap<String, Vec<WatchEvent>> = BTreeMap::new();
+    for _ in 0..count {
+        let event = watcher
+            .next_timeout(Duration::from_secs(5))?
+            .expect("watcher missed a write");
+        events.entry(event.key.clone()).or_default().push(event);
+    }
+    Ok(events
+        .into_iter()
+        .map(|(key, events)| {
+            for pair in events.windows(2) {
+                assert!(pair[0].seq < pair[1].seq, "{:?} out of order", pair);
+            }
+            (key, events.into_iter().map(|event| event.value).collect())
+        })
+        .collect())
+}
+
+/// Generates a test module named `$name` running every conformance check
+/// against the engine opened by `$open`, a `Fn(&Path) -> kvs::Result<E>`.
+#[macro_export]
+macro_rules! kvs_engine_conformance {
+    ($name:ident, $open:expr) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #[test]
+            fn crud() -> $crate::Result<()> {
+                $crate::conformance::crud($open)
+            }
+
+            #[test]
+            fn overwrite() -> $crate::Result<()> {
+                $crate::conformance::overwrite($open)
+            }
+
+            #[test]
+            fn remove_missing_key() -> $crate::Result<()> {
+                $crate::conformance::remove_missing_key($open)
+            }
+
+            #[test]
+            fn reopen() -> $crate::Result<()> {
+                $crate::conformance::reopen($open)
+            }
+
+            #[test]
+            fn concurrent() -> $crate::Result<()> {
+                $crate::conformance::concurrent($open)
+            }
+
+            #[test]
+            fn large_values() -> $crate::Result<()> {
+                $crate::conformance::large_values($open)
+            }
+
+            #[test]
+            fn scan() -> $crate::Result<()> {
+                $crate::conformance::scan($open)
+            }
+
+            #[test]
+            fn delete_range() -> $crate::Result<()> {
+                $crate::conformance::delete_range($open)
+            }
+
+            #[test]
+            fn incr_by() -> $crate::Result<()> {
+                $crate::conformance::incr_by($open)
+            }
+        }
+    };
+}
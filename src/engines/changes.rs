@@ -0,0 +1,48 @@
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// A write recorded in the log of an engine, see `KvsEngine::changes_since`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// The sequence number of the record.
+    pub seq: u64,
+    pub kind: ChangeKind,
+}
+
+/// What a `Change` did.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    Set { key: String, value: String },
+    Remove { key: String },
+    /// Removed every key between the bounds.
+    RemoveRange { start: Bound<String>, end: Bound<String> },
+    /// Merged `operand` into the value of the key.
+    Merge { key: String, operand: String },
+}
+
+/// The changes returned by `KvsEngine::changes_since`, oldest first.
+///
+/// The log is read as the iterator advances, so changes written meanwhile
+/// may show up too.
+pub struct Changes {
+    changes: Box<dyn Iterator<Item = Result<Change>> + Send>,
+}
+
+impl Changes {
+    pub(crate) fn new(changes: impl Iterator<Item = Result<Change>> + Send + 'static) -> Changes {
+        Changes {
+            changes: Box::new(changes),
+        }
+    }
+}
+
+impl Iterator for Changes {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Result<Change>> {
+        self.changes.next()
+    }
+}
@@ -0,0 +1,236 @@
+use crate::bloom::BloomFilter;
+use crate::{KvsError, Result};
+
+use serde::{Deserialize, Serialize};
+use crate::vfs::{FileSystem, VfsFile};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// number of keys read at once by a lookup
+const BLOCK_LEN: usize = 64;
+
+// the footer holds the offset and length of the meta block
+const FOOTER_LEN: u64 = 16;
+
+pub(super) const BLOOM_EXTENSION: &str = "bloom";
+
+/// A key and the position and length of its latest "set" command in a log
+/// file, `None` if its latest command is a "remove".
+pub(super) type IndexEntry = (String, Option<(u64, u64)>);
+
+/// Locates a block of an index file.
+#[derive(Serialize, Deserialize)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    // length of the log file the index was built from
+    log_len: u64,
+    blocks: Vec<BlockHandle>,
+}
+
+/// The sorted keys of a sealed log file.
+///
+/// The file is a sequence of blocks, each a JSON array of sorted entries,
+/// followed by a JSON meta block and a fixed size footer pointing at it. Only
+/// the last key of each block is held in memory, together with a bloom filter
+/// of all keys that is stored in a `bloom` file beside the index file.
+pub(super) struct IndexFile {
+    file: Mutex<Box<dyn VfsFile>>,
+    log_len: u64,
+    blocks: Vec<BlockHandle>,
+    bloom: BloomFilter,
+}
+
+impl IndexFile {
+    /// Writes the `entries`, sorted by key without duplicates, to an index
+    /// file at `path` and their bloom filter beside it, and opens them.
+    ///
+    /// The files are written under a temporary name and renamed once synced,
+    /// the index file last, and then the directory is synced.
+    pub(super) fn write(
+        fs: &dyn FileSystem,
+        path: &Path,
+        entries: impl IntoIterator<Item = IndexEntry>,
+        log_len: u64,
+        false_positive_rate: f64,
+    ) -> Result<IndexFile> {
+        let index_tmp_path = tmp_path(path);
+        let mut writer = BufWriter::new(fs.create(&index_tmp_path)?);
+        let mut pos = 0;
+        let mut blocks = Vec::new();
+        let mut block = Vec::with_capacity(BLOCK_LEN);
+        let mut hashes = Vec::new();
+        let mut entries = entries.into_iter().peekable();
+        while let Some(entry) = entries.next() {
+            hashes.push(BloomFilter::hash(entry.0.as_bytes()));
+            block.push(entry);
+            if block.len() == BLOCK_LEN || entries.peek().is_none() {
+                let buf = serde_json::to_vec(&block)?;
+                writer.write_all(&buf)?;
+                let (last_key, _) = block.pop().expect("empty block");
+                blocks.push(BlockHandle {
+                    last_key,
+                    offset: pos,
+                    len: buf.len() as u64,
+                });
+                pos += buf.len() as u64;
+                block.clear();
+            }
+        }
+
+        let buf = serde_json::to_vec(&Meta { log_len, blocks })?;
+        writer.write_all(&buf)?;
+        writer.write_all(&pos.to_le_bytes())?;
+        writer.write_all(&(buf.len() as u64).to_le_bytes())?;
+        writer.flush()?;
+        writer.get_mut().sync()?;
+        drop(writer);
+
+        let bloom = BloomFilter::build(&hashes, false_positive_rate);
+        let bloom_path = bloom_path(path);
+        let bloom_tmp_path = tmp_path(&bloom_path);
+        let mut bloom_writer = BufWriter::new(fs.create(&bloom_tmp_path)?);
+        serde_json::to_writer(&mut bloom_writer, &bloom)?;
+        bloom_writer.flush()?;
+        bloom_writer.get_mut().sync()?;
+        drop(bloom_writer);
+        fs.rename(&bloom_tmp_path, &bloom_path)?;
+
+        fs.rename(&index_tmp_path, path)?;
+        fs.sync_dir(path.parent().unwrap_or_else(|| Path::new("")))?;
+        IndexFile::open(fs, path)
+    }
+
+    /// Opens the index file at `path`.
+    pub(super) fn open(fs: &dyn FileSystem, path: &Path) -> Result<IndexFile> {
+        let mut file = fs.open(path)?;
+        let size = file.seek(SeekFrom::End(0))?;
+        if size < FOOTER_LEN {
+            return Err(corrupted(path, "too short"));
+        }
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.read_exact(&mut footer)?;
+        let mut offset = [0; 8];
+        let mut len = [0; 8];
+        offset.copy_from_slice(&footer[..8]);
+        len.copy_from_slice(&footer[8..]);
+        let (offset, len) = (u64::from_le_bytes(offset), u64::from_le_bytes(len));
+        if offset.checked_add(len) != Some(size - FOOTER_LEN) {
+            return Err(corrupted(path, "bad footer"));
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; len as usize];
+        file.read_exact(&mut buf)?;
+        let meta: Meta = serde_json::from_slice(&buf)?;
+
+        let bloom = fs.read(&bloom_path(path))?;
+        let bloom = serde_json::from_slice(&bloom)?;
+        Ok(IndexFile {
+            file: Mutex::new(file),
+            log_len: meta.log_len,
+            blocks: meta.blocks,
+            bloom,
+        })
+    }
+
+    /// Returns the length of the log file the index was built from.
+    pub(super) fn log_len(&self) -> u64 {
+        self.log_len
+    }
+
+    /// Returns `false` if the key with `hash`, see `BloomFilter::hash`, is
+    /// definitely not in the index file.
+    pub(super) fn may_contain(&self, hash: u64) -> bool {
+        self.bloom.may_contain(hash)
+    }
+
+    /// Looks up `key`.
+    ///
+    /// Returns `None` if the log file holds no command for the key, and
+    /// `Some(None)` if its latest command there is a "remove".
+    pub(super) fn get(&self, key: &str) -> Result<Option<Option<(u64, u64)>>> {
+        let idx = self.blocks.partition_point(|block| block.last_key.as_str() < key);
+        let entries = match self.blocks.get(idx) {
+            Some(block) => self.read_block(block)?,
+            None => return Ok(None),
+        };
+        Ok(entries
+            .binary_search_by(|(k, _)| k.as_str().cmp(key))
+            .ok()
+            .map(|i| entries[i].1))
+    }
+
+    /// Returns the entries whose keys are in `range`, sorted by key.
+    pub(super) fn scan(&self, range: &impl RangeBounds<String>) -> Result<Vec<IndexEntry>> {
+        let first = match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => self
+                .blocks
+                .partition_point(|block| &block.last_key < start),
+            Bound::Unbounded => 0,
+        };
+        let mut entries = Vec::new();
+        for block in &self.blocks[first..] {
+            let block_entries = self.read_block(block)?;
+            entries.extend(block_entries.into_iter().filter(|(key, _)| range.contains(key)));
+            let past_end = match range.end_bound() {
+                Bound::Included(end) | Bound::Excluded(end) => &block.last_key >= end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read_block(&self, block: &BlockHandle) -> Result<Vec<IndexEntry>> {
+        let mut file = self.file.lock().unwrap();
+        let mut buf = vec![0; block.len as usize];
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read_at(&mut buf[read..], block.offset + read as u64)? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                len => read += len,
+            }
+        }
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    /// Returns about how many bytes of memory the index file takes.
+    pub(super) fn memory_usage(&self) -> u64 {
+        let blocks: u64 = self
+            .blocks
+            .iter()
+            .map(|block| (block.last_key.len() + mem::size_of::<BlockHandle>()) as u64)
+            .sum();
+        blocks + self.bloom.memory_usage()
+    }
+}
+
+/// Returns the path of the bloom filter beside the index file at `path`.
+pub(super) fn bloom_path(path: &Path) -> PathBuf {
+    path.with_extension(BLOOM_EXTENSION)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    PathBuf::from(tmp_path)
+}
+
+fn corrupted(path: &Path, reason: &str) -> KvsError {
+    KvsError::IO(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("index file {} is corrupted: {}", path.display(), reason),
+    ))
+}
@@ -1,46 +1,385 @@
-use std::collections::{BTreeMap, HashMap};
-use std::fs::{self, File, OpenOptions};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
+use crossbeam::atomic::AtomicCell;
 use crossbeam_skiplist::SkipMap;
 
 
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+use serde_json::{Deserializer, Value};
 
-use super::KvsEngine;
+use super::live_gens::LiveGens;
+use super::index_file::{self, IndexEntry, IndexFile};
+use super::{
+    add_to_value, check_keyspace_name, Change, ChangeKind, Changes, IndexDefinition, IndexUpdate,
+    is_empty_range, KeyVersion, KvsEngine, MergeOperator, SecondaryIndexes, Transaction,
+    TransactionDriver, VersionSpec, WatchEvent, Watcher, Watchers, WriteTracker,
+};
+use crate::bloom::BloomFilter;
+use crate::rate_limiter::{RateLimited, RateLimiter};
+use crate::vfs::{DiskFileSystem, FileSystem, VfsFile};
 use crate::{KvsError, Result};
 use std::ffi::OsStr;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::cell::RefCell;
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, info, warn};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
+const DIRTY_RATIO: f64 = 0.5;
+
+const STALL_DELAY: Duration = Duration::from_millis(1);
+
+const SPACE_CHECK_INTERVAL: u64 = 64 * 1024;
+
+const COMPACTION_EXTENSION: &str = "compacting";
+
+const INDEX_EXTENSION: &str = "idx";
+
+// dead bytes of the generations with an index file
+const DEAD_BYTES_FILE: &str = "dead_bytes";
+
+const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+// approximate memory taken by an index entry besides its key
+const INDEX_ENTRY_OVERHEAD: u64 = 64;
+
+// holds a directory for every keyspace
+const KEYSPACES_DIR: &str = "keyspaces";
+// records the sequence numbers of the log, see `SeqState`
+const SEQ_FILE: &str = "seq";
+// holds the store of the secondary indexes
+const SECONDARY_DIR: &str = "secondary";
+
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
-/// A `BTreeMap` in memory stores the keys and the value locations for fast query.
+/// A `SkipMap` in memory stores the keys and the value locations for fast query.
+/// With an index memory budget, only the keys of the current generation are
+/// kept in memory and older generations are looked up in index files.
+///
+/// Every keyspace is a store of its own in a subdirectory of `keyspaces`.
+/// The entries of the secondary indexes are kept in another store in
+/// `secondary`.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result};
 /// # fn try_main() -> Result<()> {
 /// use std::env::current_dir;
 /// use kvs::KvsEngine;
-/// let mut store = KvStore::open(current_dir()?)?;
+/// let store = KvStore::open(current_dir()?)?;
 /// store.set("key".to_owned(), "value".to_owned())?;
 /// let val = store.get("key".to_owned())?;
 /// assert_eq!(val, Some("value".to_owned()));
 /// # Ok(())
 /// # }
 /// ```
+#[derive(Clone)]
 pub struct KvStore {
-    // directory for the log and other data
-    path: Arc<PathBuf>,
-    index: Arc<SkipMapp<String, CommandPos>>
-    reader: KvsSt
+    // map key to the position of its latest command
+    index: Arc<Index>,
+    reader: KvStoreReader,
+    access: Access,
+    // shared by the store and all its keyspaces
+    keyspaces: Arc<Keyspaces>,
+    tracker: Arc<Mutex<WriteTracker>>,
+    // `None` until the first index is created
+    secondary: Arc<Mutex<Option<SecondaryIndexes<KvStore>>>>,
+}
+
+/// The keyspaces of a store.
+struct Keyspaces {
+    path: PathBuf,
+    fs: Arc<dyn FileSystem>,
+    // options to open the keyspaces with, `None` if opened read-only
+    options: Option<KvStoreOptions>,
+    // keyspaces opened so far, so that all handles of one share a writer
+    open: Mutex<BTreeMap<String, KvStore>>,
+}
+
+#[derive(Clone)]
+enum Access {
+    ReadWrite(Arc<Mutex<KvStoreWriter>>),
+    // opened with `open_read_only`, tracks how far each log file was loaded
+    ReadOnly(Arc<Mutex<BTreeMap<u64, GenerationStats>>>),
+}
+
+/// Options for opening a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let options = KvStoreOptions::default()
+///     .compaction_threshold(64 * 1024 * 1024)
+///     .dirty_ratio(0.3);
+/// let store = KvStore::open_with_options(current_dir()?, options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    compaction_threshold: u64,
+    dirty_ratio: f64,
+    rate_limiter: RateLimiter,
+    soft_reclaimable_limit: u64,
+    hard_reclaimable_limit: u64,
+    soft_segment_limit: usize,
+    hard_segment_limit: usize,
+    stall_delay: Duration,
+    min_free_space: u64,
+    index_memory_budget: Option<u64>,
+    bloom_false_positive_rate: f64,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    keep_versions: usize,
+    version_retention: Option<Duration>,
+    sync_writes: bool,
+    file_system: Arc<dyn FileSystem>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_threshold: COMPACTION_THRESHOLD,
+            dirty_ratio: DIRTY_RATIO,
+            rate_limiter: RateLimiter::unlimited(),
+            soft_reclaimable_limit: u64::MAX,
+            hard_reclaimable_limit: u64::MAX,
+            soft_segment_limit: usize::MAX,
+            hard_segment_limit: usize::MAX,
+            stall_delay: STALL_DELAY,
+            min_free_space: 0,
+            index_memory_budget: None,
+            bloom_false_positive_rate: BLOOM_FALSE_POSITIVE_RATE,
+            merge_operator: None,
+            keep_versions: 1,
+            version_retention: None,
+            sync_writes: false,
+            file_system: Arc::new(DiskFileSystem),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Sets how many stale bytes may build up across all generations before
+    /// a compaction is considered.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Sets the dead-byte ratio above which a generation is picked for
+    /// compaction.
+    ///
+    /// Generations below the ratio are left untouched, so `0.0` rewrites every
+    /// generation on each compaction.
+    pub fn dirty_ratio(mut self, ratio: f64) -> Self {
+        self.dirty_ratio = ratio;
+        self
+    }
+
+    /// Sets the limiter that throttles compaction reads and writes.
+    ///
+    /// Keep a clone of the limiter to adjust the rate while the store is open.
+    pub fn rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = limiter;
+        self
+    }
+
+    /// Sets the soft and hard limits of stale bytes waiting for compaction.
+    ///
+    /// Past the soft limit every write is delayed by the stall delay. Past the
+    /// hard limit a write blocks until every generation has been compacted,
+    /// and fails with `KvsError::WriteStall` if the compaction cannot get the
+    /// store back under the limit.
+    pub fn reclaimable_limits(mut self, soft: u64, hard: u64) -> Self {
+        self.soft_reclaimable_limit = soft;
+        self.hard_reclaimable_limit = hard;
+        self
+    }
+
+    /// Sets the soft and hard limits of log files, including the one being
+    /// written. They behave like the reclaimable byte limits.
+    pub fn segment_limits(mut self, soft: usize, hard: usize) -> Self {
+        self.soft_segment_limit = soft;
+        self.hard_segment_limit = hard;
+        self
+    }
+
+    /// Sets how long a write is delayed past a soft limit.
+    pub fn stall_delay(mut self, delay: Duration) -> Self {
+        self.stall_delay = delay;
+        self
+    }
+
+    /// Sets how many bytes must stay free on the disk holding the store.
+    ///
+    /// Below that, writes fail with `KvsError::ReadOnly` until space is freed
+    /// again. `0` disables the check.
+    pub fn min_free_space(mut self, bytes: u64) -> Self {
+        self.min_free_space = bytes;
+        self
+    }
+
+    /// Keeps the index on disk, holding at most about `bytes` bytes of keys
+    /// of the current generation in memory.
+    ///
+    /// Once the budget is used up, the current generation is sealed: its keys
+    /// are written to a sorted index file beside the log file, of which only
+    /// one key per block stays in memory, and a new generation is started.
+    /// Index files are reused when the store is opened again, so only the
+    /// generations without one are replayed.
+    ///
+    /// Lookups of keys that are not in memory read a block of each index file
+    /// from the newest to the oldest generation until the key is found. A
+    /// compaction keeps the keys it moves in memory until it is done.
+    pub fn index_memory_budget(mut self, bytes: u64) -> Self {
+        self.index_memory_budget = Some(bytes);
+        self
+    }
+
+    /// Sets the false positive rate of the bloom filters built for the index
+    /// files of a persistent index.
+    ///
+    /// A lookup only reads an index file if its bloom filter may contain the
+    /// key. Lower rates save more reads of absent keys at the cost of larger
+    /// filters. Filters that already exist keep their rate.
+    pub fn bloom_false_positive_rate(mut self, rate: f64) -> Self {
+        self.bloom_false_positive_rate = rate;
+        self
+    }
+
+    /// Sets the operator applied to the operands of `KvsEngine::merge`.
+    ///
+    /// A merge only appends the operand to the log. Reads fold the operands
+    /// into the value, and compaction writes the folded value back. A store
+    /// holding merged values must always be opened with the same operator.
+    pub fn merge_operator(mut self, operator: impl MergeOperator) -> Self {
+        self.merge_operator = Some(Arc::new(operator));
+        self
+    }
+
+    /// Keeps the newest `versions` versions of every key, see
+    /// `KvsEngine::get_versions`.
+    ///
+    /// Every write then links to the version it replaces, and compaction
+    /// drops the versions past the limit. Removing a key drops all its
+    /// versions. The default of `1` keeps no history.
+    pub fn keep_versions(mut self, versions: usize) -> Self {
+        self.keep_versions = versions;
+        self
+    }
+
+    /// Keeps the versions of every key written within `window`, on top of
+    /// the ones kept by `keep_versions`.
+    pub fn keep_versions_for(mut self, window: Duration) -> Self {
+        self.version_retention = Some(window);
+        self
+    }
+
+    /// Sets whether every write is synced to disk before it returns, so that
+    /// it survives a power loss. Otherwise a power loss may take the latest
+    /// writes, though never without the ones after them.
+    pub fn sync_writes(mut self, sync: bool) -> Self {
+        self.sync_writes = sync;
+        self
+    }
+
+    /// Sets the file system the store keeps its files in, the local disk by
+    /// default. Keyspaces and secondary indexes are kept there too.
+    pub fn file_system(mut self, fs: impl FileSystem) -> Self {
+        self.file_system = Arc::new(fs);
+        self
+    }
+
+    /// Returns the options of the store of the secondary indexes, which
+    /// neither merges nor keeps history.
+    fn for_secondary_indexes(&self) -> KvStoreOptions {
+        KvStoreOptions {
+            merge_operator: None,
+            keep_versions: 1,
+            version_retention: None,
+            ..self.clone()
+        }
+    }
+
+    fn keeps_history(&self) -> bool {
+        self.keep_versions > 1 || self.version_retention.is_some()
+    }
+
+    /// Returns how many of `history`, newest first, are kept at `now`.
+    fn retained(&self, history: &[HistoryEntry], now: u64) -> usize {
+        let within = self.version_retention.map_or(0, |window| {
+            let window = window.as_millis() as u64;
+            history
+                .iter()
+                .take_while(|entry| {
+                    entry.stamp.is_some_and(|stamp| now.saturating_sub(stamp.time) <= window)
+                })
+                .count()
+        });
+        within.max(self.keep_versions).max(1).min(history.len())
+    }
+}
+
+/// Garbage accounting of a single generation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GenerationStats {
+    /// Generation number of the log file.
+    pub gen: u64,
+    /// Size of the log file in bytes.
+    pub total_bytes: u64,
+    /// Bytes of the log file taken by commands that a compaction can drop.
+    pub dead_bytes: u64,
+}
+
+impl GenerationStats {
+    fn new(gen: u64) -> Self {
+        GenerationStats {
+            gen,
+            total_bytes: 0,
+            dead_bytes: 0,
+        }
+    }
+
+    /// Returns the share of the log file that a compaction can reclaim.
+    pub fn dead_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            0.0
+        } else {
+            self.dead_bytes as f64 / self.total_bytes as f64
+        }
+    }
+}
+
+/// Counters of the bloom filters of a persistent index, one per index file
+/// consulted by a lookup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BloomStats {
+    /// Index files skipped because their filter ruled the key out.
+    pub negatives: u64,
+    /// Index files read that held the key.
+    pub true_positives: u64,
+    /// Index files read that did not hold the key.
+    pub false_positives: u64,
+}
+
+/// Counters of writes slowed down or stopped by the stall limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StallStats {
+    /// Writes delayed past a soft limit.
+    pub slowdowns: u64,
+    /// Writes blocked past a hard limit.
+    pub stops: u64,
+    /// Total time writes spent stalled.
+    pub stalled: Duration,
 }
 
 impl KvStore {
@@ -52,82 +391,414 @@ impl KvStore {
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
-        fs::create_dir_all(&path)?;
-
-        let mut readers = HashMap::new();
-        let mut index = BTreeMap::new();
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
 
-        let gen_list = sorted_gen_list(&path)?;
-        let mut uncompacted = 0;
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        let fs = Arc::clone(&options.file_system);
+        fs.create_dir_all(&path)?;
+        remove_unfinished_compactions(&*fs, &path)?;
 
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
+        let mut gens = BTreeMap::new();
+        let gen_list = sorted_gen_list(&*fs, &path)?;
+        if let Some(&last_gen) = gen_list.last() {
+            repair_log_file(&*fs, &path, last_gen)?;
         }
+        let mut seqs = read_seq_state(&*fs, &path)?;
+        let (index, readers) = match options.index_memory_budget {
+            None => {
+                // index files go stale as soon as the store is written without them
+                remove_index_files(&*fs, &path, &[])?;
+                let index = Index::Memory(SkipMap::new());
+                let readers =
+                    replay(&*fs, &path, &gen_list, &index, &mut gens, &mut seqs.last, false)?;
+                (index, readers)
+            }
+            Some(budget) => {
+                remove_index_files(&*fs, &path, &gen_list)?;
+                let index = Index::Persistent(PersistentIndex {
+                    path: Arc::clone(&path),
+                    fs: Arc::clone(&fs),
+                    budget,
+                    bloom_false_positive_rate: options.bloom_false_positive_rate,
+                    recent: SkipMap::new(),
+                    recent_size: AtomicU64::new(0),
+                    sealed: RwLock::new(BTreeMap::new()),
+                    bloom_negatives: AtomicU64::new(0),
+                    bloom_true_positives: AtomicU64::new(0),
+                    bloom_false_positives: AtomicU64::new(0),
+                });
+                replay_sealed(&*fs, &path, &gen_list, &index, &mut gens, &mut seqs)?;
+                (index, BTreeMap::new())
+            }
+        };
+        let index = Arc::new(index);
+        let uncompacted = gens.values().map(|stats| stats.dead_bytes).sum();
+        // numbers are never given out again, even if their records are gone
+        seqs.last = seqs.last.max(seqs.horizon);
 
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
+        let writer = new_log_file(&*fs, &path, current_gen)?;
+        gens.insert(current_gen, GenerationStats::new(current_gen));
 
-        Ok(KvStore {
-            path,
-            readers,
+        let live_gens = Arc::new(LiveGens::new());
+        for &gen in gens.keys() {
+            live_gens.insert(gen);
+        }
+
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            fs: Arc::clone(&fs),
+            live_gens: Arc::clone(&live_gens),
+            readers: RefCell::new(readers),
+            merge_operator: options.merge_operator.clone(),
+        };
+
+        let tracker = Arc::new(Mutex::new(WriteTracker::default()));
+        let writer = KvStoreWriter {
             writer,
             current_gen,
-            index,
             uncompacted,
+            gens,
+            live_gens,
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            options,
+            stall_stats: StallStats::default(),
+            out_of_space: false,
+            torn: false,
+            unchecked_bytes: SPACE_CHECK_INTERVAL,
+            dropped: None,
+            reader: reader.clone(),
+            tracker: Arc::clone(&tracker),
+            watchers: Watchers::default(),
+            seqs,
+            compacting: None,
+        };
+
+        let keyspaces = Arc::new(Keyspaces {
+            path: path.join(KEYSPACES_DIR),
+            fs: Arc::clone(&fs),
+            options: Some(writer.options.clone()),
+            open: Mutex::new(BTreeMap::new()),
+        });
+        let secondary_path = path.join(SECONDARY_DIR);
+        let secondary = if fs.is_dir(&secondary_path) {
+            let store = KvStore::open_with_options(
+                secondary_path,
+                writer.options.for_secondary_indexes(),
+            )?;
+            Some(SecondaryIndexes::open(store)?)
+        } else {
+            None
+        };
+        Ok(KvStore {
+            reader,
+            index,
+            access: Access::ReadWrite(Arc::new(Mutex::new(writer))),
+            keyspaces,
+            tracker,
+            secondary: Arc::new(Mutex::new(secondary)),
         })
     }
 
-    /// Clears stale entries in the log.
-    pub fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = self.new_log_file(self.current_gen)?;
+    /// Opens an existing `KvStore` at the given path for reading only.
+    ///
+    /// Nothing in the directory is created, changed or compacted, so the
+    /// store may live on a read-only mount or be written by another process
+    /// at the same time. `set` and `remove` fail with `KvsError::ReadOnly`.
+    /// Reads of values with merge operands not yet compacted fail with
+    /// `KvsError::Unsupported`, as there is no merge operator to fold them;
+    /// open with `KvStore::open_read_only_with_options` to give one.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_read_only_in(path.into(), Arc::new(DiskFileSystem), None)
+    }
 
-        let mut compaction_writer = self.new_log_file(compaction_gen)?;
+    /// Opens an existing `KvStore` at the given path of `fs` for reading
+    /// only, see `KvStore::open_read_only`.
+    pub fn open_read_only_with_file_system(
+        path: impl Into<PathBuf>,
+        fs: impl FileSystem,
+    ) -> Result<KvStore> {
+        KvStore::open_read_only_in(path.into(), Arc::new(fs), None)
+    }
+
+    /// Opens an existing `KvStore` at the given path for reading only, see
+    /// `KvStore::open_read_only`. Only the file system and the merge operator
+    /// of `options` are used, the rest only matter to writers.
+    pub fn open_read_only_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<KvStore> {
+        KvStore::open_read_only_in(path.into(), options.file_system, options.merge_operator)
+    }
+
+    fn open_read_only_in(
+        path: PathBuf,
+        fs: Arc<dyn FileSystem>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+    ) -> Result<KvStore> {
+        let path = Arc::new(path);
+        let index = Arc::new(Index::Memory(SkipMap::new()));
+        let mut gens = BTreeMap::new();
+
+        let gen_list = sorted_gen_list(&*fs, &path)?;
+        let readers = replay(&*fs, &path, &gen_list, &index, &mut gens, &mut 0, true)?;
+
+        let live_gens = Arc::new(LiveGens::new());
+        for &gen in gens.keys() {
+            live_gens.insert(gen);
+        }
 
-        let mut new_pos = 0; // pos in the new log file
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let keyspaces = Arc::new(Keyspaces {
+            path: path.join(KEYSPACES_DIR),
+            fs: Arc::clone(&fs),
+            options: None,
+            open: Mutex::new(BTreeMap::new()),
+        });
+        let reader = KvStoreReader {
+            path,
+            fs,
+            live_gens,
+            readers: RefCell::new(readers),
+            merge_operator,
+        };
+
+        let store = KvStore {
+            reader,
+            index,
+            access: Access::ReadOnly(Arc::new(Mutex::new(gens))),
+            keyspaces,
+            tracker: Arc::new(Mutex::new(WriteTracker::default())),
+            secondary: Arc::new(Mutex::new(None)),
+        };
+        store.refresh_secondary()?;
+        Ok(store)
+    }
+
+    /// Picks up the commands a writer has added to the directory since this
+    /// read-only store was opened or last refreshed.
+    ///
+    /// Appended commands and new log files are replayed incrementally. If a
+    /// log file has been compacted away in the meantime, the whole directory
+    /// is replayed again. Secondary indexes are picked up as well. Does
+    /// nothing for a writable store.
+    pub fn refresh(&self) -> Result<()> {
+        let gens = match &self.access {
+            Access::ReadWrite(_) => return Ok(()),
+            Access::ReadOnly(gens) => gens,
+        };
+        self.refresh_secondary()?;
+        let old_index = match &*self.index {
+            Index::Memory(index) => index,
+            Index::Persistent(_) => unreachable!("read-only stores keep the index in memory"),
+        };
+        let mut gens = gens.lock().unwrap();
+        let path = &*self.reader.path;
+        let fs = &*self.reader.fs;
+        let live_gens = &self.reader.live_gens;
+
+        let gen_list = sorted_gen_list(fs, path)?;
+        if gens.keys().all(|gen| gen_list.binary_search(gen).is_ok()) {
+            for &gen in &gen_list {
+                let start = gens.get(&gen).map_or(0, |stats| stats.total_bytes);
+                let mut reader = BufReaderWithPos::new(fs.open(&log_path(path, gen))?)?;
+                load(gen, &mut reader, start, &self.index, &mut gens, &mut 0, true)?;
+                live_gens.insert(gen);
             }
+            return Ok(());
+        }
 
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
+        // Commands of the vanished log files may have been moved or dropped
+        // before we saw them, so start over without hiding keys meanwhile.
+        let index = Index::Memory(SkipMap::new());
+        let mut new_gens = BTreeMap::new();
+        replay(fs, path, &gen_list, &index, &mut new_gens, &mut 0, true)?;
+        for &gen in new_gens.keys() {
+            live_gens.insert(gen);
+        }
+        let index = match index {
+            Index::Memory(index) => index,
+            Index::Persistent(_) => unreachable!(),
+        };
+        for entry in index.iter() {
+            store_entry(old_index, entry.key().clone(), entry.value().load());
+        }
+        for entry in old_index.iter() {
+            if !index.contains_key(entry.key()) {
+                entry.remove();
+            }
+        }
+        for gen in gens.keys() {
+            if !new_gens.contains_key(gen) {
+                live_gens.remove(*gen);
+            }
         }
-        compaction_writer.flush()?;
+        *gens = new_gens;
+        Ok(())
+    }
 
-        // remove stale log files
-        let stale_gens: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
-            .collect();
-        for stale_gen in stale_gens {
-            self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+    /// Clears all stale entries in the log, regardless of how dirty each
+    /// generation is.
+    pub fn compact(&self) -> Result<()> {
+        self.writer()?.lock().unwrap().compact_all()
+    }
+
+    /// Returns the garbage accounting of every generation, oldest first.
+    pub fn generation_stats(&self) -> Vec<GenerationStats> {
+        match &self.access {
+            Access::ReadWrite(writer) => writer.lock().unwrap().gens.values().cloned().collect(),
+            Access::ReadOnly(gens) => gens.lock().unwrap().values().cloned().collect(),
+        }
+    }
+
+    /// Returns about how many bytes of memory the index takes.
+    pub fn index_memory_usage(&self) -> u64 {
+        self.index.memory_usage()
+    }
+
+    /// Returns the counters of the bloom filters, all zero unless the index
+    /// is kept on disk.
+    pub fn bloom_stats(&self) -> BloomStats {
+        match &*self.index {
+            Index::Memory(_) => BloomStats::default(),
+            Index::Persistent(index) => BloomStats {
+                negatives: index.bloom_negatives.load(Ordering::Relaxed),
+                true_positives: index.bloom_true_positives.load(Ordering::Relaxed),
+                false_positives: index.bloom_false_positives.load(Ordering::Relaxed),
+            },
         }
+    }
 
-        self.uncompacted = 0;
+    /// Returns how often writes were stalled by the stall limits.
+    pub fn stall_stats(&self) -> StallStats {
+        match &self.access {
+            Access::ReadWrite(writer) => writer.lock().unwrap().stall_stats,
+            Access::ReadOnly(_) => StallStats::default(),
+        }
+    }
 
+    /// Opens or refreshes the secondary indexes of a read-only store.
+    fn refresh_secondary(&self) -> Result<()> {
+        let path = self.reader.path.join(SECONDARY_DIR);
+        let mut secondary = self.secondary.lock().unwrap();
+        let store = match &*secondary {
+            Some(indexes) => {
+                let store = indexes.store().clone();
+                store.refresh()?;
+                store
+            }
+            None if self.reader.fs.is_dir(&path) => {
+                KvStore::open_read_only_in(path, Arc::clone(&self.reader.fs), None)?
+            }
+            None => return Ok(()),
+        };
+        *secondary = Some(SecondaryIndexes::open(store)?);
         Ok(())
     }
 
-    /// Create a new log file with given generation number and add the reader to the readers map.
+    /// Returns the secondary indexes, `None` until one is created.
+    fn secondary(&self) -> Option<SecondaryIndexes<KvStore>> {
+        self.secondary.lock().unwrap().clone()
+    }
+
+    /// Adds the secondary index entries of the values a write is about to
+    /// give keys, listed by `changes` with `None` for removals. To be called
+    /// holding the writer, see `SecondaryIndexes::update`.
+    fn update_indexes(
+        &self,
+        changes: impl FnOnce() -> Result<Vec<(String, Option<String>)>>,
+    ) -> Result<IndexUpdate<KvStore>> {
+        let indexes = match self.secondary() {
+            Some(indexes) if !indexes.is_empty() => indexes,
+            _ => return Ok(IndexUpdate::none()),
+        };
+        let changes = changes()?
+            .into_iter()
+            .map(|(key, new)| Ok((key.clone(), self.get(key)?, new)))
+            .collect::<Result<_>>()?;
+        indexes.update(changes)
+    }
+
+    /// Reads with `read` the command of `key` at `cmd_pos`, as the index
+    /// returned it, see `LiveGens::read_latest`.
+    fn read_live<T>(
+        &self,
+        key: &str,
+        cmd_pos: Option<CommandPos>,
+        read: impl FnMut(CommandPos) -> Result<T>,
+    ) -> Result<Option<T>> {
+        let mut found = Some(cmd_pos);
+        self.reader.live_gens.read_latest(
+            || {
+                let cmd_pos = match found.take() {
+                    Some(cmd_pos) => cmd_pos,
+                    None => self.index.get(key)?,
+                };
+                Ok(cmd_pos.map(|cmd_pos| (cmd_pos.gen, cmd_pos)))
+            },
+            read,
+        )
+    }
+
+    /// Runs the compaction the writes have made due, if any. The writer is
+    /// let go while the live commands are copied, so that only the writes
+    /// arriving as the copies are published wait for it.
+    fn maybe_compact(&self, mut writer: MutexGuard<'_, KvStoreWriter>) -> Result<()> {
+        let mut compaction = match writer.due_compaction()? {
+            Some(compaction) => compaction,
+            None => return Ok(()),
+        };
+        drop(writer);
+        let copied = compaction.copy();
+        self.writer()?.lock().unwrap().finish_compaction(compaction, copied)
+    }
+
+    fn writer(&self) -> Result<&Mutex<KvStoreWriter>> {
+        match &self.access {
+            Access::ReadWrite(writer) => Ok(writer),
+            Access::ReadOnly(_) => Err(KvsError::ReadOnly("opened read-only".to_owned())),
+        }
+    }
+
+    /// Returns the keyspace `name`, opening it if needed.
     ///
-    /// Returns the writer to the log.
-    fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+    /// A missing keyspace is created with `create`, and is an error otherwise.
+    fn open_keyspace(&self, name: &str, create: bool) -> Result<KvStore> {
+        check_keyspace_name(name)?;
+        let mut open = self.keyspaces.open.lock().unwrap();
+        if let Some(store) = open.get(name) {
+            return Ok(store.clone());
+        }
+
+        let path = self.keyspaces.path.join(name);
+        let exists = self.keyspaces.fs.is_dir(&path);
+        let mut store = match &self.keyspaces.options {
+            Some(options) if exists || create => {
+                KvStore::open_with_options(&path, options.clone())?
+            }
+            None if exists => KvStore::open_read_only_in(
+                path,
+                Arc::clone(&self.keyspaces.fs),
+                self.reader.merge_operator.clone(),
+            )?,
+            None if create => return Err(KvsError::ReadOnly("opened read-only".to_owned())),
+            _ => return Err(KvsError::KeyspaceNotFound(name.to_owned())),
+        };
+        // keyspaces do not nest, every one of them sees the same keyspaces
+        store.keyspaces = Arc::clone(&self.keyspaces);
+        open.insert(name.to_owned(), store.clone());
+        Ok(store)
     }
 }
 
@@ -140,44 +811,20 @@ impl KvsEngine for KvStore {
     ///
     /// It propagates I/O or serialization errors during writing the log.
     fn set(&self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self
-                .index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into())
-            {
-                self.uncompacted += old_cmd.len;
-            }
-        }
-
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
-        Ok(())
+        let mut writer = self.writer()?.lock().unwrap();
+        let update = self.update_indexes(|| Ok(vec![(key.clone(), Some(value.clone()))]))?;
+        writer.set(key, value)?;
+        update.finish();
+        self.maybe_compact(writer)
     }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
     fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
-            }
-        } else {
-            Ok(None)
-        }
+        self.read_live(&key, self.index.get(&key)?, |cmd_pos| {
+            self.reader.read_value(&key, cmd_pos)
+        })
     }
 
     /// Removes a given key.
@@ -188,46 +835,354 @@ impl KvsEngine for KvStore {
     ///
     /// It propagates I/O or serialization errors during writing the log.
     fn remove(&self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.len;
+        let mut writer = self.writer()?.lock().unwrap();
+        let update = self.update_indexes(|| Ok(vec![(key.clone(), None)]))?;
+        writer.remove(key)?;
+        update.finish();
+        self.maybe_compact(writer)
+    }
+
+    /// Returns the key/value pairs whose keys are in `range`, sorted by key.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for (key, cmd_pos) in self.index.scan(range)? {
+            let value = self.read_live(&key, Some(cmd_pos), |cmd_pos| {
+                self.reader.read_value(&key, cmd_pos)
+            })?;
+            // the key may have been removed while it was read again
+            if let Some(value) = value {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Appends a merge command for `key`, to be folded on reads.
+    ///
+    /// It returns `KvsError::Unsupported` if the store was opened without a
+    /// merge operator.
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        let mut writer = self.writer()?.lock().unwrap();
+        let update = self.update_indexes(|| match &writer.options.merge_operator {
+            Some(operator) => {
+                let old = self.get(key.clone())?;
+                let new = operator.merge(&key, old.as_deref(), &operand);
+                Ok(vec![(key.clone(), Some(new))])
+            }
+            // the merge fails
+            None => Ok(Vec::new()),
+        })?;
+        writer.merge(key, operand)?;
+        update.finish();
+        self.maybe_compact(writer)
+    }
+
+    /// Removes every key in `range` with a single range tombstone in the log.
+    fn delete_range<R: RangeBounds<String>>(&self, range: R) -> Result<()> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut writer = self.writer()?.lock().unwrap();
+        let update = self.update_indexes(|| {
+            if is_empty_range(&range.0, &range.1) {
+                return Ok(Vec::new());
+            }
+            let removed = self.index.scan(range.clone())?;
+            Ok(removed.into_iter().map(|(key, _)| (key, None)).collect())
+        })?;
+        writer.remove_range(range)?;
+        update.finish();
+        self.maybe_compact(writer)
+    }
+
+    /// Adds `delta` to the integer value of `key` while holding the writer,
+    /// so no other write comes in between.
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let mut writer = self.writer()?.lock().unwrap();
+        let value = add_to_value(self.get(key.clone())?.as_deref(), delta)?;
+        let update = self.update_indexes(|| Ok(vec![(key.clone(), Some(value.to_string()))]))?;
+        writer.set(key, value.to_string())?;
+        update.finish();
+        self.maybe_compact(writer)?;
+        Ok(value)
+    }
+
+    /// Returns the versions of `key` kept by `KvStoreOptions::keep_versions`
+    /// and `KvStoreOptions::keep_versions_for`, newest first.
+    ///
+    /// Versions past the limits stay readable until a compaction drops them.
+    fn get_versions(&self, key: String) -> Result<Vec<KeyVersion>> {
+        let history = self.read_live(&key, self.index.get(&key)?, |cmd_pos| {
+            self.reader.read_history(&key, cmd_pos)
+        })?;
+        let history = match history {
+            Some(history) => history,
+            None => return Ok(Vec::new()),
+        };
+        Ok(history
+            .into_iter()
+            .map(|entry| {
+                let stamp = entry.stamp.unwrap_or_default();
+                KeyVersion {
+                    version: stamp.version,
+                    timestamp: stamp.time,
+                    value: entry.value,
+                }
+            })
+            .collect())
+    }
+
+    fn get_at(&self, key: String, at: VersionSpec) -> Result<Option<String>> {
+        let versions = self.get_versions(key)?;
+        let found = versions.into_iter().find(|version| match at {
+            VersionSpec::Version(number) => version.version == number,
+            VersionSpec::Timestamp(time) => version.timestamp <= time,
+        });
+        Ok(found.map(|version| version.value))
+    }
+
+    /// Starts a transaction that commits with a single batch command.
+    ///
+    /// Besides the keys it read, a commit also fails if a key it writes has
+    /// been written since the transaction began. Writes are tracked in memory
+    /// from the oldest open transaction on, with the values they replaced, so
+    /// long-lived transactions keep every key written meanwhile in memory.
+    fn begin(&self) -> Result<Transaction> {
+        // writes are tracked before they become visible, so starting amid
+        // one would let the transaction read the value it writes untracked
+        let _writer = self.writer()?.lock().unwrap();
+        let start = self.tracker.lock().unwrap().begin();
+        Ok(Transaction::new(KvStoreTransaction {
+            store: self.clone(),
+            start,
+        }))
+    }
+
+    /// Watches the keys with `prefix`. Events carry the sequence numbers of
+    /// their records, see `changes_since`.
+    ///
+    /// A store opened read-only cannot be watched.
+    fn watch_prefix(&self, prefix: &str) -> Result<Watcher> {
+        let writer = self.writer()?.lock().unwrap();
+        Ok(writer.watchers.watch(prefix))
+    }
+
+    /// Reads the records written after `seq` from every generation in turn.
+    ///
+    /// Records are numbered from 1 in the order they are written, with gaps
+    /// where writes failed. Compaction drops the numbered records of the
+    /// generations it compacts, the copies it writes carry no numbers. So do
+    /// records written before numbers were kept, which are left out.
+    fn changes_since(&self, seq: u64) -> Result<Changes> {
+        // compactions wait while the log files are opened
+        let writer = match &self.access {
+            Access::ReadWrite(writer) => Some(writer.lock().unwrap()),
+            Access::ReadOnly(_) => None,
+        };
+        let path = &*self.reader.path;
+        let fs = &*self.reader.fs;
+        loop {
+            let horizon = read_seq_state(fs, path)?.horizon;
+            if seq < horizon {
+                return Err(KvsError::HistoryCompacted(format!(
+                    "records up to {} may be gone, asked for those after {}",
+                    horizon, seq
+                )));
+            }
+            match open_log_files(fs, path) {
+                Ok(files) => return Ok(Changes::new(read_changes(files, seq))),
+                // the writing process removed a compacted generation meanwhile
+                Err(KvsError::IO(ref e))
+                    if writer.is_none() && e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
             }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
         }
     }
+
+    /// Creates the secondary index `name`, filled while writes wait.
+    fn create_index(&self, name: &str, pointer: &str) -> Result<()> {
+        let writer = self.writer()?.lock().unwrap();
+        let mut indexes = match self.secondary() {
+            Some(indexes) => indexes,
+            None => {
+                let store = KvStore::open_with_options(
+                    self.reader.path.join(SECONDARY_DIR),
+                    writer.options.for_secondary_indexes(),
+                )?;
+                SecondaryIndexes::open(store)?
+            }
+        };
+        let values = self.index.scan(..)?.into_iter().map(|(key, cmd_pos)| {
+            let value = self.reader.read_value(&key, cmd_pos)?;
+            Ok((key, value))
+        });
+        indexes.create(name, pointer, values)?;
+        *self.secondary.lock().unwrap() = Some(indexes);
+        Ok(())
+    }
+
+    fn drop_index(&self, name: &str) -> Result<()> {
+        let _writer = self.writer()?.lock().unwrap();
+        let mut indexes = self
+            .secondary()
+            .ok_or_else(|| KvsError::IndexNotFound(name.to_owned()))?;
+        indexes.drop_index(name)?;
+        *self.secondary.lock().unwrap() = Some(indexes);
+        Ok(())
+    }
+
+    fn indexes(&self) -> Result<Vec<IndexDefinition>> {
+        Ok(self
+            .secondary()
+            .map_or_else(Vec::new, |indexes| indexes.definitions()))
+    }
+
+    /// Looks the keys up in the index, skipping entries that no longer
+    /// match their values. A read-only store sees the indexes as of the
+    /// last `refresh`.
+    fn index_scan<R: RangeBounds<Value>>(
+        &self,
+        name: &str,
+        range: R,
+    ) -> Result<Vec<(String, String)>> {
+        let indexes = self
+            .secondary()
+            .ok_or_else(|| KvsError::IndexNotFound(name.to_owned()))?;
+        indexes.scan(name, range.start_bound(), range.end_bound(), |key| {
+            self.get(key.to_owned())
+        })
+    }
+
+    fn create_keyspace(&self, name: &str) -> Result<()> {
+        self.open_keyspace(name, true).map(|_| ())
+    }
+
+    fn keyspace(&self, name: &str) -> Result<KvStore> {
+        self.open_keyspace(name, false)
+    }
+
+    /// Drops the keyspace `name` and deletes its directory.
+    ///
+    /// Handles of the keyspace still around fail to write afterwards.
+    fn drop_keyspace(&self, name: &str) -> Result<()> {
+        check_keyspace_name(name)?;
+        if self.keyspaces.options.is_none() {
+            return Err(KvsError::ReadOnly("opened read-only".to_owned()));
+        }
+        let mut open = self.keyspaces.open.lock().unwrap();
+        let path = self.keyspaces.path.join(name);
+        if !self.keyspaces.fs.is_dir(&path) {
+            return Err(KvsError::KeyspaceNotFound(name.to_owned()));
+        }
+        if let Some(store) = open.remove(name) {
+            store.writer()?.lock().unwrap().dropped = Some(name.to_owned());
+        }
+        self.keyspaces.fs.remove_dir_all(&path)?;
+        Ok(())
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let fs = &*self.keyspaces.fs;
+        let entries = match fs.list(&self.keyspaces.path) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = Vec::new();
+        for path in entries {
+            if let Some(name) = path.file_name().and_then(OsStr::to_str) {
+                if fs.is_dir(&path) && check_keyspace_name(name).is_ok() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+}
+
+struct KvStoreTransaction {
+    store: KvStore,
+    // the sequence number of the store when the transaction began
+    start: u64,
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+impl TransactionDriver for KvStoreTransaction {
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        // a write that becomes visible is tracked already, so looking it up
+        // after reading replaces any value newer than the start
+        let value = self.store.get(key.to_owned())?;
+        Ok(self.store.tracker.lock().unwrap().read(key, self.start, value))
+    }
+
+    fn commit(
+        &mut self,
+        reads: &BTreeMap<String, Option<String>>,
+        writes: &BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        let mut writer = self.store.writer()?.lock().unwrap();
+        {
+            let tracker = self.store.tracker.lock().unwrap();
+            for key in reads.keys().chain(writes.keys()) {
+                tracker.check(key, self.start)?;
+            }
+        }
+        let update = self.store.update_indexes(|| {
+            Ok(writes.iter().map(|(key, value)| (key.clone(), value.clone())).collect())
+        })?;
+        writer.apply_batch(writes)?;
+        update.finish();
+        self.store.maybe_compact(writer)
+    }
+}
+
+impl Drop for KvStoreTransaction {
+    fn drop(&mut self) {
+        self.store.tracker.lock().unwrap().end(self.start);
+    }
+}
+
+/// Create a new log file with given generation number.
 ///
-/// Returns the writer to the log.
+/// Returns the writer to the log. The directory is synced, so that the
+/// synced writes to the log are not lost along with its name in a crash.
 fn new_log_file(
+    fs: &dyn FileSystem,
     path: &Path,
     gen: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
-    readers.insert(gen, BufReaderWithPos::new(File::open(&path)?)?);
+) -> Result<BufWriterWithPos<Box<dyn VfsFile>>> {
+    let writer = BufWriterWithPos::new(fs.append(&log_path(path, gen))?)?;
+    fs.sync_dir(path)?;
     Ok(writer)
 }
 
+/// Cuts an incomplete command off the end of the log file of generation
+/// `gen`, left by a crash amid a write that was never acknowledged, and syncs
+/// the file, as the writes after it go to a new one.
+fn repair_log_file(fs: &dyn FileSystem, path: &Path, gen: u64) -> Result<()> {
+    let log_path = log_path(path, gen);
+    let mut stream = Deserializer::from_reader(BufReader::new(fs.open(&log_path)?))
+        .into_iter::<Command>();
+    let mut len = 0;
+    loop {
+        match stream.next() {
+            Some(Ok(_)) => len = stream.byte_offset() as u64,
+            Some(Err(e)) if e.is_eof() => break,
+            Some(Err(e)) => return Err(e.into()),
+            None => return Ok(fs.append(&log_path)?.sync()?),
+        }
+    }
+    warn!("Removing an incomplete command at {} of {}", len, log_path.display());
+    let mut file = fs.append(&log_path)?;
+    file.set_len(len)?;
+    file.sync()?;
+    Ok(())
+}
+
 /// Returns sorted generation numbers in the given directory
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+fn sorted_gen_list(fs: &dyn FileSystem, path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs
+        .list(path)?
+        .into_iter()
+        .filter(|path| fs.is_file(path) && path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
             path.file_name()
                 .and_then(OsStr::to_str)
@@ -240,62 +1195,741 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     Ok(gen_list)
 }
 
-/// Load the whole log file and store value locations in the index map.
+/// Load the log file from `start` on and store value locations in the index map.
 ///
-/// Returns how many bytes can be saved after a compaction.
+/// Stale bytes are accounted to the generation that holds them in `gens`, and
+/// the loaded length of the log file is recorded as its `total_bytes`.
+///
+/// With `allow_torn_tail`, an incomplete command at the end of the file is
+/// left for a later load instead of failing, as it may still be being written.
+///
+/// Raises `last_seq` to the highest sequence number of the commands read.
 fn load(
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    reader: &mut BufReaderWithPos<Box<dyn VfsFile>>,
+    start: u64,
+    index: &Index,
+    gens: &mut BTreeMap<u64, GenerationStats>,
+    last_seq: &mut u64,
+    allow_torn_tail: bool,
+) -> Result<()> {
+    let mut pos = reader.seek(SeekFrom::Start(start))?;
     let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
+    gens.entry(gen).or_insert_with(|| GenerationStats::new(gen));
     while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len;
+        let new_pos = start + stream.byte_offset() as u64;
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            Err(e) if allow_torn_tail && e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        };
+        let batch_pos: CommandPos = (gen, pos..new_pos).into();
+        let cmds = flatten_batch(cmd, batch_pos)?;
+        // the framing around the commands of a batch is never read again
+        let framing = batch_pos.len - cmds.iter().map(|(_, cmd_pos)| cmd_pos.len).sum::<u64>();
+        if framing > 0 {
+            mark_stale(gens, CommandPos { len: framing, ..batch_pos });
+        }
+        for (cmd, cmd_pos) in cmds {
+            *last_seq = cmd.seq().unwrap_or(0).max(*last_seq);
+            match cmd {
+                // the command a merge applies to is stale once the merge is compacted
+                Command::Set { key, .. } | Command::Merge { key, .. } => {
+                    if let Some(old_cmd) = index.get(&key)? {
+                        mark_stale(gens, old_cmd);
+                    }
+                    index.insert(key, cmd_pos);
                 }
-            }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
+                Command::Remove { key, .. } => {
+                    if let Some(old_cmd) = index.remove(&key)? {
+                        mark_stale(gens, old_cmd);
+                    }
+                    // the "remove" command itself can be deleted in the next compaction
+                    // so we count it as stale
+                    mark_stale(gens, cmd_pos);
                 }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
+                Command::RemoveRange { start, end, .. } => {
+                    if !is_empty_range(&start, &end) {
+                        for (key, old_cmd) in index.scan((start, end))? {
+                            index.remove(&key)?;
+                            mark_stale(gens, old_cmd);
+                        }
+                    }
+                    mark_stale(gens, cmd_pos);
+                }
+                Command::Batch(_) => return Err(KvsError::UnexpectedCommandType),
             }
         }
         pos = new_pos;
     }
-    Ok(uncompacted)
+    gens.get_mut(&gen).expect("generation not loaded").total_bytes = pos;
+    Ok(())
+}
+
+/// Replays the log files of `gen_list` in order.
+///
+/// Returns the readers used for the replay so that they can be reused.
+fn replay(
+    fs: &dyn FileSystem,
+    path: &Path,
+    gen_list: &[u64],
+    index: &Index,
+    gens: &mut BTreeMap<u64, GenerationStats>,
+    last_seq: &mut u64,
+    allow_torn_tail: bool,
+) -> Result<BTreeMap<u64, BufReaderWithPos<Box<dyn VfsFile>>>> {
+    let mut readers = BTreeMap::new();
+    for &gen in gen_list {
+        let mut reader = BufReaderWithPos::new(fs.open(&log_path(path, gen))?)?;
+        load(gen, &mut reader, 0, index, gens, last_seq, allow_torn_tail)?;
+        readers.insert(gen, reader);
+    }
+    Ok(readers)
+}
+
+/// Restores the persistent `index` of the log files of `gen_list`.
+///
+/// Generations with an up to date index file are not read at all. The others
+/// are replayed in order and sealed right away, once `seqs` covers them.
+fn replay_sealed(
+    fs: &dyn FileSystem,
+    path: &Path,
+    gen_list: &[u64],
+    index: &Index,
+    gens: &mut BTreeMap<u64, GenerationStats>,
+    seqs: &mut SeqState,
+) -> Result<()> {
+    let dead_bytes_path = path.join(DEAD_BYTES_FILE);
+    let dead_bytes: BTreeMap<u64, u64> = if fs.exists(&dead_bytes_path) {
+        serde_json::from_reader(BufReader::new(fs.open(&dead_bytes_path)?))?
+    } else {
+        BTreeMap::new()
+    };
+    for &gen in gen_list {
+        let log_len = fs.open(&log_path(path, gen))?.seek(SeekFrom::End(0))?;
+        if index.open_sealed(gen, log_len) {
+            gens.insert(
+                gen,
+                GenerationStats {
+                    gen,
+                    total_bytes: log_len,
+                    dead_bytes: dead_bytes.get(&gen).cloned().unwrap_or(0),
+                },
+            );
+        } else {
+            let mut reader = BufReaderWithPos::new(fs.open(&log_path(path, gen))?)?;
+            load(gen, &mut reader, 0, index, gens, &mut seqs.last, false)?;
+            write_seq_state(fs, path, seqs)?;
+            index.seal(gen, gens[&gen].total_bytes)?;
+        }
+    }
+    write_dead_bytes(fs, path, gens)
+}
+
+/// Opens the log files of the store at `path`, oldest first.
+fn open_log_files(fs: &dyn FileSystem, path: &Path) -> Result<Vec<Box<dyn VfsFile>>> {
+    sorted_gen_list(fs, path)?
+        .into_iter()
+        .map(|gen| Ok(fs.open(&log_path(path, gen))?))
+        .collect()
+}
+
+/// Returns the changes of the records in `files` numbered above `seq`.
+///
+/// An incomplete record ends the changes, as it may still be being written.
+fn read_changes(files: Vec<Box<dyn VfsFile>>, seq: u64) -> impl Iterator<Item = Result<Change>> {
+    files
+        .into_iter()
+        .flat_map(|file| Deserializer::from_reader(BufReader::new(file)).into_iter::<Command>())
+        .take_while(|cmd| !matches!(cmd, Err(e) if e.is_eof()))
+        .flat_map(|cmd| match cmd {
+            Ok(Command::Batch(cmds)) => cmds.into_iter().map(Ok).collect(),
+            cmd => vec![cmd.map_err(KvsError::from)],
+        })
+        .filter_map(move |cmd| match cmd {
+            Ok(cmd) => cmd.into_change().filter(|change| change.seq > seq).map(Ok),
+            Err(e) => Some(Err(e)),
+        })
+}
+
+/// The sequence numbers of the records of a store, kept apart from the log
+/// files as compaction drops records.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct SeqState {
+    // no record has a higher sequence number, except in generations that
+    // have not been sealed
+    last: u64,
+    // records up to this sequence number may have been compacted away
+    horizon: u64,
+}
+
+/// Reads the sequence numbers of the store at `path`, zero if it has none.
+fn read_seq_state(fs: &dyn FileSystem, path: &Path) -> Result<SeqState> {
+    let seq_path = path.join(SEQ_FILE);
+    if fs.exists(&seq_path) {
+        Ok(serde_json::from_reader(BufReader::new(fs.open(&seq_path)?))?)
+    } else {
+        Ok(SeqState::default())
+    }
+}
+
+/// Replaces the recorded sequence numbers of the store at `path`.
+fn write_seq_state(fs: &dyn FileSystem, path: &Path, seqs: &SeqState) -> Result<()> {
+    let tmp_path = path.join(format!("{}.tmp", SEQ_FILE));
+    let mut file = fs.create(&tmp_path)?;
+    serde_json::to_writer(&mut file, seqs)?;
+    file.sync()?;
+    fs.rename(&tmp_path, &path.join(SEQ_FILE))?;
+    fs.sync_dir(path)?;
+    Ok(())
+}
+
+/// Records the dead bytes of every generation, which are not kept in the
+/// index files as they grow after sealing.
+fn write_dead_bytes(
+    fs: &dyn FileSystem,
+    path: &Path,
+    gens: &BTreeMap<u64, GenerationStats>,
+) -> Result<()> {
+    let dead_bytes: BTreeMap<u64, u64> = gens
+        .values()
+        .map(|stats| (stats.gen, stats.dead_bytes))
+        .collect();
+    let tmp_path = path.join(format!("{}.tmp", DEAD_BYTES_FILE));
+    let mut writer = BufWriter::new(fs.create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, &dead_bytes)?;
+    writer.flush()?;
+    writer.get_mut().sync()?;
+    fs.rename(&tmp_path, &path.join(DEAD_BYTES_FILE))?;
+    fs.sync_dir(path)?;
+    Ok(())
+}
+
+/// Removes index files and bloom filters except those of the generations in
+/// `keep`, together with leftovers of files that were never completed.
+fn remove_index_files(fs: &dyn FileSystem, path: &Path, keep: &[u64]) -> Result<()> {
+    let extensions = [INDEX_EXTENSION, index_file::BLOOM_EXTENSION];
+    for path in fs.list(path)? {
+        if !fs.is_file(&path) {
+            continue;
+        }
+        let name = match path.file_name().and_then(OsStr::to_str) {
+            Some(name) => name,
+            None => continue,
+        };
+        let stale = if extensions
+            .iter()
+            .any(|extension| name.ends_with(&format!(".{}.tmp", extension)))
+            || name == format!("{}.tmp", DEAD_BYTES_FILE)
+        {
+            true
+        } else if name == DEAD_BYTES_FILE {
+            keep.is_empty()
+        } else {
+            extensions
+                .iter()
+                .find_map(|extension| name.strip_suffix(&format!(".{}", extension)))
+                .and_then(|gen| gen.parse::<u64>().ok())
+                .is_some_and(|gen| keep.binary_search(&gen).is_err())
+        };
+        if stale {
+            fs.remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Accounts the command at `cmd_pos` as stale in its generation.
+fn mark_stale(gens: &mut BTreeMap<u64, GenerationStats>, cmd_pos: CommandPos) {
+    if let Some(stats) = gens.get_mut(&cmd_pos.gen) {
+        stats.dead_bytes += cmd_pos.len;
+    }
 }
 
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
+fn index_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}", gen, INDEX_EXTENSION))
+}
+
+fn compaction_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.{}", gen, COMPACTION_EXTENSION))
+}
+
+/// Removes compaction files left behind by a compaction that did not finish.
+fn remove_unfinished_compactions(fs: &dyn FileSystem, path: &Path) -> Result<()> {
+    for path in fs.list(path)? {
+        if fs.is_file(&path) && path.extension() == Some(COMPACTION_EXTENSION.as_ref()) {
+            warn!("Removing unfinished compaction file {}", path.display());
+            fs.remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// A key moved by a compaction and the new position of its "set" command,
+/// `None` if its "remove" command was moved.
+type MovedKey = (String, Option<CommandPos>);
+
+/// Maps each key to the position of its latest "set" command.
+enum Index {
+    // every key is held in memory
+    Memory(SkipMap<String, AtomicCell<CommandPos>>),
+    // only the keys of the generation being written are held in memory
+    Persistent(PersistentIndex),
+}
+
+/// Points `key` at `value` in `map`.
+///
+/// An entry already there is updated in place: replacing it, as
+/// `SkipMap::insert` does, hides the key from readers for a moment.
+fn store_entry<V: Copy + Send + 'static>(map: &SkipMap<String, AtomicCell<V>>, key: String, value: V) {
+    match map.get(&key) {
+        Some(entry) if !entry.is_removed() => entry.value().store(value),
+        _ => {
+            map.insert(key, AtomicCell::new(value));
+        }
+    }
+}
+
+struct PersistentIndex {
+    path: Arc<PathBuf>,
+    fs: Arc<dyn FileSystem>,
+    budget: u64,
+    bloom_false_positive_rate: f64,
+    // latest commands of the generation being written, `None` marks a removal
+    recent: SkipMap<String, AtomicCell<Option<CommandPos>>>,
+    // approximate memory taken by `recent`
+    recent_size: AtomicU64,
+    // index files of the sealed generations
+    sealed: RwLock<BTreeMap<u64, Arc<IndexFile>>>,
+    bloom_negatives: AtomicU64,
+    bloom_true_positives: AtomicU64,
+    bloom_false_positives: AtomicU64,
+}
+
+impl Index {
+    fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        let index = match self {
+            Index::Memory(index) => return Ok(index.get(key).map(|entry| entry.value().load())),
+            Index::Persistent(index) => index,
+        };
+        if let Some(entry) = index.recent.get(key) {
+            return Ok(entry.value().load());
+        }
+        // the newest generation holding the key has its latest command
+        let hash = BloomFilter::hash(key.as_bytes());
+        let sealed = index.sealed.read().unwrap();
+        for (&gen, file) in sealed.iter().rev() {
+            if !file.may_contain(hash) {
+                index.bloom_negatives.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if let Some(cmd_pos) = file.get(key)? {
+                index.bloom_true_positives.fetch_add(1, Ordering::Relaxed);
+                return Ok(cmd_pos.map(|(pos, len)| CommandPos { gen, pos, len }));
+            }
+            index.bloom_false_positives.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(None)
+    }
+
+    /// Returns the keys in `range` and the positions of their latest "set"
+    /// commands, sorted by key.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, CommandPos)>> {
+        let index = match self {
+            Index::Memory(index) => {
+                return Ok(index
+                    .range(range)
+                    .map(|entry| (entry.key().clone(), entry.value().load()))
+                    .collect())
+            }
+            Index::Persistent(index) => index,
+        };
+        // Holding the lock keeps sealing from moving keys out of `recent`
+        // while they are collected.
+        let sealed = index.sealed.read().unwrap();
+        let mut latest = BTreeMap::new();
+        for (&gen, file) in sealed.iter() {
+            for (key, cmd_pos) in file.scan(&range)? {
+                latest.insert(key, cmd_pos.map(|(pos, len)| CommandPos { gen, pos, len }));
+            }
+        }
+        for entry in index.recent.range(range) {
+            latest.insert(entry.key().clone(), entry.value().load());
+        }
+        Ok(latest
+            .into_iter()
+            .filter_map(|(key, cmd_pos)| cmd_pos.map(|cmd_pos| (key, cmd_pos)))
+            .collect())
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn insert(&self, key: String, cmd_pos: CommandPos) {
+        match self {
+            Index::Memory(index) => store_entry(index, key, cmd_pos),
+            Index::Persistent(index) => index.insert_recent(key, Some(cmd_pos)),
+        }
+    }
+
+    /// Removes `key` and returns the position of its latest "set" command.
+    fn remove(&self, key: &str) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Memory(index) => Ok(index.remove(key).map(|entry| entry.value().load())),
+            Index::Persistent(index) => {
+                let old_cmd = self.get(key)?;
+                if old_cmd.is_some() {
+                    // hides the key in the sealed generations
+                    index.insert_recent(key.to_owned(), None);
+                }
+                Ok(old_cmd)
+            }
+        }
+    }
+
+    /// Returns whether the keys held in memory take more than the budget.
+    fn over_budget(&self) -> bool {
+        match self {
+            Index::Memory(_) => false,
+            Index::Persistent(index) => index.recent_size.load(Ordering::SeqCst) > index.budget,
+        }
+    }
+
+    /// Writes the keys held in memory, which all belong to generation `gen`,
+    /// to the index file of the generation and drops them from memory.
+    fn seal(&self, gen: u64, log_len: u64) -> Result<()> {
+        let index = match self {
+            Index::Memory(_) => return Ok(()),
+            Index::Persistent(index) => index,
+        };
+        let entries = index.recent.iter().map(|entry| {
+            let cmd_pos = entry.value().load().map(|cmd_pos| (cmd_pos.pos, cmd_pos.len));
+            (entry.key().clone(), cmd_pos)
+        });
+        index.add_sealed(gen, entries, log_len)?;
+        for entry in index.recent.iter() {
+            entry.remove();
+        }
+        index.recent_size.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Publishes the commands a compaction moved into generation `gen`.
+    /// Removed keys are only needed by a persistent index.
+    fn publish_compaction(
+        &self,
+        gen: u64,
+        log_len: u64,
+        mut moved: Vec<MovedKey>,
+    ) -> Result<()> {
+        match self {
+            Index::Memory(index) => {
+                for (key, cmd_pos) in moved {
+                    if let Some(cmd_pos) = cmd_pos {
+                        store_entry(index, key, cmd_pos);
+                    }
+                }
+                Ok(())
+            }
+            Index::Persistent(index) => {
+                moved.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+                // a key may have been removed in several of the compacted generations
+                moved.dedup_by(|(a, _), (b, _)| a == b);
+                let entries = moved
+                    .into_iter()
+                    .map(|(key, cmd_pos)| (key, cmd_pos.map(|cmd_pos| (cmd_pos.pos, cmd_pos.len))));
+                index.add_sealed(gen, entries, log_len)
+            }
+        }
+    }
+
+    /// Returns the keys in `range` that the index files of the generations
+    /// before `gen` and not in `selected` hold, but that have no value.
+    /// Always empty for an index in memory.
+    fn removed_keys(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        gen: u64,
+        selected: &BTreeSet<u64>,
+    ) -> Result<Vec<String>> {
+        let index = match self {
+            Index::Memory(_) => return Ok(Vec::new()),
+            Index::Persistent(index) => index,
+        };
+        let files: Vec<_> = index
+            .sealed
+            .read()
+            .unwrap()
+            .range(..gen)
+            .filter(|(gen, _)| !selected.contains(gen))
+            .map(|(_, file)| Arc::clone(file))
+            .collect();
+        let mut keys = BTreeSet::new();
+        for file in files {
+            keys.extend(file.scan(&range)?.into_iter().map(|(key, _)| key));
+        }
+        let mut removed = Vec::new();
+        for key in keys {
+            if !self.contains_key(&key)? {
+                removed.push(key);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Forgets generation `gen` after its log file has been removed.
+    fn remove_gen(&self, gen: u64) -> Result<()> {
+        if let Index::Persistent(index) = self {
+            index.sealed.write().unwrap().remove(&gen);
+            let path = index_path(&index.path, gen);
+            for path in &[index_file::bloom_path(&path), path] {
+                match index.fs.remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens the index file of generation `gen` unless it is missing or was
+    /// built from a log file of another length than `log_len`.
+    fn open_sealed(&self, gen: u64, log_len: u64) -> bool {
+        let index = match self {
+            Index::Memory(_) => return false,
+            Index::Persistent(index) => index,
+        };
+        let path = index_path(&index.path, gen);
+        if !index.fs.exists(&path) {
+            return false;
+        }
+        match IndexFile::open(&*index.fs, &path) {
+            Ok(file) if file.log_len() == log_len => {
+                index.sealed.write().unwrap().insert(gen, Arc::new(file));
+                true
+            }
+            Ok(_) => {
+                warn!("Rebuilding index file {}: log file has changed", path.display());
+                false
+            }
+            Err(e) => {
+                warn!("Rebuilding index file {}: {}", path.display(), e);
+                false
+            }
+        }
+    }
+
+    fn memory_usage(&self) -> u64 {
+        match self {
+            Index::Memory(index) => index
+                .iter()
+                .map(|entry| entry.key().len() as u64 + INDEX_ENTRY_OVERHEAD)
+                .sum(),
+            Index::Persistent(index) => {
+                let sealed: u64 = index
+                    .sealed
+                    .read()
+                    .unwrap()
+                    .values()
+                    .map(|file| file.memory_usage())
+                    .sum();
+                index.recent_size.load(Ordering::SeqCst) + sealed
+            }
+        }
+    }
+
+    fn is_persistent(&self) -> bool {
+        matches!(self, Index::Persistent(_))
+    }
+}
+
+impl PersistentIndex {
+    fn insert_recent(&self, key: String, cmd_pos: Option<CommandPos>) {
+        if !self.recent.contains_key(&key) {
+            self.recent_size
+                .fetch_add(key.len() as u64 + INDEX_ENTRY_OVERHEAD, Ordering::SeqCst);
+        }
+        store_entry(&self.recent, key, cmd_pos);
+    }
+
+    fn add_sealed(
+        &self,
+        gen: u64,
+        entries: impl IntoIterator<Item = IndexEntry>,
+        log_len: u64,
+    ) -> Result<()> {
+        let file = IndexFile::write(
+            &*self.fs,
+            &index_path(&self.path, gen),
+            entries,
+            log_len,
+            self.bloom_false_positive_rate,
+        )?;
+        self.sealed.write().unwrap().insert(gen, Arc::new(file));
+        Ok(())
+    }
+}
+
 /// Struct representing a command
 #[derive(Serialize, Deserialize, Debug)]
+// `seq` is the sequence number of the record, see `KvStore::changes_since`
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    // `prev` is the version the command replaces, kept with history
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stamp: Option<Stamp>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prev: Option<CommandPos>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    Remove {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    // removes every key between the bounds
+    RemoveRange {
+        start: Bound<String>,
+        end: Bound<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    // `prev` is the latest command of the key before the merge
+    Merge {
+        key: String,
+        operand: String,
+        prev: Option<CommandPos>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stamp: Option<Stamp>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    // "set" and "remove" commands written at once, see `encode_batch`
+    Batch(Vec<Command>),
 }
 
-impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+/// Serializes `cmds` as a batch command, the same as `serde_json` would.
+///
+/// Returns the bytes with the range of each command in them. The index
+/// points into the batch, where each command can be read on its own.
+fn encode_batch(cmds: &[Command]) -> Result<(Vec<u8>, Vec<Range<u64>>)> {
+    let mut bytes = b"{\"Batch\":[".to_vec();
+    let mut ranges = Vec::with_capacity(cmds.len());
+    for (i, cmd) in cmds.iter().enumerate() {
+        if i > 0 {
+            bytes.push(b',');
+        }
+        let start = bytes.len() as u64;
+        serde_json::to_writer(&mut bytes, cmd)?;
+        ranges.push(start..bytes.len() as u64);
+    }
+    bytes.extend_from_slice(b"]}");
+    Ok((bytes, ranges))
+}
+
+/// Returns the commands of the command at `cmd_pos`, with their positions:
+/// the commands of a batch, or the command itself.
+fn flatten_batch(cmd: Command, cmd_pos: CommandPos) -> Result<Vec<(Command, CommandPos)>> {
+    match cmd {
+        Command::Batch(cmds) => {
+            let (bytes, ranges) = encode_batch(&cmds)?;
+            // the positions are only right for a batch laid out as it is written
+            if bytes.len() as u64 != cmd_pos.len {
+                return Err(corrupted(cmd_pos, "batch not laid out as written"));
+            }
+            Ok(cmds
+                .into_iter()
+                .zip(ranges)
+                .map(|(cmd, range)| {
+                    let pos = CommandPos {
+                        gen: cmd_pos.gen,
+                        pos: cmd_pos.pos + range.start,
+                        len: range.end - range.start,
+                    };
+                    (cmd, pos)
+                })
+                .collect())
+        }
+        cmd => Ok(vec![(cmd, cmd_pos)]),
+    }
+}
+
+fn corrupted(cmd_pos: CommandPos, reason: &str) -> KvsError {
+    KvsError::IO(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("log {} is corrupted at {}: {}", cmd_pos.gen, cmd_pos.pos, reason),
+    ))
+}
+
+/// The version a command writes, kept with history.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct Stamp {
+    version: u64,
+    // milliseconds since the Unix epoch
+    time: u64,
+}
+
+impl Command {
+    fn set(key: String, value: String, seq: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            stamp: None,
+            prev: None,
+            seq,
+        }
+    }
+
+    fn remove(key: String, seq: Option<u64>) -> Command {
+        Command::Remove { key, seq }
+    }
+
+    /// Returns the change the command records, `None` if it has no sequence
+    /// number.
+    fn into_change(self) -> Option<Change> {
+        let (seq, kind) = match self {
+            Command::Set { key, value, seq, .. } => (seq?, ChangeKind::Set { key, value }),
+            Command::Remove { key, seq } => (seq?, ChangeKind::Remove { key }),
+            Command::RemoveRange { start, end, seq } => {
+                (seq?, ChangeKind::RemoveRange { start, end })
+            }
+            Command::Merge {
+                key, operand, seq, ..
+            } => (seq?, ChangeKind::Merge { key, operand }),
+            Command::Batch(_) => return None,
+        };
+        Some(Change { seq, kind })
     }
 
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
+    /// Returns the sequence number of the command, `None` for a batch.
+    fn seq(&self) -> Option<u64> {
+        match self {
+            Command::Set { seq, .. }
+            | Command::Remove { seq, .. }
+            | Command::RemoveRange { seq, .. }
+            | Command::Merge { seq, .. } => *seq,
+            Command::Batch(_) => None,
+        }
     }
 }
 
 /// Represents the position and length of a json-serialized command in the log
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 struct CommandPos {
     gen: u64,
     pos: u64,
@@ -319,7 +1953,7 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos,
@@ -349,7 +1983,7 @@ struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.seek(SeekFrom::End(0))?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
@@ -369,6 +2003,13 @@ impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     }
 }
 
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    /// Drops the writer without flushing the bytes it still buffers.
+    fn discard(self) -> W {
+        self.writer.into_parts().0
+    }
+}
+
 impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         self.pos = self.writer.seek(pos)?;
@@ -377,37 +2018,56 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
 }
 
 
+/// A single-threaded reader of the log files.
+///
+/// Each clone keeps its own file handles. Handles of generations that have
+/// been compacted away are closed lazily on the next read.
 struct KvStoreReader {
     path: Arc<PathBuf>,
-    safe_point: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>
+    fs: Arc<dyn FileSystem>,
+    // generations whose log files still exist
+    live_gens: Arc<LiveGens>,
+    readers: RefCell<BTreeMap<u64, BufReaderWithPos<Box<dyn VfsFile>>>>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+/// A version of a key read from the log.
+struct HistoryEntry {
+    cmd_pos: CommandPos,
+    stamp: Option<Stamp>,
+    value: String,
+}
+
+/// The commands that make up the value of a key.
+struct Chain {
+    // the merge commands, newest first
+    merges: Vec<(CommandPos, String)>,
+    // the value the oldest merge applies to, with the position of its command
+    base: Option<(CommandPos, String)>,
 }
 
 impl KvStoreReader {
+    /// Close file handles of generations that no longer exist.
     fn close_stale_handles(&self) {
         let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let first_gen = *readers.keys().next().unwrap();
-            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
-                break;
-            }
-            readers.remove(&first_gen);
-        }
+        let live_gens = &self.live_gens;
+        readers.retain(|gen, _| live_gens.contains(*gen));
     }
 
     fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
         where
-            F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>, {
+            F: FnOnce(io::Take<&mut BufReaderWithPos<Box<dyn VfsFile>>>) -> Result<R>, {
 
         self.close_stale_handles();
 
         let mut readers = self.readers.borrow_mut();
 
-        if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, cmd_pos.gen))?)?;
-            readers.insert(cmd_pos.gen, reader);
-        }
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        let reader = match readers.entry(cmd_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(BufReaderWithPos::new(
+                self.fs.open(&log_path(&self.path, cmd_pos.gen))?,
+            )?),
+        };
         reader.seek(SeekFrom::Start(cmd_pos.pos))?;
         let cmd_reader = reader.take(cmd_pos.len);
         f(cmd_reader)
@@ -418,49 +2078,1091 @@ impl KvStoreReader {
             Ok(serde_json::from_reader(cmd_reader)?)
         })
     }
+
+    /// Reads the value whose latest command is at `cmd_pos`, folding merges.
+    fn read_value(&self, key: &str, cmd_pos: CommandPos) -> Result<String> {
+        self.fold(key, self.read_chain(cmd_pos)?)
+    }
+
+    /// Follows the merge commands from `cmd_pos` back to the value they
+    /// apply to.
+    fn read_chain(&self, cmd_pos: CommandPos) -> Result<Chain> {
+        let mut merges = Vec::new();
+        let mut seen = HashSet::new();
+        let mut next = Some(cmd_pos);
+        while let Some(cmd_pos) = next {
+            check_unseen(&mut seen, cmd_pos)?;
+            match self.read_command(cmd_pos)? {
+                Command::Set { value, .. } => {
+                    return Ok(Chain {
+                        merges,
+                        base: Some((cmd_pos, value)),
+                    })
+                }
+                Command::Merge { operand, prev, .. } => {
+                    merges.push((cmd_pos, operand));
+                    next = prev;
+                }
+                Command::Remove { .. }
+                | Command::RemoveRange { .. }
+                | Command::Batch(_) => {
+                    return Err(KvsError::UnexpectedCommandType)
+                }
+            }
+        }
+        Ok(Chain { merges, base: None })
+    }
+
+    /// Reads every version of `key` linked from its latest command at
+    /// `cmd_pos`, newest first.
+    fn read_history(&self, key: &str, cmd_pos: CommandPos) -> Result<Vec<HistoryEntry>> {
+        let mut cmds = Vec::new();
+        let mut seen = HashSet::new();
+        let mut next = Some(cmd_pos);
+        while let Some(cmd_pos) = next {
+            check_unseen(&mut seen, cmd_pos)?;
+            let cmd = self.read_command(cmd_pos)?;
+            next = match cmd {
+                Command::Set { prev, .. } | Command::Merge { prev, .. } => prev,
+                Command::Remove { .. }
+                | Command::RemoveRange { .. }
+                | Command::Batch(_) => {
+                    return Err(KvsError::UnexpectedCommandType)
+                }
+            };
+            cmds.push((cmd_pos, cmd));
+        }
+
+        let mut history = Vec::with_capacity(cmds.len());
+        let mut value = None;
+        for (cmd_pos, cmd) in cmds.into_iter().rev() {
+            let stamp = match cmd {
+                Command::Set {
+                    value: set, stamp, ..
+                } => {
+                    value = Some(set);
+                    stamp
+                }
+                Command::Merge { operand, stamp, .. } => {
+                    value = Some(self.merge_operator()?.merge(key, value.as_deref(), &operand));
+                    stamp
+                }
+                Command::Remove { .. }
+                | Command::RemoveRange { .. }
+                | Command::Batch(_) => unreachable!(),
+            };
+            history.push(HistoryEntry {
+                cmd_pos,
+                stamp,
+                value: value.clone().expect("no value read"),
+            });
+        }
+        history.reverse();
+        Ok(history)
+    }
+
+    fn merge_operator(&self) -> Result<&Arc<dyn MergeOperator>> {
+        self.merge_operator.as_ref().ok_or_else(|| {
+            KvsError::Unsupported("merged values without a merge operator".to_owned())
+        })
+    }
+
+    fn fold(&self, key: &str, chain: Chain) -> Result<String> {
+        let mut value = chain.base.map(|(_, value)| value);
+        if chain.merges.is_empty() {
+            return value.ok_or(KvsError::UnexpectedCommandType);
+        }
+        let operator = self.merge_operator()?;
+        for (_, operand) in chain.merges.iter().rev() {
+            value = Some(operator.merge(key, value.as_deref(), operand));
+        }
+        Ok(value.expect("no value folded"))
+    }
+}
+
+/// Reads the value and the versions of a key whose latest command is at
+/// `pos` in the log file holding `log`, for the fuzz targets.
+#[cfg(fuzzing)]
+pub(crate) fn read_record(log: &[u8], pos: u64, len: u64) -> Result<()> {
+    let fs = Arc::new(crate::MemoryFileSystem::new());
+    let path = Arc::new(PathBuf::from("store"));
+    fs.create_dir_all(&path)?;
+    fs.create(&log_path(&path, 1))?.write_all(log)?;
+    let live_gens = LiveGens::new();
+    live_gens.insert(1);
+    let reader = KvStoreReader {
+        path,
+        fs,
+        live_gens: Arc::new(live_gens),
+        readers: RefCell::new(BTreeMap::new()),
+        merge_operator: Some(Arc::new(crate::conformance::append)),
+    };
+    let cmd_pos = CommandPos { gen: 1, pos, len };
+    let _ = reader.read_value("key", cmd_pos);
+    reader.read_history("key", cmd_pos)?;
+    Ok(())
+}
+
+/// Records that the versions of a key lead to `cmd_pos`, failing if they
+/// did before: a corrupt log could make them go round forever.
+fn check_unseen(seen: &mut HashSet<CommandPos>, cmd_pos: CommandPos) -> Result<()> {
+    if seen.insert(cmd_pos) {
+        Ok(())
+    } else {
+        Err(corrupted(cmd_pos, "the versions of a key form a cycle"))
+    }
 }
 
 impl Clone for KvStoreReader {
     fn clone(&self) -> KvStoreReader {
         KvStoreReader {
             path: Arc::clone(&self.path),
-            safe_point: Arc::clone(&self.safe_point),
+            fs: Arc::clone(&self.fs),
+            live_gens: Arc::clone(&self.live_gens),
             readers: RefCell::new(BTreeMap::new()),
+            merge_operator: self.merge_operator.clone(),
         }
     }
 }
 
 struct KvStoreWriter {
-    reader: KvStoreReader,
-    writer: BufReaderWithPos<File>,
+    writer: BufWriterWithPos<Box<dyn VfsFile>>,
     current_gen: u64,
+    // the number of bytes representing "stale" commands that could be
+    // deleted during a compaction
     uncompacted: u64,
+    // garbage accounting of every generation on disk
+    gens: BTreeMap<u64, GenerationStats>,
+    live_gens: Arc<LiveGens>,
     path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<Index>,
+    options: KvStoreOptions,
+    stall_stats: StallStats,
+    // whether writes are refused because the disk is (almost) full
+    out_of_space: bool,
+    // whether writes are refused because a failed write could not be cut
+    // off the log, until the store is opened again
+    torn: bool,
+    // bytes written since the free space was last checked
+    unchecked_bytes: u64,
+    // the name of the keyspace once it has been dropped
+    dropped: Option<String>,
+    // reads the versions that writes and compactions link to
+    reader: KvStoreReader,
+    tracker: Arc<Mutex<WriteTracker>>,
+    watchers: Watchers,
+    seqs: SeqState,
+    // the cancel flag of the compaction copying without the writer, if any
+    compacting: Option<Arc<AtomicBool>>,
 }
 
 impl KvStoreWriter {
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
+        self.check_writable()?;
+        self.check_free_space()?;
+        self.stall_writes()?;
+        let replaced = self.replaced_value(&key)?;
+        let seq = self.next_seq();
+        let cmd = self.set_command(key, value, seq)?;
+        let range = self.append(&cmd)?;
+
+        if let Command::Set { key, value, .. } = cmd {
+            self.tracker.lock().unwrap().record(&key, replaced);
+            if let Some(old_cmd) = self.index.get(&key)? {
+                self.mark_stale(old_cmd);
+            }
+            self.index.insert(key.clone(), (self.current_gen, range).into());
+            self.notify(key, seq, || Ok(Some(value)))?;
+        }
+
+        if self.index.over_budget() {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.check_writable()?;
+        if self.index.contains_key(&key)? {
+            self.check_free_space()?;
+            self.stall_writes()?;
+            let replaced = self.replaced_value(&key)?;
+            let seq = self.next_seq();
+            let cmd = Command::remove(key, Some(seq));
+            let range = self.append(&cmd)?;
+
+            if let Command::Remove { key, .. } = cmd {
+                self.tracker.lock().unwrap().record(&key, replaced);
+                let old_cmd = self.index.remove(&key)?.expect("key not found");
+                self.mark_stale(old_cmd);
+                // the "remove" command itself can be deleted in the next compaction
+                self.mark_stale((self.current_gen, range).into());
+                self.notify(key, seq, || Ok(None))?;
+            }
+
+            if self.index.over_budget() {
+                self.rotate()?;
+            }
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// Removes the keys in `range`, unless there are none.
+    fn remove_range(&mut self, range: (Bound<String>, Bound<String>)) -> Result<()> {
+        self.check_writable()?;
+        self.check_free_space()?;
+        self.stall_writes()?;
+        if is_empty_range(&range.0, &range.1) {
+            return Ok(());
+        }
+        let removed = self.index.scan(range.clone())?;
+        if removed.is_empty() {
+            return Ok(());
+        }
+        let replaced = removed
+            .iter()
+            .map(|(key, _)| self.replaced_value(key))
+            .collect::<Result<Vec<_>>>()?;
+        let (start, end) = range;
+        let seq = self.next_seq();
+        let cmd_range = self.append(&Command::RemoveRange {
+            start,
+            end,
+            seq: Some(seq),
+        })?;
+
+        {
+            let mut tracker = self.tracker.lock().unwrap();
+            for ((key, _), replaced) in removed.iter().zip(replaced) {
+                tracker.record(key, replaced);
+            }
+        }
+        for (key, old_cmd) in &removed {
+            self.index.remove(key)?;
+            self.mark_stale(*old_cmd);
+        }
+        // like a "remove" command, the range tombstone can go in the next compaction
+        self.mark_stale((self.current_gen, cmd_range).into());
+        for (key, _) in removed {
+            self.notify(key, seq, || Ok(None))?;
+        }
+
+        if self.index.over_budget() {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Applies `writes`, `None` removing the key, with a single batch command
+    /// that takes effect entirely or not at all.
+    fn apply_batch(&mut self, writes: &BTreeMap<String, Option<String>>) -> Result<()> {
+        self.check_writable()?;
+        self.check_free_space()?;
+        self.stall_writes()?;
+        let mut cmds = Vec::with_capacity(writes.len());
+        let mut seqs = Vec::with_capacity(writes.len());
+        // removes of absent keys write nothing, so the tracker skips them too
+        let mut written = Vec::with_capacity(writes.len());
+        for (key, value) in writes {
+            if value.is_none() && !self.index.contains_key(key)? {
+                continue;
+            }
+            written.push(key);
+            let seq = self.next_seq();
+            let cmd = match value {
+                Some(value) => self.set_command(key.clone(), value.clone(), seq)?,
+                None => Command::remove(key.clone(), Some(seq)),
+            };
+            cmds.push(cmd);
+            seqs.push(seq);
+        }
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        let replaced = written
+            .iter()
+            .map(|key| self.replaced_value(key))
+            .collect::<Result<Vec<_>>>()?;
+        let (bytes, ranges) = encode_batch(&cmds)?;
+        let range = self.append_bytes(&bytes)?;
+
+        {
+            let mut tracker = self.tracker.lock().unwrap();
+            for (key, replaced) in written.into_iter().zip(replaced) {
+                tracker.record(key, replaced);
+            }
+        }
+        // the watchers hear of the writes once all of them are visible
+        let mut events = Vec::with_capacity(cmds.len());
+        let mut framing = range.end - range.start;
+        for ((cmd, cmd_range), seq) in cmds.into_iter().zip(ranges).zip(seqs) {
+            let cmd_pos = CommandPos {
+                gen: self.current_gen,
+                pos: range.start + cmd_range.start,
+                len: cmd_range.end - cmd_range.start,
+            };
+            framing -= cmd_pos.len;
+            match cmd {
+                Command::Set { key, value, .. } => {
+                    if let Some(old_cmd) = self.index.get(&key)? {
+                        self.mark_stale(old_cmd);
+                    }
+                    self.index.insert(key.clone(), cmd_pos);
+                    events.push((key, seq, Some(value)));
+                }
+                Command::Remove { key, .. } => {
+                    let old_cmd = self.index.remove(&key)?.expect("key not found");
+                    self.mark_stale(old_cmd);
+                    self.mark_stale(cmd_pos);
+                    events.push((key, seq, None));
+                }
+                _ => unreachable!("batches only set and remove keys"),
+            }
+        }
+        for (key, seq, value) in events {
+            self.notify(key, seq, || Ok(value))?;
+        }
+        // the framing around the commands is never read again
+        self.mark_stale(CommandPos {
+            gen: self.current_gen,
+            pos: range.start,
+            len: framing,
+        });
+
+        if self.index.over_budget() {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the command setting `key` to `value` as record `seq`, linked
+    /// to the version it replaces if history is kept.
+    fn set_command(&self, key: String, value: String, seq: u64) -> Result<Command> {
+        if !self.options.keeps_history() {
+            return Ok(Command::set(key, value, Some(seq)));
+        }
+        let prev = self.index.get(&key)?;
+        Ok(Command::Set {
+            stamp: Some(self.next_stamp(prev)?),
+            key,
+            value,
+            prev,
+            seq: Some(seq),
+        })
+    }
+
+    fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        self.check_writable()?;
+        if self.options.merge_operator.is_none() {
+            return Err(KvsError::Unsupported("merge without a merge operator".to_owned()));
+        }
+        self.check_free_space()?;
+        self.stall_writes()?;
+        let replaced = self.replaced_value(&key)?;
+        let prev = self.index.get(&key)?;
+        let stamp = if self.options.keeps_history() {
+            Some(self.next_stamp(prev)?)
+        } else {
+            None
+        };
+        let seq = self.next_seq();
+        let cmd = Command::Merge {
+            key,
+            operand,
+            prev,
+            stamp,
+            seq: Some(seq),
+        };
+        let range = self.append(&cmd)?;
+
+        if let Command::Merge { key, .. } = cmd {
+            self.tracker.lock().unwrap().record(&key, replaced);
+            // the previous command is needed until compaction folds the merge
+            if let Some(old_cmd) = prev {
+                self.mark_stale(old_cmd);
+            }
+            let cmd_pos = (self.current_gen, range).into();
+            self.index.insert(key.clone(), cmd_pos);
+            self.notify(key.clone(), seq, || self.reader.read_value(&key, cmd_pos).map(Some))?;
+        }
+
+        if self.index.over_budget() {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the value a write of `key` replaces if an open transaction
+    /// may read it, read before the write is appended so that it cannot fail
+    /// halfway.
+    fn replaced_value(&self, key: &str) -> Result<Option<Option<String>>> {
+        if !self.tracker.lock().unwrap().needs_replaced(key) {
+            return Ok(None);
+        }
+        match self.index.get(key)? {
+            Some(cmd_pos) => Ok(Some(Some(self.reader.read_value(key, cmd_pos)?))),
+            None => Ok(Some(None)),
+        }
+    }
+
+    /// Returns the sequence number of the next record. Records that fail to
+    /// be written leave gaps.
+    fn next_seq(&mut self) -> u64 {
+        self.seqs.last += 1;
+        self.seqs.last
+    }
+
+    /// Tells the watchers of `key` about its write at `seq`, once readers see
+    /// it. `value` returns the new value, and is only called if the key is
+    /// watched.
+    fn notify<F>(&self, key: String, seq: u64, value: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<Option<String>>,
+    {
+        if self.watchers.is_watched(&key) {
+            let value = value()?;
+            self.watchers.send(WatchEvent { key, value, seq });
+        }
+        Ok(())
+    }
+
+    /// Returns the stamp of the version following the one at `prev`.
+    fn next_stamp(&self, prev: Option<CommandPos>) -> Result<Stamp> {
+        let version = match prev {
+            Some(prev) => match self.reader.read_command(prev)? {
+                Command::Set { stamp, .. } | Command::Merge { stamp, .. } => {
+                    stamp.map_or(0, |stamp| stamp.version)
+                }
+                Command::Remove { .. }
+                | Command::RemoveRange { .. }
+                | Command::Batch(_) => {
+                    return Err(KvsError::UnexpectedCommandType)
+                }
+            },
+            None => 0,
+        };
+        Ok(Stamp {
+            version: version + 1,
+            time: now_millis(),
+        })
+    }
+
+    /// Refuses writes to a keyspace that has been dropped, or to a log that
+    /// ends with a torn command.
+    fn check_writable(&self) -> Result<()> {
+        if let Some(name) = &self.dropped {
+            return Err(KvsError::KeyspaceNotFound(name.clone()));
+        }
+        if self.torn {
+            return Err(KvsError::ReadOnly(
+                "a failed write could not be removed from the log".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Appends `cmd` to the current log file and returns where it was written.
+    ///
+    /// A command that could not be written completely is cut off the log, so
+    /// that a failed write never leaves a torn command behind.
+    fn append(&mut self, cmd: &Command) -> Result<Range<u64>> {
+        self.append_bytes(&serde_json::to_vec(cmd)?)
+    }
+
+    /// Appends serialized commands to the current log file.
+    fn append_bytes(&mut self, bytes: &[u8]) -> Result<Range<u64>> {
         let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
+        let sync = self.options.sync_writes;
+        let res = self
+            .writer
+            .write_all(bytes)
+            .and_then(|_| self.writer.flush())
+            .and_then(|_| if sync { self.writer.writer.get_mut().sync() } else { Ok(()) });
+        if let Err(e) = res {
+            if let Err(truncate_err) = self.truncate_current_gen(pos) {
+                warn!("Store turned read-only: unable to remove a failed write: {}", truncate_err);
+                self.torn = true;
+                return Err(e.into());
+            }
+            if e.kind() == io::ErrorKind::StorageFull {
+                warn!("Store turned read-only: {}", e);
+                self.out_of_space = true;
+                return Err(KvsError::ReadOnly(format!("disk is full: {}", e)));
+            }
+            return Err(e.into());
+        }
+
+        let stats = self
+            .gens
+            .get_mut(&self.current_gen)
+            .expect("current generation not tracked");
+        stats.total_bytes = self.writer.pos;
+        self.unchecked_bytes += self.writer.pos - pos;
+        Ok(pos..self.writer.pos)
+    }
+
+    /// Drops everything after `pos` from the current log file, including
+    /// bytes still buffered in the writer.
+    fn truncate_current_gen(&mut self, pos: u64) -> Result<()> {
+        let mut file = self.fs().append(&log_path(&self.path, self.current_gen))?;
+        file.set_len(pos)?;
+        let torn = mem::replace(&mut self.writer, BufWriterWithPos::new(file)?);
+        torn.discard();
+        Ok(())
+    }
+
+    /// Refuses writes while the disk has less free space than configured and
+    /// accepts them again once space has been freed.
+    ///
+    /// While the store is writable the disk is only checked every
+    /// `SPACE_CHECK_INTERVAL` written bytes.
+    fn check_free_space(&mut self) -> Result<()> {
+        let min_free_space = self.options.min_free_space;
+        if min_free_space == 0
+            || (!self.out_of_space && self.unchecked_bytes < SPACE_CHECK_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.unchecked_bytes = 0;
+        let available = self.fs().available_space(&self.path)?;
+        if available < min_free_space {
+            if !self.out_of_space {
+                warn!("Store turned read-only: {} bytes free on disk", available);
+                self.out_of_space = true;
+            }
+            return Err(KvsError::ReadOnly(format!(
+                "{} bytes free on disk, at least {} required",
+                available, min_free_space
+            )));
+        }
+        if self.out_of_space {
+            info!("Store is writable again: {} bytes free on disk", available);
+            self.out_of_space = false;
+        }
+        Ok(())
+    }
+
+    /// Seals the current generation into its index file and starts a new one.
+    fn rotate(&mut self) -> Result<()> {
+        self.seal_current_gen()?;
+        self.start_gen(self.current_gen + 1)?;
+        write_dead_bytes(self.fs(), &self.path, &self.gens)
+    }
 
+    /// Moves the writer to a new log file of generation `gen`.
+    ///
+    /// The current log file is synced first, so that a power loss never
+    /// keeps writes to the new file but drops earlier ones.
+    fn start_gen(&mut self, gen: u64) -> Result<()> {
         self.writer.flush()?;
+        self.writer.writer.get_mut().sync()?;
+        self.writer = new_log_file(self.fs(), &self.path, gen)?;
+        self.current_gen = gen;
+        self.track_gen(gen);
+        Ok(())
+    }
+
+    /// Seals the current generation into its index file. Its sequence
+    /// numbers are recorded first, as it is not read when the store is
+    /// opened again.
+    fn seal_current_gen(&mut self) -> Result<()> {
+        if self.index.is_persistent() {
+            write_seq_state(self.fs(), &self.path, &self.seqs)?;
+        }
+        self.index.seal(self.current_gen, self.writer.pos)
+    }
+
+    fn mark_stale(&mut self, cmd_pos: CommandPos) {
+        mark_stale(&mut self.gens, cmd_pos);
+        self.uncompacted += cmd_pos.len;
+    }
+
+    /// Slows down or stops a write while stale bytes or log files pile up
+    /// faster than compaction reclaims them.
+    fn stall_writes(&mut self) -> Result<()> {
+        let options = &self.options;
+        if self.uncompacted > options.hard_reclaimable_limit
+            || self.gens.len() > options.hard_segment_limit
+        {
+            warn!(
+                "Write stopped: {} reclaimable bytes in {} segments",
+                self.uncompacted,
+                self.gens.len()
+            );
+            self.stall_stats.stops += 1;
+            let start = Instant::now();
+            let res = self.compact_all();
+            self.stall_stats.stalled += start.elapsed();
+            res.map_err(|e| KvsError::WriteStall(format!("compaction failed: {}", e)))?;
+
+            let options = &self.options;
+            if self.uncompacted > options.hard_reclaimable_limit
+                || self.gens.len() > options.hard_segment_limit
+            {
+                return Err(KvsError::WriteStall(format!(
+                    "{} reclaimable bytes in {} segments after compaction",
+                    self.uncompacted,
+                    self.gens.len()
+                )));
+            }
+        } else if self.uncompacted > options.soft_reclaimable_limit
+            || self.gens.len() > options.soft_segment_limit
+        {
+            debug!(
+                "Write slowed down: {} reclaimable bytes in {} segments",
+                self.uncompacted,
+                self.gens.len()
+            );
+            self.stall_stats.slowdowns += 1;
+            self.stall_stats.stalled += options.stall_delay;
+            thread::sleep(options.stall_delay);
+        }
+        Ok(())
+    }
+
+    /// Starts a compaction of the generations whose dead-byte ratio reaches
+    /// the configured ratio once enough stale bytes have built up, unless
+    /// one is copying already.
+    fn due_compaction(&mut self) -> Result<Option<Compaction>> {
+        if self.compacting.is_some() || self.uncompacted <= self.options.compaction_threshold {
+            return Ok(None);
+        }
+        let dirty: BTreeSet<u64> = self
+            .gens
+            .values()
+            .filter(|stats| stats.dead_bytes > 0 && stats.dead_ratio() >= self.options.dirty_ratio)
+            .map(|stats| stats.gen)
+            .collect();
+        if dirty.is_empty() {
+            return Ok(None);
+        }
+        self.start_compaction(dirty).map(Some)
+    }
+
+    /// Compacts every generation while holding the writer. A compaction
+    /// copying meanwhile is cancelled, as its generations are taken over.
+    fn compact_all(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if let Some(cancelled) = self.compacting.take() {
+            cancelled.store(true, Ordering::SeqCst);
+        }
+        let all = self.gens.keys().cloned().collect();
+        let mut compaction = self.start_compaction(all)?;
+        let copied = compaction.copy();
+        self.finish_compaction(compaction, copied)
+    }
+
+    /// Starts merging the live commands of `selected` into a new generation.
+    /// Other generations are left untouched.
+    ///
+    /// The writer moves on to another generation, so that the selected ones
+    /// no longer change while `Compaction::copy` reads them.
+    fn start_compaction(&mut self, selected: BTreeSet<u64>) -> Result<Compaction> {
+        // A "remove" command must be kept while an older generation that may
+        // still hold the removed value survives the compaction.
+        let oldest_kept = self
+            .gens
+            .keys()
+            .find(|gen| !selected.contains(gen))
+            .cloned();
+
+        // The index of the current generation must be on disk before the
+        // generation may be compacted.
+        self.seal_current_gen()?;
+
+        // increase current gen by 2. current_gen + 1 is for the compaction file
+        let compaction_gen = self.current_gen + 1;
+        self.start_gen(self.current_gen + 2)?;
+
+        // The compaction file only gets its real name once it is complete, so
+        // that a crash or a full disk never leaves a partial generation behind.
+        let writer =
+            BufWriterWithPos::new(self.fs().create_new(&compaction_path(&self.path, compaction_gen))?)?;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.compacting = Some(Arc::clone(&cancelled));
+        Ok(Compaction {
+            selected,
+            oldest_kept,
+            gen: compaction_gen,
+            index: Arc::clone(&self.index),
+            options: self.options.clone(),
+            reader: self.reader.clone(),
+            writer,
+            resolved: HashSet::new(),
+            moved: Vec::new(),
+            copies: HashMap::new(),
+            stale: Vec::new(),
+            max_seq: 0,
+            cancelled,
+        })
+    }
+
+    /// Publishes what `compaction` copied, with `copied` the result of its
+    /// `Compaction::copy`, and removes the log files of its generations.
+    ///
+    /// The keys written while it copied are copied again, as their copies
+    /// are outdated.
+    fn finish_compaction(&mut self, mut compaction: Compaction, copied: Result<()>) -> Result<()> {
+        let tmp_path = compaction_path(&self.path, compaction.gen);
+        let discard = |fs: &dyn FileSystem| {
+            if let Err(e) = fs.remove_file(&tmp_path) {
+                warn!("Failed to remove {}: {}", tmp_path.display(), e);
+            }
+        };
+        // a full compaction has taken the generations over
+        if compaction.cancelled.load(Ordering::SeqCst) {
+            discard(self.fs());
+            return Ok(());
+        }
+        self.compacting = None;
+        if self.dropped.is_some() {
+            // the directory is gone along with the keyspace
+            return Ok(());
+        }
+        let res = copied
+            .and_then(|()| compaction.catch_up())
+            .and_then(|()| compaction.sync());
+        let len = match res {
+            Ok(len) => len,
+            Err(e) => {
+                discard(self.fs());
+                return Err(e);
+            }
+        };
+        let compaction_gen = compaction.gen;
+        // the records of the selected generations are gone for
+        // `KvStore::changes_since` once the first of them is removed
+        if compaction.max_seq > self.seqs.horizon {
+            self.seqs.horizon = compaction.max_seq;
+            write_seq_state(self.fs(), &self.path, &self.seqs)?;
+        }
+        if len > 0 {
+            self.fs().rename(&tmp_path, &log_path(&self.path, compaction_gen))?;
+            // the selected log files may only go once the new one is found
+            self.fs().sync_dir(&self.path)?;
 
-        if let Command::Set {key, ..} = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+            // Only publish the new positions once they are readable.
+            self.track_gen(compaction_gen);
+            let stats = self
+                .gens
+                .get_mut(&compaction_gen)
+                .expect("compaction generation not tracked");
+            stats.total_bytes = len;
+            // the first copies of the keys written meanwhile
+            stats.dead_bytes = compaction
+                .copies
+                .values()
+                .filter(|copy| copy.outdated)
+                .map(|copy| copy.len)
+                .sum();
+            self.index.publish_compaction(compaction_gen, len, compaction.moved)?;
+            for (_, cmd_pos) in compaction.stale {
+                self.mark_stale(cmd_pos);
             }
-            self.index.insert(key, (self.current_gen, pos..self.writer.pos).into());
+        } else {
+            self.fs().remove_file(&tmp_path)?;
+        }
+
+        // remove stale log files
+        for stale_gen in compaction.selected {
+            self.gens.remove(&stale_gen);
+            let fs = &*self.options.file_system;
+            self.live_gens
+                .retire(stale_gen, || Ok(fs.remove_file(&log_path(&self.path, stale_gen))?))?;
+            self.index.remove_gen(stale_gen)?;
         }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        self.uncompacted = self.gens.values().map(|stats| stats.dead_bytes).sum();
+
+        if self.index.is_persistent() {
+            write_dead_bytes(self.fs(), &self.path, &self.gens)?;
         }
         Ok(())
     }
+
+    fn fs(&self) -> &dyn FileSystem {
+        &*self.options.file_system
+    }
+
+    fn track_gen(&mut self, gen: u64) {
+        self.gens.insert(gen, GenerationStats::new(gen));
+        self.live_gens.insert(gen);
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.flush().and_then(|_| self.writer.writer.get_mut().sync()) {
+            warn!("Failed to sync the log when closing the store: {}", e);
+        }
+    }
+}
+
+/// A compaction of some generations into a new one, see
+/// `KvStoreWriter::start_compaction`.
+///
+/// It copies the live commands without holding the writer, so writes go on
+/// meanwhile, then `KvStoreWriter::finish_compaction` publishes the copies.
+struct Compaction {
+    selected: BTreeSet<u64>,
+    // the oldest generation left out, whose keys removals must still hide
+    oldest_kept: Option<u64>,
+    // the generation written
+    gen: u64,
+    index: Arc<Index>,
+    options: KvStoreOptions,
+    // reads the versions of the keys copied
+    reader: KvStoreReader,
+    writer: BufWriterWithPos<Box<dyn VfsFile>>,
+    // keys whose versions have been dealt with
+    resolved: HashSet<String>,
+    moved: Vec<MovedKey>,
+    // the copies of the moved keys
+    copies: HashMap<String, KeyCopy>,
+    // the latest commands of rewritten keys left behind in other
+    // generations, with their keys
+    stale: Vec<(String, CommandPos)>,
+    // the highest sequence number in `selected`
+    max_seq: u64,
+    // set once a full compaction takes the generations over
+    cancelled: Arc<AtomicBool>,
+}
+
+/// The commands a compaction wrote for a key.
+struct KeyCopy {
+    // the position the index held for the key when it was copied
+    from: Option<CommandPos>,
+    // the bytes written
+    len: u64,
+    // whether the key has been written since, so the bytes are garbage
+    outdated: bool,
 }
 
+impl Compaction {
+    /// Writes the live commands of the selected generations into the
+    /// temporary compaction file. It stops early once cancelled.
+    ///
+    /// The versions of a key are rewritten if any of them is in `selected`:
+    /// merge commands are folded into "set" commands, and of the history only
+    /// the versions kept by the options are left. The commands written carry
+    /// no sequence numbers.
+    fn copy(&mut self) -> Result<()> {
+        let limiter = self.options.rate_limiter.clone();
+        let selected = self.selected.clone();
+        for &gen in &selected {
+            let mut reader = BufReaderWithPos::new(RateLimited::new(
+                self.fs().open(&log_path(&self.reader.path, gen))?,
+                limiter.clone(),
+            ))?;
+            let mut pos = 0;
+            let mut stream = Deserializer::from_reader(&mut reader).into_iter::<Command>();
+            while let Some(cmd) = stream.next() {
+                if self.cancelled.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                let new_pos = stream.byte_offset() as u64;
+                let batch_pos = (gen, pos..new_pos).into();
+                for (cmd, cmd_pos) in flatten_batch(cmd?, batch_pos)? {
+                    self.max_seq = cmd.seq().unwrap_or(0).max(self.max_seq);
+                    match cmd {
+                        Command::Set {
+                            key,
+                            value,
+                            stamp,
+                            prev: None,
+                            ..
+                        } if !self.resolved.contains(&key)
+                            && self.index.get(&key)? == Some(cmd_pos) =>
+                        {
+                            self.resolved.insert(key.clone());
+                            let cmd = Command::Set {
+                                key: key.clone(),
+                                value,
+                                stamp,
+                                prev: None,
+                                seq: None,
+                            };
+                            let range = self.write(&cmd)?;
+                            self.moved.push((key.clone(), Some((self.gen, range.clone()).into())));
+                            self.add_copy(key, Some(cmd_pos), range);
+                        }
+                        Command::Set { key, .. } | Command::Merge { key, .. } => {
+                            self.rewrite_versions(key)?;
+                        }
+                        Command::Remove { key, .. } => {
+                            let live = self.oldest_kept.is_some_and(|kept| kept < gen)
+                                && !self.index.contains_key(&key)?;
+                            if live {
+                                let range = self.write(&Command::remove(key.clone(), None))?;
+                                self.moved.push((key.clone(), None));
+                                self.add_copy(key, None, range);
+                            }
+                        }
+                        Command::RemoveRange { start, end, .. } => {
+                            if self.oldest_kept.is_some_and(|kept| kept < gen) {
+                                self.write_range_tombstones((start, end), gen)?;
+                            }
+                        }
+                        Command::Batch(_) => return Err(KvsError::UnexpectedCommandType),
+                    }
+                }
+                pos = new_pos;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies again the keys written since they were copied, holding the
+    /// writer so that no more writes come in. Their first copies are left as
+    /// garbage.
+    fn catch_up(&mut self) -> Result<()> {
+        let mut outdated = HashSet::new();
+        for (key, copy) in self.copies.iter_mut() {
+            if !copy.outdated && self.index.get(key)? != copy.from {
+                copy.outdated = true;
+                outdated.insert(key.clone());
+            }
+        }
+        if outdated.is_empty() {
+            return Ok(());
+        }
+        self.moved.retain(|(key, _)| !outdated.contains(key));
+        // the writes have marked the latest commands stale already
+        self.stale.retain(|(key, _)| !outdated.contains(key));
+        for key in outdated {
+            self.resolved.remove(&key);
+            self.rewrite_versions(key)?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the compaction file and returns its length.
+    fn sync(&mut self) -> Result<u64> {
+        self.writer.flush()?;
+        self.writer.writer.get_mut().sync()?;
+        Ok(self.writer.pos)
+    }
+
+    /// Writes `cmd` to the compaction file and returns where it was written.
+    fn write(&mut self, cmd: &Command) -> Result<Range<u64>> {
+        let start = self.writer.pos;
+        serde_json::to_writer(
+            RateLimited::new(&mut self.writer, self.options.rate_limiter.clone()),
+            cmd,
+        )?;
+        Ok(start..self.writer.pos)
+    }
+
+    /// Accounts the bytes in `range` written for `key`, whose position in
+    /// the index was `from`.
+    fn add_copy(&mut self, key: String, from: Option<CommandPos>, range: Range<u64>) {
+        let copy = self.copies.entry(key).or_insert(KeyCopy {
+            from,
+            len: 0,
+            outdated: false,
+        });
+        copy.len += range.end - range.start;
+    }
+
+    /// Writes the range tombstone of generation `gen` to the compaction file,
+    /// split around the keys set since, which the compaction generation would
+    /// otherwise remove when replayed.
+    ///
+    /// A persistent index also gets a removed entry for each key of the range
+    /// that an older generation outside `selected` still holds.
+    fn write_range_tombstones(
+        &mut self,
+        (start, end): (Bound<String>, Bound<String>),
+        gen: u64,
+    ) -> Result<()> {
+        let live = self.index.scan((start.clone(), end.clone()))?;
+        let mut pieces = Vec::with_capacity(live.len() + 1);
+        let mut piece_start = start.clone();
+        for (key, _) in live {
+            pieces.push((piece_start, Bound::Excluded(key.clone())));
+            piece_start = Bound::Excluded(key);
+        }
+        pieces.push((piece_start, end.clone()));
+        for (start, end) in pieces {
+            if is_empty_range(&start, &end) {
+                continue;
+            }
+            self.write(&Command::RemoveRange {
+                start,
+                end,
+                seq: None,
+            })?;
+        }
+
+        for key in self.index.removed_keys((start, end), gen, &self.selected)? {
+            self.moved.push((key.clone(), None));
+            self.add_copy(key, None, 0..0);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the versions of `key` into the compaction file as "set"
+    /// commands if any of them is in `selected`, and no command of the key
+    /// has been resolved yet.
+    ///
+    /// Without history only the latest version is written. A latest command
+    /// outside `selected` is added to `stale`.
+    fn rewrite_versions(&mut self, key: String) -> Result<()> {
+        if self.resolved.contains(&key) {
+            return Ok(());
+        }
+        let latest = match self.index.get(&key)? {
+            Some(latest) => latest,
+            None => return Ok(()),
+        };
+        self.resolved.insert(key.clone());
 
+        let versions = if self.options.keeps_history() {
+            let mut history = self.reader.read_history(&key, latest)?;
+            if !history.iter().any(|entry| self.selected.contains(&entry.cmd_pos.gen)) {
+                return Ok(());
+            }
+            history.truncate(self.options.retained(&history, now_millis()));
+            history
+                .into_iter()
+                .rev()
+                .map(|entry| (entry.stamp, entry.value))
+                .collect()
+        } else {
+            let chain = self.reader.read_chain(latest)?;
+            let touched = chain
+                .merges
+                .iter()
+                .chain(chain.base.iter())
+                .any(|(cmd_pos, _)| self.selected.contains(&cmd_pos.gen));
+            if !touched {
+                return Ok(());
+            }
+            vec![(None, self.reader.fold(&key, chain)?)]
+        };
 
+        let mut prev = None;
+        for (stamp, value) in versions {
+            let range = self.write(&Command::Set {
+                key: key.clone(),
+                value,
+                stamp,
+                prev,
+                seq: None,
+            })?;
+            prev = Some((self.gen, range.clone()).into());
+            self.add_copy(key.clone(), Some(latest), range);
+        }
+        self.moved.push((key.clone(), prev));
+        if !self.selected.contains(&latest.gen) {
+            self.stale.push((key, latest));
+        }
+        Ok(())
+    }
 
+    fn fs(&self) -> &dyn FileSystem {
+        &*self.options.file_system
+    }
+}
@@ -0,0 +1,94 @@
+use std::io;
+
+#[cfg(not(loom))]
+use crossbeam_skiplist::SkipSet;
+#[cfg(loom)]
+use loom::sync::Mutex;
+#[cfg(loom)]
+use std::collections::BTreeSet;
+
+use crate::{KvsError, Result};
+
+/// The generations of a `KvStore` whose log files exist, shared by its
+/// readers and its writer.
+///
+/// Readers look the position of a key up and then read it without a lock,
+/// so a compaction may remove the generation in between. It publishes the
+/// new positions before it retires the old generations, and retires them
+/// before it removes their files, so a reader that finds the files gone
+/// finds the new position when it looks again.
+///
+/// Built with `--cfg loom`, a loom mutex stands in for the skip set, so that
+/// the loom tests in `tests/loom.rs` can check `read_latest` and `retire`.
+/// Neither the skip set nor the callers in `KvStore` are checked there.
+#[derive(Default)]
+pub struct LiveGens {
+    #[cfg(not(loom))]
+    gens: SkipSet<u64>,
+    #[cfg(loom)]
+    gens: Mutex<BTreeSet<u64>>,
+}
+
+#[cfg(not(loom))]
+impl LiveGens {
+    pub fn insert(&self, gen: u64) {
+        self.gens.insert(gen);
+    }
+
+    pub fn remove(&self, gen: u64) {
+        self.gens.remove(&gen);
+    }
+
+    pub fn contains(&self, gen: u64) -> bool {
+        self.gens.contains(&gen)
+    }
+}
+
+#[cfg(loom)]
+impl LiveGens {
+    pub fn insert(&self, gen: u64) {
+        self.gens.lock().unwrap().insert(gen);
+    }
+
+    pub fn remove(&self, gen: u64) {
+        self.gens.lock().unwrap().remove(&gen);
+    }
+
+    pub fn contains(&self, gen: u64) -> bool {
+        self.gens.lock().unwrap().contains(&gen)
+    }
+}
+
+impl LiveGens {
+    pub fn new() -> LiveGens {
+        LiveGens::default()
+    }
+
+    /// Retires `gen` and then removes its files with `remove_files`. The
+    /// new positions of its commands must have been published before.
+    pub fn retire(&self, gen: u64, remove_files: impl FnOnce() -> Result<()>) -> Result<()> {
+        self.remove(gen);
+        remove_files()
+    }
+
+    /// Reads with `read` the position `lookup` returns along with its
+    /// generation, looking it up again while the files read have been
+    /// removed by a compaction meanwhile.
+    pub fn read_latest<P, T>(
+        &self,
+        mut lookup: impl FnMut() -> Result<Option<(u64, P)>>,
+        mut read: impl FnMut(P) -> Result<T>,
+    ) -> Result<Option<T>> {
+        loop {
+            let (gen, pos) = match lookup()? {
+                Some(found) => found,
+                None => return Ok(None),
+            };
+            match read(pos) {
+                Err(KvsError::IO(ref e))
+                    if e.kind() == io::ErrorKind::NotFound && !self.contains(gen) => {}
+                res => return res.map(Some),
+            }
+        }
+    }
+}
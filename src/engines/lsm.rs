@@ -0,0 +1,1013 @@
+use super::{add_to_value, is_empty_range, KvsEngine};
+
+use crate::bloom::BloomFilter;
+use crate::vfs::{DiskFileSystem, FileSystem};
+use crate::{KvsError, Result};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::vec;
+
+const MEMTABLE_SIZE: u64 = 4 * 1024 * 1024;
+
+const BLOCK_SIZE: u64 = 4 * 1024;
+
+const TABLE_SIZE: u64 = 2 * 1024 * 1024;
+
+const LEVEL0_LIMIT: usize = 4;
+
+const LEVEL_BASE_SIZE: u64 = 10 * 1024 * 1024;
+
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+const MANIFEST: &str = "MANIFEST";
+
+const TABLE_EXTENSION: &str = "sst";
+
+const WAL_EXTENSION: &str = "wal";
+
+// the footer of a table holds the offset and length of its meta block
+const FOOTER_LEN: u64 = 16;
+
+/// A key/value pair of the memtable or a table, `None` marks a removal.
+type Entry = (String, Option<String>);
+
+/// The `LsmKvsEngine` stores string key/value pairs in a log-structured
+/// merge tree.
+///
+/// Writes are appended to a write-ahead log and collected in a sorted
+/// in-memory memtable. A full memtable is frozen and written out as an
+/// immutable sorted table file in level 0, while a new memtable takes the
+/// writes. Each table holds a block index and a bloom filter in memory, so a
+/// lookup reads at most one block per table.
+///
+/// Tables of level 0 may overlap. Once there are too many, they are merged
+/// into level 1, and every deeper level holds tables with disjoint key ranges
+/// that are merged into the next level when the level grows past its size
+/// limit. Flushes and compactions run in the writer that filled the
+/// memtable, without blocking other reads and writes.
+///
+/// ```rust
+/// # use kvs::{LsmKvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let engine = LsmKvsEngine::open(current_dir()?)?;
+/// engine.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LsmKvsEngine(Arc<Lsm>);
+
+/// Options for opening a `LsmKvsEngine`.
+#[derive(Clone, Debug)]
+pub struct LsmOptions {
+    memtable_size: u64,
+    block_size: u64,
+    table_size: u64,
+    level0_limit: usize,
+    level_base_size: u64,
+    bloom_false_positive_rate: f64,
+    sync_writes: bool,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: MEMTABLE_SIZE,
+            block_size: BLOCK_SIZE,
+            table_size: TABLE_SIZE,
+            level0_limit: LEVEL0_LIMIT,
+            level_base_size: LEVEL_BASE_SIZE,
+            bloom_false_positive_rate: BLOOM_FALSE_POSITIVE_RATE,
+            sync_writes: false,
+        }
+    }
+}
+
+impl LsmOptions {
+    /// Sets how many bytes of keys and values the memtable collects before
+    /// it is written to a table.
+    pub fn memtable_size(mut self, bytes: u64) -> Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    /// Sets the size of the blocks a table is read in.
+    pub fn block_size(mut self, bytes: u64) -> Self {
+        self.block_size = bytes;
+        self
+    }
+
+    /// Sets the size at which compaction starts a new table.
+    pub fn table_size(mut self, bytes: u64) -> Self {
+        self.table_size = bytes;
+        self
+    }
+
+    /// Sets how many tables level 0 may hold before it is merged into level 1.
+    pub fn level0_limit(mut self, tables: usize) -> Self {
+        self.level0_limit = tables;
+        self
+    }
+
+    /// Sets the size limit of level 1. Each deeper level may grow ten times
+    /// larger than the one above it.
+    pub fn level_base_size(mut self, bytes: u64) -> Self {
+        self.level_base_size = bytes;
+        self
+    }
+
+    /// Sets the false positive rate of the bloom filters of new tables.
+    pub fn bloom_false_positive_rate(mut self, rate: f64) -> Self {
+        self.bloom_false_positive_rate = rate;
+        self
+    }
+
+    /// Sets whether every write is synced to the write-ahead log before it
+    /// returns, so that it survives a power loss. Otherwise a power loss may
+    /// take the writes not yet in a table.
+    pub fn sync_writes(mut self, sync: bool) -> Self {
+        self.sync_writes = sync;
+        self
+    }
+}
+
+struct Lsm {
+    path: PathBuf,
+    options: LsmOptions,
+    state: RwLock<State>,
+    // next number for a table or write-ahead log file
+    next_id: AtomicU64,
+    // held while flushing or compacting, which only lock `state` to swap in
+    // their results
+    maintenance: Mutex<()>,
+}
+
+struct State {
+    memtable: BTreeMap<String, Option<String>>,
+    // total length of the keys and values in the memtable
+    memtable_size: u64,
+    wal: Wal,
+    // full memtables waiting to be written to level 0, oldest first
+    frozen: Vec<Arc<Frozen>>,
+    // level 0 holds possibly overlapping tables, oldest first. Deeper levels
+    // hold tables with disjoint key ranges, sorted by key.
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+/// A full memtable, with the write-ahead logs it was replayed from.
+struct Frozen {
+    memtable: BTreeMap<String, Option<String>>,
+    wals: Vec<u64>,
+}
+
+/// The list of live tables, replaced atomically on every change.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    next_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+impl LsmKvsEngine {
+    /// Opens a `LsmKvsEngine` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while loading the tables
+    /// or replaying the write-ahead log.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        LsmKvsEngine::open_with_options(path, LsmOptions::default())
+    }
+
+    /// Opens a `LsmKvsEngine` with the given path and options.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while loading the tables
+    /// or replaying the write-ahead log.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: LsmOptions) -> Result<LsmKvsEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        let manifest_path = path.join(MANIFEST);
+        let manifest: Manifest = if manifest_path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&manifest_path)?))?
+        } else {
+            Manifest::default()
+        };
+
+        let mut levels = Vec::new();
+        for ids in &manifest.levels {
+            let tables = ids
+                .iter()
+                .map(|&id| Table::open(&path, id).map(Arc::new))
+                .collect::<Result<Vec<_>>>()?;
+            levels.push(tables);
+        }
+        if levels.is_empty() {
+            levels.push(Vec::new());
+        }
+        // tables of a compaction that did not make it into the manifest
+        for id in sorted_id_list(&path, TABLE_EXTENSION)? {
+            if !manifest.levels.iter().any(|ids| ids.contains(&id)) {
+                fs::remove_file(file_path(&path, id, TABLE_EXTENSION))?;
+            }
+        }
+
+        let mut memtable = BTreeMap::new();
+        let old_wals = sorted_id_list(&path, WAL_EXTENSION)?;
+        for &id in &old_wals {
+            Wal::replay(&file_path(&path, id, WAL_EXTENSION), &mut memtable)?;
+        }
+
+        // logs written after the last manifest update may carry larger numbers
+        let next_id = old_wals
+            .last()
+            .map_or(manifest.next_id, |&id| manifest.next_id.max(id + 1));
+        let wal = Wal::create(&path, next_id, options.sync_writes)?;
+        // Start from an empty log so that a torn write at the end of an old
+        // one is never appended to. The old ones go once their entries are
+        // in a table.
+        let frozen = if old_wals.is_empty() {
+            Vec::new()
+        } else {
+            vec![Arc::new(Frozen {
+                memtable,
+                wals: old_wals,
+            })]
+        };
+
+        let lsm = Lsm {
+            path,
+            options,
+            state: RwLock::new(State {
+                memtable: BTreeMap::new(),
+                memtable_size: 0,
+                wal,
+                frozen,
+                levels,
+            }),
+            next_id: AtomicU64::new(next_id + 1),
+            maintenance: Mutex::new(()),
+        };
+        lsm.maintain()?;
+
+        Ok(LsmKvsEngine(Arc::new(lsm)))
+    }
+
+    /// Returns the number of tables in each level.
+    pub fn table_counts(&self) -> Vec<usize> {
+        let state = self.0.state.read().unwrap();
+        state.levels.iter().map(Vec::len).collect()
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    /// Sets the value of a string key to a string.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while writing the log, a table or compacting.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let full = self.0.write(&mut self.0.state.write().unwrap(), key, Some(value))?;
+        self.0.maintain_if(full)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.0.state.read().unwrap().get(&key)?.flatten())
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Error
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove(&self, key: String) -> Result<()> {
+        let full = {
+            let mut state = self.0.state.write().unwrap();
+            if state.get(&key)?.flatten().is_none() {
+                return Err(KvsError::KeyNotFound);
+            }
+            self.0.write(&mut state, key, None)?
+        };
+        self.0.maintain_if(full)
+    }
+
+    /// Returns the key/value pairs whose keys are in `range`, sorted by key.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        self.0.state.read().unwrap().scan(range)
+    }
+
+    /// Writes a tombstone for every key in `range`.
+    fn delete_range<R: RangeBounds<String>>(&self, range: R) -> Result<()> {
+        if is_empty_range(&range.start_bound().cloned(), &range.end_bound().cloned()) {
+            return Ok(());
+        }
+        let mut full = false;
+        {
+            let mut state = self.0.state.write().unwrap();
+            for (key, _) in state.scan(range)? {
+                full |= self.0.write(&mut state, key, None)?;
+            }
+        }
+        self.0.maintain_if(full)
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let (value, full) = {
+            let mut state = self.0.state.write().unwrap();
+            let value = add_to_value(state.get(&key)?.flatten().as_deref(), delta)?;
+            (value, self.0.write(&mut state, key, Some(value.to_string()))?)
+        };
+        self.0.maintain_if(full)?;
+        Ok(value)
+    }
+}
+
+impl State {
+    /// Looks `key` up from the newest data to the oldest.
+    ///
+    /// Returns `Some(None)` if the key was removed.
+    fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        let memtables = iter::once(&self.memtable)
+            .chain(self.frozen.iter().rev().map(|frozen| &frozen.memtable));
+        for memtable in memtables {
+            if let Some(value) = memtable.get(key) {
+                return Ok(Some(value.clone()));
+            }
+        }
+        for table in self.levels[0].iter().rev() {
+            if let Some(value) = table.get(key)? {
+                return Ok(Some(value));
+            }
+        }
+        for level in &self.levels[1..] {
+            let idx = level.partition_point(|table| table.last_key.as_str() < key);
+            if let Some(table) = level.get(idx) {
+                if let Some(value) = table.get(key)? {
+                    return Ok(Some(value));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        // Newer data overwrites older, so go from the bottom level up.
+        let mut latest = BTreeMap::new();
+        for table in self.levels[1..].iter().rev().flatten() {
+            latest.extend(table.scan(&range)?);
+        }
+        for table in &self.levels[0] {
+            latest.extend(table.scan(&range)?);
+        }
+        let memtables = self
+            .frozen
+            .iter()
+            .map(|frozen| &frozen.memtable)
+            .chain(iter::once(&self.memtable));
+        for memtable in memtables {
+            latest.extend(
+                memtable
+                    .range::<String, _>((range.start_bound(), range.end_bound()))
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+        Ok(latest
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect())
+    }
+
+    /// Returns the manifest listing the tables of the levels.
+    fn manifest(&self, next_id: u64) -> Manifest {
+        Manifest {
+            next_id,
+            levels: self
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.id).collect())
+                .collect(),
+        }
+    }
+}
+
+impl Lsm {
+    /// Writes to the log and the memtable of `state`.
+    ///
+    /// Returns whether the memtable filled up and was frozen, so that the
+    /// caller flushes it with `maintain_if` once `state` is unlocked.
+    fn write(&self, state: &mut State, key: String, value: Option<String>) -> Result<bool> {
+        let entry = (key, value);
+        state.wal.append(&entry, self.options.sync_writes)?;
+        let (key, value) = entry;
+        let size = entry_size(&key, &value);
+        if let Some(old_value) = state.memtable.insert(key.clone(), value) {
+            state.memtable_size -= entry_size(&key, &old_value);
+        }
+        state.memtable_size += size;
+
+        if state.memtable_size < self.options.memtable_size {
+            return Ok(false);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let wal = Wal::create(&self.path, id, self.options.sync_writes)?;
+        let old_wal = std::mem::replace(&mut state.wal, wal);
+        let memtable = std::mem::take(&mut state.memtable);
+        state.frozen.push(Arc::new(Frozen {
+            memtable,
+            wals: vec![old_wal.id],
+        }));
+        state.memtable_size = 0;
+        Ok(true)
+    }
+
+    fn maintain_if(&self, full: bool) -> Result<()> {
+        if full {
+            self.maintain()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes the frozen memtables to level 0 and compacts the levels.
+    ///
+    /// Reads and writes go on meanwhile, a writer filling up another
+    /// memtable waits for the maintenance to finish.
+    fn maintain(&self) -> Result<()> {
+        let _maintenance = self.maintenance.lock().unwrap();
+        loop {
+            let frozen = self.state.read().unwrap().frozen.first().cloned();
+            match frozen {
+                Some(frozen) => self.flush(&frozen)?,
+                None => break,
+            }
+        }
+        self.maybe_compact()
+    }
+
+    /// Writes the oldest frozen memtable to a new level 0 table and removes
+    /// its logs.
+    fn flush(&self, frozen: &Frozen) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut builder = TableBuilder::new(&self.path, id, &self.options)?;
+        for (key, value) in &frozen.memtable {
+            builder.add((key.clone(), value.clone()))?;
+        }
+        let table = builder.finish()?;
+
+        let manifest = {
+            let mut state = self.state.write().unwrap();
+            state.levels[0].extend(table.map(Arc::new));
+            state.frozen.remove(0);
+            state.manifest(self.next_id.load(Ordering::SeqCst))
+        };
+        self.write_manifest(&manifest)?;
+        for &id in &frozen.wals {
+            fs::remove_file(file_path(&self.path, id, WAL_EXTENSION))?;
+        }
+        Ok(())
+    }
+
+    /// Compacts levels until every level is within its limit.
+    fn maybe_compact(&self) -> Result<()> {
+        loop {
+            let full = {
+                let state = self.state.read().unwrap();
+                let levels = &state.levels;
+                if levels[0].len() > self.options.level0_limit {
+                    Some(0)
+                } else {
+                    let mut limit = self.options.level_base_size;
+                    (1..levels.len()).find(|&level| {
+                        let size: u64 = levels[level].iter().map(|table| table.size).sum();
+                        let full = size > limit;
+                        limit = limit.saturating_mul(LEVEL_SIZE_MULTIPLIER);
+                        full
+                    })
+                }
+            };
+            match full {
+                Some(level) => self.compact_level(level)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Merges `level` into the next level.
+    ///
+    /// All tables of level 0 are merged at once, deeper levels give up their
+    /// first table. Tables of the next level overlapping the merged key range
+    /// are rewritten together with them.
+    ///
+    /// The tables are merged without locking the state. Only maintenance
+    /// changes the levels, so they are still as read when the new tables
+    /// replace the merged ones.
+    fn compact_level(&self, level: usize) -> Result<()> {
+        let (inputs, overlapping, start, keep_removals) = {
+            let state = self.state.read().unwrap();
+            let levels = &state.levels;
+            let inputs: Vec<Arc<Table>> = if level == 0 {
+                levels[0].iter().rev().cloned().collect()
+            } else {
+                vec![Arc::clone(&levels[level][0])]
+            };
+            let first_key = inputs.iter().map(|table| &table.first_key).min();
+            let last_key = inputs.iter().map(|table| &table.last_key).max();
+            let (first_key, last_key) = match (first_key, last_key) {
+                (Some(first_key), Some(last_key)) => (first_key, last_key),
+                _ => return Ok(()),
+            };
+
+            let next = levels.get(level + 1).map_or(&[][..], Vec::as_slice);
+            let start = next.partition_point(|table| &table.last_key < first_key);
+            let end = next.partition_point(|table| &table.first_key <= last_key);
+            // Removals only matter while older data may still sit deeper down.
+            let keep_removals = levels.iter().skip(level + 2).any(|tables| !tables.is_empty());
+            (inputs, next[start..end].to_vec(), start, keep_removals)
+        };
+
+        // Newer sources come first so that they win in the merge.
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<Entry>>>> = inputs
+            .iter()
+            .map(|table| Box::new(TableIter::new(Arc::clone(table))) as Box<dyn Iterator<Item = _>>)
+            .collect();
+        sources.push(Box::new(
+            overlapping
+                .clone()
+                .into_iter()
+                .flat_map(TableIter::new),
+        ));
+
+        let mut outputs = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for entry in MergeIter::new(sources) {
+            let entry = entry?;
+            if entry.1.is_none() && !keep_removals {
+                continue;
+            }
+            if builder.is_none() {
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                builder = Some(TableBuilder::new(&self.path, id, &self.options)?);
+            }
+            let full = {
+                let builder = builder.as_mut().unwrap();
+                builder.add(entry)?;
+                builder.size() >= self.options.table_size
+            };
+            if full {
+                outputs.extend(builder.take().unwrap().finish()?);
+            }
+        }
+        if let Some(builder) = builder {
+            outputs.extend(builder.finish()?);
+        }
+
+        let manifest = {
+            let mut state = self.state.write().unwrap();
+            if state.levels.len() == level + 1 {
+                state.levels.push(Vec::new());
+            }
+            if level == 0 {
+                state.levels[0].drain(..inputs.len());
+            } else {
+                state.levels[level].remove(0);
+            }
+            let merged = start..start + overlapping.len();
+            state.levels[level + 1].splice(merged, outputs.into_iter().map(Arc::new));
+            state.manifest(self.next_id.load(Ordering::SeqCst))
+        };
+        self.write_manifest(&manifest)?;
+
+        for table in inputs.iter().chain(overlapping.iter()) {
+            fs::remove_file(file_path(&self.path, table.id, TABLE_EXTENSION))?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the manifest, syncing the directory so that the new one
+    /// survives a crash together with the tables it lists.
+    fn write_manifest(&self, manifest: &Manifest) -> Result<()> {
+        let tmp_path = self.path.join(format!("{}.tmp", MANIFEST));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, manifest)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, self.path.join(MANIFEST))?;
+        DiskFileSystem.sync_dir(&self.path)?;
+        Ok(())
+    }
+}
+
+/// The write-ahead log of the memtable.
+struct Wal {
+    id: u64,
+    writer: BufWriter<File>,
+}
+
+impl Wal {
+    /// Creates a log. With `sync`, the directory is synced so that the
+    /// synced writes to the log are not lost along with its name.
+    fn create(path: &Path, id: u64, sync: bool) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(file_path(path, id, WAL_EXTENSION))?;
+        if sync {
+            DiskFileSystem.sync_dir(path)?;
+        }
+        Ok(Wal {
+            id,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn append(&mut self, entry: &Entry, sync: bool) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, entry)?;
+        self.writer.flush()?;
+        if sync {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Replays a log into `memtable`, ignoring a torn write at its end.
+    fn replay(path: &Path, memtable: &mut BTreeMap<String, Option<String>>) -> Result<()> {
+        let reader = BufReader::new(File::open(path)?);
+        for entry in Deserializer::from_reader(reader).into_iter::<Entry>() {
+            match entry {
+                Ok((key, value)) => {
+                    memtable.insert(key, value);
+                }
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Locates a block of a table.
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// The meta block at the end of a table.
+#[derive(Serialize, Deserialize)]
+struct TableMeta {
+    first_key: String,
+    blocks: Vec<BlockHandle>,
+    bloom: BloomFilter,
+}
+
+/// An immutable sorted table file.
+///
+/// A table is a sequence of blocks, each a JSON array of sorted entries,
+/// followed by a JSON meta block and a fixed size footer pointing at it.
+struct Table {
+    id: u64,
+    file: Mutex<File>,
+    first_key: String,
+    last_key: String,
+    blocks: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    // size of the table file
+    size: u64,
+}
+
+impl Table {
+    fn open(path: &Path, id: u64) -> Result<Table> {
+        let mut file = File::open(file_path(path, id, TABLE_EXTENSION))?;
+        let size = file.seek(SeekFrom::End(0))?;
+        if size < FOOTER_LEN {
+            return Err(corrupt_table(id, "too short"));
+        }
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.read_exact(&mut footer)?;
+        let mut offset = [0; 8];
+        let mut len = [0; 8];
+        offset.copy_from_slice(&footer[..8]);
+        len.copy_from_slice(&footer[8..]);
+        let (offset, len) = (u64::from_le_bytes(offset), u64::from_le_bytes(len));
+        if offset.checked_add(len) != Some(size - FOOTER_LEN) {
+            return Err(corrupt_table(id, "bad footer"));
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; len as usize];
+        file.read_exact(&mut buf)?;
+        let meta: TableMeta = serde_json::from_slice(&buf)?;
+        let last_key = match meta.blocks.last() {
+            Some(block) => block.last_key.clone(),
+            None => return Err(corrupt_table(id, "no blocks")),
+        };
+        Ok(Table {
+            id,
+            file: Mutex::new(file),
+            first_key: meta.first_key,
+            last_key,
+            blocks: meta.blocks,
+            bloom: meta.bloom,
+            size,
+        })
+    }
+
+    /// Returns `Some(None)` if the table records the removal of `key`.
+    fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.first_key.as_str()
+            || key > self.last_key.as_str()
+            || !self.bloom.may_contain(BloomFilter::hash(key.as_bytes()))
+        {
+            return Ok(None);
+        }
+        let idx = self.blocks.partition_point(|block| block.last_key.as_str() < key);
+        let block = match self.blocks.get(idx) {
+            Some(block) => self.read_block(block)?,
+            None => return Ok(None),
+        };
+        Ok(block
+            .binary_search_by(|(k, _)| k.as_str().cmp(key))
+            .ok()
+            .map(|i| block[i].1.clone()))
+    }
+
+    /// Returns the entries whose keys are in `range`, sorted by key.
+    fn scan(&self, range: &impl RangeBounds<String>) -> Result<Vec<Entry>> {
+        let first = match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => self
+                .blocks
+                .partition_point(|block| &block.last_key < start),
+            Bound::Unbounded => 0,
+        };
+        let mut entries = Vec::new();
+        for block in &self.blocks[first..] {
+            let block_entries = self.read_block(block)?;
+            entries.extend(block_entries.into_iter().filter(|(key, _)| range.contains(key)));
+            let past_end = match range.end_bound() {
+                Bound::Included(end) | Bound::Excluded(end) => &block.last_key >= end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read_block(&self, block: &BlockHandle) -> Result<Vec<Entry>> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(block.offset))?;
+        let mut buf = vec![0; block.len as usize];
+        file.read_exact(&mut buf)?;
+        Ok(serde_json::from_slice(&buf)?)
+    }
+}
+
+fn corrupt_table(id: u64, reason: &str) -> KvsError {
+    KvsError::IO(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("table {} is corrupted: {}", id, reason),
+    ))
+}
+
+/// Writes sorted entries into a new table file.
+struct TableBuilder {
+    path: PathBuf,
+    id: u64,
+    writer: BufWriter<File>,
+    pos: u64,
+    block: Vec<Entry>,
+    block_size: u64,
+    max_block_size: u64,
+    first_key: Option<String>,
+    blocks: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+    bloom_false_positive_rate: f64,
+}
+
+impl TableBuilder {
+    fn new(path: &Path, id: u64, options: &LsmOptions) -> Result<TableBuilder> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(file_path(path, id, TABLE_EXTENSION))?;
+        Ok(TableBuilder {
+            path: path.to_owned(),
+            id,
+            writer: BufWriter::new(file),
+            pos: 0,
+            block: Vec::new(),
+            block_size: 0,
+            max_block_size: options.block_size,
+            first_key: None,
+            blocks: Vec::new(),
+            hashes: Vec::new(),
+            bloom_false_positive_rate: options.bloom_false_positive_rate,
+        })
+    }
+
+    /// Adds an entry, keys must come in ascending order.
+    fn add(&mut self, entry: Entry) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(entry.0.clone());
+        }
+        self.hashes.push(BloomFilter::hash(entry.0.as_bytes()));
+        self.block_size += entry_size(&entry.0, &entry.1);
+        self.block.push(entry);
+        if self.block_size >= self.max_block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the approximate size of the table so far.
+    fn size(&self) -> u64 {
+        self.pos + self.block_size
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        let last_key = match self.block.last() {
+            Some((key, _)) => key.clone(),
+            None => return Ok(()),
+        };
+        let buf = serde_json::to_vec(&self.block)?;
+        self.writer.write_all(&buf)?;
+        self.blocks.push(BlockHandle {
+            last_key,
+            offset: self.pos,
+            len: buf.len() as u64,
+        });
+        self.pos += buf.len() as u64;
+        self.block.clear();
+        self.block_size = 0;
+        Ok(())
+    }
+
+    /// Writes the meta block and syncs the file.
+    ///
+    /// Returns `None` and removes the file if no entry was added.
+    fn finish(mut self) -> Result<Option<Table>> {
+        self.finish_block()?;
+        let first_key = match self.first_key.take() {
+            Some(first_key) => first_key,
+            None => {
+                drop(self.writer);
+                fs::remove_file(file_path(&self.path, self.id, TABLE_EXTENSION))?;
+                return Ok(None);
+            }
+        };
+        let meta = TableMeta {
+            first_key,
+            blocks: std::mem::take(&mut self.blocks),
+            bloom: BloomFilter::build(&self.hashes, self.bloom_false_positive_rate),
+        };
+        let buf = serde_json::to_vec(&meta)?;
+        self.writer.write_all(&buf)?;
+        self.writer.write_all(&self.pos.to_le_bytes())?;
+        self.writer.write_all(&(buf.len() as u64).to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(Some(Table::open(&self.path, self.id)?))
+    }
+}
+
+/// Iterates over the entries of a table in key order, a block at a time.
+struct TableIter {
+    table: Arc<Table>,
+    next_block: usize,
+    entries: vec::IntoIter<Entry>,
+}
+
+impl TableIter {
+    fn new(table: Arc<Table>) -> TableIter {
+        TableIter {
+            table,
+            next_block: 0,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            let block = self.table.blocks.get(self.next_block)?;
+            self.next_block += 1;
+            match self.table.read_block(block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Merges sorted sources into one sorted stream. For keys found in several
+/// sources, only the entry of the first such source is kept.
+struct MergeIter<'a> {
+    sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>>,
+    heads: Vec<Option<Entry>>,
+    started: bool,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>>) -> MergeIter<'a> {
+        let heads = sources.iter().map(|_| None).collect();
+        MergeIter {
+            sources,
+            heads,
+            started: false,
+        }
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        if !self.started {
+            self.started = true;
+            for i in 0..self.sources.len() {
+                if let Err(e) = self.advance(i) {
+                    return Some(Err(e));
+                }
+            }
+        }
+        let min_key = self
+            .heads
+            .iter()
+            .flatten()
+            .map(|(key, _)| key)
+            .min()?
+            .clone();
+        let mut winner = None;
+        for i in 0..self.heads.len() {
+            if self.heads[i].as_ref().map(|(key, _)| key) == Some(&min_key) {
+                let entry = self.heads[i].take();
+                if winner.is_none() {
+                    winner = entry;
+                }
+                if let Err(e) = self.advance(i) {
+                    return Some(Err(e));
+                }
+            }
+        }
+        winner.map(Ok)
+    }
+}
+
+fn entry_size(key: &str, value: &Option<String>) -> u64 {
+    (key.len() + value.as_ref().map_or(0, String::len)) as u64
+}
+
+fn file_path(dir: &Path, id: u64, extension: &str) -> PathBuf {
+    dir.join(format!("{}.{}", id, extension))
+}
+
+/// Returns sorted numbers of the files with `extension` in the given directory.
+fn sorted_id_list(path: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut id_list: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    id_list.sort_unstable();
+    Ok(id_list)
+}
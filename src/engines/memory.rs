@@ -0,0 +1,224 @@
+use super::{add_to_value, is_empty_range, KvsEngine};
+
+use crate::vfs::{DiskFileSystem, FileSystem};
+use crate::{KvsError, Result};
+
+use log::error;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// A `KvsEngine` that keeps every key/value pair in memory.
+///
+/// Clones share the same data. The engine may be bounded by the total size of
+/// its keys and values, and may restore its data from a snapshot file when
+/// created and write it back once the last clone is dropped.
+///
+/// Only the plain key/value methods are implemented: `set`, `get`,
+/// `remove`, `scan`, `delete_range`, `delete_prefix` and `incr_by`. It has
+/// no keyspaces, and merges, versions, transactions, watches, the change
+/// history and secondary indexes fail with `KvsError::Unsupported`.
+///
+/// ```rust
+/// # use kvs::{KvsEngine, MemoryKvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let engine = MemoryKvsEngine::new();
+/// engine.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MemoryKvsEngine(Arc<Inner>);
+
+struct Inner {
+    data: RwLock<Data>,
+    size_limit: Option<u64>,
+    // file the data is written to when the last clone is dropped
+    snapshot: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct Data {
+    map: BTreeMap<String, String>,
+    // total length of keys and values
+    size: u64,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty, unbounded engine.
+    pub fn new() -> Self {
+        MemoryKvsEngine::with_data(BTreeMap::new(), None, None)
+    }
+
+    /// Creates an empty engine that holds at most `bytes` bytes of keys and
+    /// values.
+    pub fn with_size_limit(bytes: u64) -> Self {
+        MemoryKvsEngine::with_data(BTreeMap::new(), Some(bytes), None)
+    }
+
+    /// Creates an engine backed by the snapshot file at `path`.
+    ///
+    /// The data is restored from the file if it exists, and written back to
+    /// it when the last clone of the engine is dropped or closed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the snapshot.
+    pub fn with_snapshot(path: impl Into<PathBuf>, size_limit: Option<u64>) -> Result<Self> {
+        let path = path.into();
+        let map = if path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&path)?))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(MemoryKvsEngine::with_data(map, size_limit, Some(path)))
+    }
+
+    fn with_data(
+        map: BTreeMap<String, String>,
+        size_limit: Option<u64>,
+        snapshot: Option<PathBuf>,
+    ) -> Self {
+        let size = map.iter().map(|(key, value)| entry_size(key, value)).sum();
+        MemoryKvsEngine(Arc::new(Inner {
+            data: RwLock::new(Data { map, size }),
+            size_limit,
+            snapshot,
+        }))
+    }
+
+    /// Writes all key/value pairs to a snapshot file at `path`.
+    ///
+    /// The file is replaced atomically, so a failed snapshot never destroys
+    /// the previous one.
+    pub fn snapshot(&self, path: &Path) -> Result<()> {
+        self.0.snapshot(path)
+    }
+
+    /// Drops the engine, writing the snapshot file it was created with if
+    /// this is the last clone.
+    ///
+    /// Dropping the last clone writes the file too, but can only log the
+    /// errors that this returns.
+    pub fn close(self) -> Result<()> {
+        match Arc::try_unwrap(self.0) {
+            Ok(mut inner) => match inner.snapshot.take() {
+                Some(path) => inner.snapshot(&path),
+                None => Ok(()),
+            },
+            // the last clone writes it
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl Default for MemoryKvsEngine {
+    fn default() -> Self {
+        MemoryKvsEngine::new()
+    }
+}
+
+impl Inner {
+    /// Inserts a pair into `data`, the locked data of the engine, unless it
+    /// grows beyond the size limit.
+    fn insert(&self, data: &mut Data, key: String, value: String) -> Result<()> {
+        let old_size = data
+            .map
+            .get(&key)
+            .map_or(0, |old_value| entry_size(&key, old_value));
+        let size = data.size - old_size + entry_size(&key, &value);
+        if let Some(limit) = self.size_limit {
+            if size > limit {
+                return Err(KvsError::CapacityExceeded(format!(
+                    "{} bytes needed, limit is {} bytes",
+                    size, limit
+                )));
+            }
+        }
+        data.map.insert(key, value);
+        data.size = size;
+        Ok(())
+    }
+
+    fn snapshot(&self, path: &Path) -> Result<()> {
+        let data = self.data.read().unwrap();
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &data.map)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        DiskFileSystem.sync_dir(dir)?;
+        Ok(())
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(path) = &self.snapshot {
+            if let Err(e) = self.snapshot(path) {
+                error!("Failed to snapshot to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut data = self.0.data.write().unwrap();
+        self.0.insert(&mut data, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.0.data.read().unwrap().map.get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut data = self.0.data.write().unwrap();
+        let value = data.map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        data.size -= entry_size(&key, &value);
+        Ok(())
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let data = self.0.data.read().unwrap();
+        Ok(data
+            .map
+            .range(range)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn delete_range<R: RangeBounds<String>>(&self, range: R) -> Result<()> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        if is_empty_range(&range.0, &range.1) {
+            return Ok(());
+        }
+        let mut data = self.0.data.write().unwrap();
+        let keys: Vec<String> = data.map.range(range).map(|(key, _)| key.clone()).collect();
+        for key in keys {
+            let value = data.map.remove(&key).expect("key not found");
+            data.size -= entry_size(&key, &value);
+        }
+        Ok(())
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let mut data = self.0.data.write().unwrap();
+        let value = add_to_value(data.map.get(&key).map(String::as_str), delta)?;
+        self.0.insert(&mut data, key, value.to_string())?;
+        Ok(value)
+    }
+}
+
+fn entry_size(key: &str, value: &str) -> u64 {
+    (key.len() + value.len()) as u64
+}
@@ -1,15 +1,328 @@
+mod changes;
+mod index_file;
 mod kvs;
+mod live_gens;
+mod lsm;
+mod memory;
+mod secondary;
+mod sharded;
 mod sled;
+mod transaction;
+mod watch;
 
-use crate::Result;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
 
-pub trait KvsEngine {
+// longest allowed keyspace name
+const MAX_KEYSPACE_NAME_LEN: usize = 64;
+// prefix of the keyspace names taken by sled's own trees
+const RESERVED_KEYSPACE_PREFIX: &str = "__sled__";
+
+pub trait KvsEngine: Clone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
 
     fn get(&self, key: String) -> Result<Option<String>>;
 
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Returns the key/value pairs whose keys are in `range`, sorted by key.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>>;
+
+    /// Removes every key in `range`. Missing keys are not an error.
+    fn delete_range<R: RangeBounds<String>>(&self, range: R) -> Result<()>;
+
+    /// Removes every key starting with `prefix`.
+    fn delete_prefix(&self, prefix: &str) -> Result<()> {
+        self.delete_range(prefix_range(prefix))
+    }
+
+    /// Atomically adds `delta` to the integer value of `key` and returns the
+    /// new value. A missing key counts as 0.
+    ///
+    /// It returns `KvsError::NotAnInteger` if the value is not a decimal
+    /// `i64`, and `KvsError::IntegerOverflow` if the result does not fit one.
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// Merges `operand` into the value of `key` with the merge operator the
+    /// engine was opened with.
+    ///
+    /// It returns `KvsError::Unsupported` if the engine has no merge operator.
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        let _ = (key, operand);
+        Err(KvsError::Unsupported("merge".to_owned()))
+    }
+
+    /// Creates the keyspace `name` unless it already exists.
+    ///
+    /// A keyspace holds keys apart from the ones of the engine and of every
+    /// other keyspace. Names consist of up to 64 ASCII letters, digits, `-`
+    /// and `_`, and must not start with `__sled__`.
+    fn create_keyspace(&self, name: &str) -> Result<()> {
+        let _ = name;
+        Err(KvsError::Unsupported("keyspaces".to_owned()))
+    }
+
+    /// Returns an engine reading and writing the keyspace `name`.
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if the keyspace does not exist.
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        let _ = name;
+        Err(KvsError::Unsupported("keyspaces".to_owned()))
+    }
+
+    /// Drops the keyspace `name` together with all its keys.
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if the keyspace does not exist.
+    fn drop_keyspace(&self, name: &str) -> Result<()> {
+        let _ = name;
+        Err(KvsError::Unsupported("keyspaces".to_owned()))
+    }
+
+    /// Returns the names of all keyspaces, sorted.
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Returns the versions of `key` the engine keeps, newest first. A
+    /// missing key has none.
+    fn get_versions(&self, key: String) -> Result<Vec<KeyVersion>> {
+        let _ = key;
+        Err(KvsError::Unsupported("versions".to_owned()))
+    }
+
+    /// Returns the value of `key` at `at`, or `None` if no version kept
+    /// matches.
+    fn get_at(&self, key: String, at: VersionSpec) -> Result<Option<String>> {
+        let _ = (key, at);
+        Err(KvsError::Unsupported("versions".to_owned()))
+    }
+
+    /// Starts an optimistic transaction, see `Transaction`.
+    fn begin(&self) -> Result<Transaction> {
+        Err(KvsError::Unsupported("transactions".to_owned()))
+    }
+
+    /// Returns a `Watcher` receiving the changes of the keys starting with
+    /// `prefix` from now on, in the order they are made.
+    ///
+    /// Every event carries a sequence number that grows with the changes it
+    /// follows. Engines document what the numbers count.
+    fn watch_prefix(&self, prefix: &str) -> Result<Watcher> {
+        let _ = prefix;
+        Err(KvsError::Unsupported("watches".to_owned()))
+    }
+
+    /// Returns the changes recorded after the sequence number `seq`, oldest
+    /// first. Passing the number of the last change seen resumes where it
+    /// left off, also after a restart.
+    ///
+    /// It returns `KvsError::HistoryCompacted` if changes after `seq` are no
+    /// longer kept.
+    fn changes_since(&self, seq: u64) -> Result<Changes> {
+        let _ = seq;
+        Err(KvsError::Unsupported("change history".to_owned()))
+    }
+
+    /// Creates the secondary index `name` over the field at the JSON
+    /// `pointer` of the values, unless it already exists, and fills it from
+    /// the keys stored. Index names consist of the same characters as
+    /// keyspace names.
+    ///
+    /// Values that are not JSON, lack the field or hold an array or object
+    /// there are left out of the index. It returns `KvsError::IndexExists` if
+    /// an index of the name has another pointer.
+    fn create_index(&self, name: &str, pointer: &str) -> Result<()> {
+        let _ = (name, pointer);
+        Err(KvsError::Unsupported("secondary indexes".to_owned()))
+    }
+
+    /// Drops the secondary index `name`.
+    ///
+    /// It returns `KvsError::IndexNotFound` if the index does not exist.
+    fn drop_index(&self, name: &str) -> Result<()> {
+        let _ = name;
+        Err(KvsError::Unsupported("secondary indexes".to_owned()))
+    }
+
+    /// Returns the secondary indexes, sorted by name.
+    fn indexes(&self) -> Result<Vec<IndexDefinition>> {
+        Err(KvsError::Unsupported("secondary indexes".to_owned()))
+    }
+
+    /// Returns the key/value pairs whose field indexed by `name` lies in
+    /// `range`, sorted by field and then by key.
+    ///
+    /// Fields compare as JSON values of the same type, and across types
+    /// `null` sorts before booleans, numbers and then strings. Numbers
+    /// compare as `f64`, strings by their UTF-8 bytes.
+    fn index_scan<R: RangeBounds<Value>>(
+        &self,
+        name: &str,
+        range: R,
+    ) -> Result<Vec<(String, String)>> {
+        let _ = (name, range);
+        Err(KvsError::Unsupported("secondary indexes".to_owned()))
+    }
+
+    /// Returns the key/value pairs whose field indexed by `name` equals
+    /// `value`, sorted by key.
+    fn index_get(&self, name: &str, value: &Value) -> Result<Vec<(String, String)>> {
+        self.index_scan(name, value.clone()..=value.clone())
+    }
+}
+
+/// A version of the value of a key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyVersion {
+    /// Counts the writes of the key, starting at 1. Versions written before
+    /// history was kept are 0.
+    pub version: u64,
+    /// When the version was written, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The value of the key.
+    pub value: String,
+}
+
+/// Picks a version of a key for `KvsEngine::get_at`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VersionSpec {
+    /// The version with this number.
+    Version(u64),
+    /// The newest version written at or before this time, in milliseconds
+    /// since the Unix epoch.
+    Timestamp(u64),
+}
+
+/// Combines the value of a key with the operands passed to
+/// `KvsEngine::merge`.
+///
+/// Engines may apply operands long after they were merged, so the result must
+/// only depend on the arguments. Any `Fn(&str, Option<&str>, &str) -> String`
+/// is a merge operator:
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let max = |_key: &str, existing: Option<&str>, operand: &str| match existing {
+///     Some(existing) if existing >= operand => existing.to_owned(),
+///     _ => operand.to_owned(),
+/// };
+/// let store = KvStore::open_with_options(
+///     current_dir()?,
+///     KvStoreOptions::default().merge_operator(max),
+/// )?;
+/// store.merge("key".to_owned(), "b".to_owned())?;
+/// store.merge("key".to_owned(), "a".to_owned())?;
+/// assert_eq!(store.get("key".to_owned())?, Some("b".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub trait MergeOperator: Send + Sync + 'static {
+    /// Returns the value of `key` after merging `operand` into `existing`,
+    /// which is `None` if the key has no value.
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> String;
+}
+
+impl<F> MergeOperator for F
+where
+    F: Fn(&str, Option<&str>, &str) -> String + Send + Sync + 'static,
+{
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> String {
+        self(key, existing, operand)
+    }
+}
+
+impl fmt::Debug for dyn MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+/// Returns the range of the keys starting with `prefix`.
+pub(crate) fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    // the smallest string above all keys with the prefix replaces its last
+    // character by the next one, dropping trailing characters without one
+    let mut end: Vec<char> = prefix.chars().collect();
+    while let Some(last) = end.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            let end = end.into_iter().collect();
+            return (Bound::Included(prefix.to_owned()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_owned()), Bound::Unbounded)
+}
+
+/// Returns whether no key lies between `start` and `end`.
+fn is_empty_range(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// Adds `delta` to the integer `value` for `KvsEngine::incr_by`.
+fn add_to_value(value: Option<&str>, delta: i64) -> Result<i64> {
+    let current = match value {
+        Some(value) => value
+            .parse::<i64>()
+            .map_err(|_| KvsError::NotAnInteger(format!("{:?}", value)))?,
+        None => 0,
+    };
+    current
+        .checked_add(delta)
+        .ok_or_else(|| KvsError::IntegerOverflow(format!("{} + {}", current, delta)))
+}
+
+/// Checks that `name` may be used as a keyspace name.
+fn check_keyspace_name(name: &str) -> Result<()> {
+    check_name("keyspace", name)?;
+    if name.starts_with(RESERVED_KEYSPACE_PREFIX) {
+        return Err(KvsError::StringError(format!("reserved keyspace name {:?}", name)));
+    }
+    Ok(())
+}
+
+/// Checks that `name` may be used as a secondary index name.
+fn check_index_name(name: &str) -> Result<()> {
+    check_name("index", name)
+}
+
+fn check_name(kind: &str, name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_KEYSPACE_NAME_LEN
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+    if valid {
+        Ok(())
+    } else {
+        Err(KvsError::StringError(format!("invalid {} name {:?}", kind, name)))
+    }
 }
 
-pub use self::kvs::KvStore;
+pub use self::changes::{Change, ChangeKind, Changes};
+#[cfg(fuzzing)]
+pub(crate) use self::kvs::read_record;
+pub use self::kvs::{BloomStats, GenerationStats, KvStore, KvStoreOptions, StallStats};
+#[cfg(loom)]
+pub use self::live_gens::LiveGens;
+pub use self::lsm::{LsmKvsEngine, LsmOptions};
+pub use self::memory::MemoryKvsEngine;
+pub use self::secondary::IndexDefinition;
+pub(crate) use self::secondary::{sort_by_field, IndexUpdate, SecondaryIndexes};
+pub use self::sharded::ShardedEngine;
 pub use self::sled::SledKvsEngine;
+pub use self::transaction::Transaction;
+pub(crate) use self::transaction::{TransactionDriver, WriteTracker};
+pub use self::watch::{WatchEvent, Watcher};
+pub(crate) use self::watch::Watchers;
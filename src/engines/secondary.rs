@@ -0,0 +1,312 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{check_index_name, is_empty_range, prefix_range, KvsEngine};
+use crate::{KvsError, Result};
+
+// definitions are kept under this prefix, the entries of an index under its
+// name followed by it
+const SEPARATOR: char = '\u{0}';
+// entries written in one batch while an index is filled
+const FILL_BATCH: usize = 1024;
+
+/// A secondary index, see `KvsEngine::create_index`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexDefinition {
+    pub name: String,
+    /// The JSON pointer to the indexed field, like `/address/city`.
+    pub pointer: String,
+}
+
+/// The secondary indexes of an engine, kept in the internal engine `store`.
+///
+/// Every index has one entry `NAME \0 FIELD \0 KEY` for each key, where
+/// `FIELD` encodes the field of the value so that entries sort like the
+/// fields. Lookups check the entries against the values, so entries of
+/// values that have been replaced are harmless.
+#[derive(Clone)]
+pub(crate) struct SecondaryIndexes<E: KvsEngine> {
+    store: E,
+    // the pointer of every index, by name
+    pointers: BTreeMap<String, String>,
+}
+
+impl<E: KvsEngine> SecondaryIndexes<E> {
+    /// Loads the indexes defined in `store`.
+    pub(crate) fn open(store: E) -> Result<Self> {
+        let pointers = store
+            .scan(prefix_range(&SEPARATOR.to_string()))?
+            .into_iter()
+            .map(|(key, pointer)| (key[SEPARATOR.len_utf8()..].to_owned(), pointer))
+            .collect();
+        Ok(SecondaryIndexes { store, pointers })
+    }
+
+    pub(crate) fn store(&self) -> &E {
+        &self.store
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pointers.is_empty()
+    }
+
+    pub(crate) fn definitions(&self) -> Vec<IndexDefinition> {
+        self.pointers
+            .iter()
+            .map(|(name, pointer)| IndexDefinition {
+                name: name.clone(),
+                pointer: pointer.clone(),
+            })
+            .collect()
+    }
+
+    /// Creates the index `name` unless it exists, filled from the key/value
+    /// pairs of `values`.
+    pub(crate) fn create(
+        &mut self,
+        name: &str,
+        pointer: &str,
+        values: impl Iterator<Item = Result<(String, String)>>,
+    ) -> Result<()> {
+        check_index_name(name)?;
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err(KvsError::StringError(format!("invalid JSON pointer {:?}", pointer)));
+        }
+        match self.pointers.get(name) {
+            Some(existing) if existing == pointer => return Ok(()),
+            Some(existing) => {
+                return Err(KvsError::IndexExists(format!("{} indexes {:?}", name, existing)))
+            }
+            None => {}
+        }
+
+        let mut entries = Vec::new();
+        for value in values {
+            let (key, value) = value?;
+            if let Some(field) = field_of(pointer, &value) {
+                entries.push(entry_key(name, &field, &key));
+            }
+            if entries.len() == FILL_BATCH {
+                self.write(entries.drain(..), Vec::new())?;
+            }
+        }
+        self.write(entries, Vec::new())?;
+        // the definition goes last, so an index filled partway is never used
+        let mut txn = self.store.begin()?;
+        txn.set(definition_key(name), pointer.to_owned());
+        txn.commit()?;
+        self.pointers.insert(name.to_owned(), pointer.to_owned());
+        Ok(())
+    }
+
+    pub(crate) fn drop_index(&mut self, name: &str) -> Result<()> {
+        if !self.pointers.contains_key(name) {
+            return Err(KvsError::IndexNotFound(name.to_owned()));
+        }
+        // the definition goes first, so entries left behind are never used
+        self.store.remove(definition_key(name))?;
+        self.pointers.remove(name);
+        self.store.delete_prefix(&entry_prefix(name))
+    }
+
+    /// Adds the entries of the values a write is about to give keys, listed
+    /// by `changes` as key, value replaced and new value. A value of `None`
+    /// means the key has none.
+    ///
+    /// The entries of the values replaced are removed by the returned update
+    /// once the write is done, so lookups find the key by either value
+    /// meanwhile.
+    pub(crate) fn update(
+        &self,
+        changes: Vec<(String, Option<String>, Option<String>)>,
+    ) -> Result<IndexUpdate<E>> {
+        let mut added = Vec::new();
+        let mut stale = Vec::new();
+        for (key, old, new) in changes {
+            let old = self.entries(&key, old.as_deref());
+            let new = self.entries(&key, new.as_deref());
+            added.extend(new.difference(&old).cloned());
+            stale.extend(old.difference(&new).cloned());
+        }
+        self.write(added, Vec::new())?;
+        Ok(IndexUpdate {
+            indexes: Some(self.clone()),
+            stale,
+        })
+    }
+
+    /// Returns the key/value pairs whose field indexed by `name` lies between
+    /// `start` and `end`, checking every entry against the value `read`
+    /// returns for its key.
+    pub(crate) fn scan(
+        &self,
+        name: &str,
+        start: Bound<&Value>,
+        end: Bound<&Value>,
+        read: impl Fn(&str) -> Result<Option<String>>,
+    ) -> Result<Vec<(String, String)>> {
+        let pointer = self
+            .pointers
+            .get(name)
+            .ok_or_else(|| KvsError::IndexNotFound(name.to_owned()))?;
+        let prefix = entry_prefix(name);
+        // the entries of a field continue with a separator, the entries of
+        // the fields it is a prefix of with characters from `\u{1}` on
+        let bound = |field: &Value, next: char| -> Result<String> {
+            Ok(format!("{}{}{}", prefix, encode(field)?, next))
+        };
+        let start = match start {
+            Bound::Included(field) => Bound::Included(bound(field, SEPARATOR)?),
+            Bound::Excluded(field) => Bound::Included(bound(field, '\u{1}')?),
+            Bound::Unbounded => Bound::Included(prefix.clone()),
+        };
+        let end = match end {
+            Bound::Included(field) => Bound::Excluded(bound(field, '\u{1}')?),
+            Bound::Excluded(field) => Bound::Excluded(bound(field, SEPARATOR)?),
+            Bound::Unbounded => prefix_range(&prefix).1,
+        };
+        if is_empty_range(&start, &end) {
+            return Ok(Vec::new());
+        }
+
+        let mut found = Vec::new();
+        for (entry, _) in self.store.scan((start, end))? {
+            let mut parts = entry[prefix.len()..].splitn(2, SEPARATOR);
+            let (field, key) = match (parts.next(), parts.next()) {
+                (Some(field), Some(key)) => (field, key),
+                _ => continue,
+            };
+            if let Some(value) = read(key)? {
+                if field_of(pointer, &value).as_deref() == Some(field) {
+                    found.push((key.to_owned(), value));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Returns the entries of every index for `key` holding `value`.
+    fn entries(&self, key: &str, value: Option<&str>) -> BTreeSet<String> {
+        let value = match value {
+            Some(value) => value,
+            None => return BTreeSet::new(),
+        };
+        self.pointers
+            .iter()
+            .filter_map(|(name, pointer)| {
+                field_of(pointer, value).map(|field| entry_key(name, &field, key))
+            })
+            .collect()
+    }
+
+    /// Adds the entries `added` and removes the entries `removed` at once.
+    fn write(&self, added: impl IntoIterator<Item = String>, removed: Vec<String>) -> Result<()> {
+        let mut txn = self.store.begin()?;
+        for entry in added {
+            txn.set(entry, String::new());
+        }
+        for entry in removed {
+            match txn.remove(entry) {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        txn.commit()
+    }
+}
+
+/// Removes the entries of the values replaced by a write, see
+/// `SecondaryIndexes::update`.
+#[must_use]
+pub(crate) struct IndexUpdate<E: KvsEngine> {
+    // `None` if there are no indexes to update
+    indexes: Option<SecondaryIndexes<E>>,
+    stale: Vec<String>,
+}
+
+impl<E: KvsEngine> IndexUpdate<E> {
+    pub(crate) fn none() -> IndexUpdate<E> {
+        IndexUpdate {
+            indexes: None,
+            stale: Vec::new(),
+        }
+    }
+
+    /// Removes the stale entries once the write is done.
+    pub(crate) fn finish(self) {
+        if let Some(indexes) = self.indexes {
+            if self.stale.is_empty() {
+                return;
+            }
+            // lookups skip the entries left behind, so the write stands
+            if let Err(e) = indexes.write(Vec::new(), self.stale) {
+                warn!("Unable to remove stale index entries: {}", e);
+            }
+        }
+    }
+}
+
+/// Sorts the key/value pairs found in the index over `pointer` by field and
+/// then by key, like `KvsEngine::index_scan` returns them.
+pub(crate) fn sort_by_field(pointer: &str, pairs: &mut [(String, String)]) {
+    pairs.sort_by_cached_key(|(key, value)| (field_of(pointer, value), key.clone()));
+}
+
+fn definition_key(name: &str) -> String {
+    format!("{}{}", SEPARATOR, name)
+}
+
+fn entry_prefix(name: &str) -> String {
+    format!("{}{}", name, SEPARATOR)
+}
+
+fn entry_key(name: &str, field: &str, key: &str) -> String {
+    format!("{}{}{}{}{}", name, SEPARATOR, field, SEPARATOR, key)
+}
+
+/// Returns the encoded field at `pointer` of the JSON `value`, if it can be
+/// indexed.
+fn field_of(pointer: &str, value: &str) -> Option<String> {
+    let value: Value = serde_json::from_str(value).ok()?;
+    encode_field(value.pointer(pointer)?)
+}
+
+/// Encodes `field` into a string without `\0` that sorts like the field,
+/// `None` for arrays and objects.
+fn encode_field(field: &Value) -> Option<String> {
+    Some(match field {
+        Value::Null => "0".to_owned(),
+        Value::Bool(false) => "1".to_owned(),
+        Value::Bool(true) => "2".to_owned(),
+        Value::Number(number) => {
+            // adding 0.0 turns -0.0 into 0.0
+            let bits = (number.as_f64()? + 0.0).to_bits();
+            // flipping the sign bit of positive numbers and every bit of
+            // negative ones orders the bits like the numbers
+            let bits = if bits >> 63 == 1 { !bits } else { bits | 1 << 63 };
+            format!("3{:016x}", bits)
+        }
+        Value::String(string) => {
+            let mut encoded = String::with_capacity(string.len() + 1);
+            encoded.push('4');
+            for c in string.chars() {
+                match c {
+                    '\u{0}' => encoded.push_str("\u{1}\u{1}"),
+                    '\u{1}' => encoded.push_str("\u{1}\u{2}"),
+                    c => encoded.push(c),
+                }
+            }
+            encoded
+        }
+        Value::Array(_) | Value::Object(_) => return None,
+    })
+}
+
+fn encode(field: &Value) -> Result<String> {
+    encode_field(field)
+        .ok_or_else(|| KvsError::StringError(format!("{} cannot be looked up in an index", field)))
+}
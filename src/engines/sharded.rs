@@ -0,0 +1,362 @@
+use super::{
+    sort_by_field, Changes, IndexDefinition, KeyVersion, KvsEngine, Transaction, VersionSpec,
+    Watcher,
+};
+
+use crate::{KvsError, Result};
+
+use log::warn;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+
+// records the number of shards in the data directory
+const SHARDS_FILE: &str = "shards";
+// kvs-server records its engine next to the shards
+const ENGINE_FILE: &str = "engine";
+
+/// A `KvsEngine` that splits the keyspace by hash across independent
+/// engines.
+///
+/// Each shard lives in a subdirectory `shard-{i}` of its own, so writes to
+/// different shards never wait for the same lock or file. The number of shards
+/// is recorded in the data directory, and opening it with another number
+/// fails, as keys would be looked up in the wrong shards. Transactions and
+/// the change history need a single shard.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, ShardedEngine};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let engine = ShardedEngine::open(current_dir()?, 4, |path| KvStore::open(path))?;
+/// engine.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ShardedEngine<E: KvsEngine> {
+    // each clone of the wrapper holds clones of the shards
+    shards: Vec<E>,
+}
+
+impl<E: KvsEngine> ShardedEngine<E> {
+    /// Opens `shards` engines with `open` in the subdirectories of `path`.
+    ///
+    /// This will create the directories if they do not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ShardCountMismatch` if the directory was created
+    /// with another number of shards, or holds an engine that is not sharded,
+    /// whose keys would no longer be found. It propagates the errors of
+    /// `open`.
+    pub fn open<F>(path: impl Into<PathBuf>, shards: usize, open: F) -> Result<Self>
+    where
+        F: Fn(&Path) -> Result<E>,
+    {
+        if shards == 0 {
+            return Err(KvsError::StringError("at least one shard is required".to_owned()));
+        }
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        let shards_path = path.join(SHARDS_FILE);
+        if shards_path.exists() {
+            let content = fs::read_to_string(&shards_path)?;
+            let recorded: usize = content.trim().parse().map_err(|_| {
+                KvsError::StringError(format!("invalid shard count: {:?}", content))
+            })?;
+            if recorded != shards {
+                return Err(KvsError::ShardCountMismatch(format!(
+                    "{} holds {} shards, not {}",
+                    path.display(),
+                    recorded,
+                    shards
+                )));
+            }
+        } else {
+            if holds_engine(&path)? {
+                return Err(KvsError::ShardCountMismatch(format!(
+                    "{} holds an unsharded engine, not {} shards",
+                    path.display(),
+                    shards
+                )));
+            }
+            let tmp_path = path.join(format!("{}.tmp", SHARDS_FILE));
+            fs::write(&tmp_path, format!("{}\n", shards))?;
+            fs::rename(&tmp_path, &shards_path)?;
+        }
+
+        let shards = (0..shards)
+            .map(|i| {
+                let shard_path = path.join(format!("shard-{}", i));
+                fs::create_dir_all(&shard_path)?;
+                open(&shard_path)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShardedEngine { shards })
+    }
+
+    /// Returns the shards in the order keys are assigned to them.
+    pub fn shards(&self) -> &[E] {
+        &self.shards
+    }
+
+    fn shard(&self, key: &str) -> &E {
+        &self.shards[self.shard_number(key)]
+    }
+
+    fn shard_number(&self, key: &str) -> usize {
+        (shard_hash(key) % self.shards.len() as u64) as usize
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ShardedEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    /// Deletes the range from every shard, one after the other.
+    fn delete_range<R: RangeBounds<String>>(&self, range: R) -> Result<()> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        for shard in self.shards.iter() {
+            shard.delete_range(range.clone())?;
+        }
+        Ok(())
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.shard(&key).incr_by(key, delta)
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.shard(&key).merge(key, operand)
+    }
+
+    fn get_versions(&self, key: String) -> Result<Vec<KeyVersion>> {
+        self.shard(&key).get_versions(key)
+    }
+
+    fn get_at(&self, key: String, at: VersionSpec) -> Result<Option<String>> {
+        self.shard(&key).get_at(key, at)
+    }
+
+    /// Scans every shard and merges the results.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            pairs.extend(shard.scan(range.clone())?);
+        }
+        // the stable sort merges the already sorted runs of the shards
+        pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(pairs)
+    }
+
+    /// Creates the keyspace in every shard.
+    ///
+    /// If a shard fails, the keyspace is dropped again from the shards it
+    /// was created in, so that they still agree.
+    fn create_keyspace(&self, name: &str) -> Result<()> {
+        let mut created: Vec<&E> = Vec::new();
+        for shard in self.shards.iter() {
+            let existed = shard.keyspaces()?.iter().any(|existing| existing == name);
+            if let Err(e) = shard.create_keyspace(name) {
+                for shard in created {
+                    if let Err(e) = shard.drop_keyspace(name) {
+                        warn!("Unable to drop the keyspace {} created in part: {}", name, e);
+                    }
+                }
+                return Err(e);
+            }
+            if !existed {
+                created.push(shard);
+            }
+        }
+        Ok(())
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.keyspace(name))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShardedEngine { shards })
+    }
+
+    /// Drops the keyspace from every shard that has it.
+    ///
+    /// The dropped keys cannot be brought back, so a drop that fails part
+    /// way is finished by dropping the keyspace again.
+    fn drop_keyspace(&self, name: &str) -> Result<()> {
+        let mut found = false;
+        for shard in self.shards.iter() {
+            match shard.drop_keyspace(name) {
+                Ok(()) => found = true,
+                Err(KvsError::KeyspaceNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(KvsError::KeyspaceNotFound(name.to_owned()))
+        }
+    }
+
+    /// Returns the keyspaces of any shard, so that one left behind by a drop
+    /// that failed part way can be dropped again.
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let mut names = BTreeSet::new();
+        for shard in self.shards.iter() {
+            names.extend(shard.keyspaces()?);
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    /// Starts a transaction on the only shard.
+    ///
+    /// It returns `KvsError::Unsupported` with more than one shard, as the
+    /// shards could not commit a transaction together atomically.
+    fn begin(&self) -> Result<Transaction> {
+        match self.shards.as_slice() {
+            [shard] => shard.begin(),
+            _ => Err(KvsError::Unsupported("transactions across shards".to_owned())),
+        }
+    }
+
+    /// Returns the changes of the only shard.
+    ///
+    /// It returns `KvsError::Unsupported` with more than one shard, as every
+    /// shard numbers its changes apart, so no one number tells where to
+    /// resume in all of them.
+    fn changes_since(&self, seq: u64) -> Result<Changes> {
+        match self.shards.as_slice() {
+            [shard] => shard.changes_since(seq),
+            _ => Err(KvsError::Unsupported("change history across shards".to_owned())),
+        }
+    }
+
+    /// Creates the index in every shard, each indexing its own keys.
+    ///
+    /// If a shard fails, the index is dropped again from the shards it was
+    /// created in, so that they still agree.
+    fn create_index(&self, name: &str, pointer: &str) -> Result<()> {
+        let mut created: Vec<&E> = Vec::new();
+        for shard in self.shards.iter() {
+            let existed = shard.indexes()?.iter().any(|index| index.name == name);
+            if let Err(e) = shard.create_index(name, pointer) {
+                for shard in created {
+                    if let Err(e) = shard.drop_index(name) {
+                        warn!("Unable to drop the index {} created in part: {}", name, e);
+                    }
+                }
+                return Err(e);
+            }
+            if !existed {
+                created.push(shard);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the index from every shard that has it, so a drop that fails
+    /// part way is finished by dropping the index again.
+    fn drop_index(&self, name: &str) -> Result<()> {
+        let mut found = false;
+        for shard in self.shards.iter() {
+            match shard.drop_index(name) {
+                Ok(()) => found = true,
+                Err(KvsError::IndexNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(KvsError::IndexNotFound(name.to_owned()))
+        }
+    }
+
+    /// Returns the indexes of any shard.
+    fn indexes(&self) -> Result<Vec<IndexDefinition>> {
+        let mut indexes = BTreeMap::new();
+        for shard in self.shards.iter() {
+            for index in shard.indexes()? {
+                indexes.entry(index.name.clone()).or_insert(index);
+            }
+        }
+        Ok(indexes.into_values().collect())
+    }
+
+    /// Looks the keys up in every shard and merges the results.
+    fn index_scan<R: RangeBounds<Value>>(
+        &self,
+        name: &str,
+        range: R,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            pairs.extend(shard.index_scan(name, range.clone())?);
+        }
+        // every shard has the index, or its scan would have failed
+        let index = self.shards[0]
+            .indexes()?
+            .into_iter()
+            .find(|index| index.name == name)
+            .ok_or_else(|| KvsError::IndexNotFound(name.to_owned()))?;
+        sort_by_field(&index.pointer, &mut pairs);
+        Ok(pairs)
+    }
+
+    /// Watches the keys with `prefix` in every shard.
+    ///
+    /// Events carry the sequence numbers of their shard, so only the events
+    /// of one key are ordered by them.
+    fn watch_prefix(&self, prefix: &str) -> Result<Watcher> {
+        let watchers = self
+            .shards
+            .iter()
+            .map(|shard| shard.watch_prefix(prefix))
+            .collect::<Result<_>>()?;
+        Ok(Watcher::merge(watchers))
+    }
+}
+
+/// Returns whether the directory at `path`, which has no shard count file,
+/// holds anything but the engine file of kvs-server.
+fn holds_engine(path: &Path) -> Result<bool> {
+    for entry in fs::read_dir(path)? {
+        if entry?.file_name() != ENGINE_FILE {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Hashes a key to pick its shard. The hash decides where keys are stored,
+/// so it must never change.
+fn shard_hash(key: &str) -> u64 {
+    // 64-bit FNV-1a
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    // FNV leaves the low bits poorly mixed, finish with the splitmix64 finalizer
+    let hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
@@ -1,28 +1,123 @@
-use super::KvsEngine;
+use super::{
+    add_to_value, check_keyspace_name, is_empty_range, KvsEngine, MergeOperator, Transaction,
+    TransactionDriver, WatchEvent, Watcher, WriteTracker,
+};
 
 use crate::{KvsError, Result};
 
-use sled::{Db, Tree};
+use sled::transaction::{abort, TransactionError};
+use sled::{Batch, Db, Event, Subscriber, Tree};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::ops::RangeBounds;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
 
+// the tree sled creates for itself
+const DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// A `KvsEngine` backed by a sled `Db`. Every keyspace is a `Tree` of the
+/// same name.
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    // the tree this handle reads and writes
+    tree: Tree,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    // the transactions of the tree
+    txns: Arc<TreeTransactions>,
+    // the transactions of every tree used through the engine, by name
+    trees: Arc<Mutex<HashMap<String, Arc<TreeTransactions>>>>,
+    // the sequence number of the latest event of any watcher of the engine
+    watch_seq: Arc<AtomicU64>,
+}
+
+/// Tracks the writes of a tree for the transactions reading it.
+#[derive(Default)]
+struct TreeTransactions {
+    // held by writes while they are tracked and made, and by `begin`
+    // exclusively, so that no write is half made when a transaction starts
+    gate: RwLock<()>,
+    tracker: Mutex<WriteTracker>,
+}
 
 impl SledKvsEngine {
     pub fn new(db: Db) -> Self {
-        SledKvsEngine(db)
+        let tree: Tree = (*db).clone();
+        SledKvsEngine {
+            db,
+            tree,
+            merge_operator: None,
+            txns: Arc::default(),
+            trees: Arc::default(),
+            watch_seq: Arc::default(),
+        }
+    }
+
+    /// Creates an engine that merges with `operator`, registered as the
+    /// merge operator of every tree it uses.
+    pub fn with_merge_operator(db: Db, operator: impl MergeOperator) -> Self {
+        let mut engine = SledKvsEngine::new(db);
+        engine.merge_operator = Some(Arc::new(operator));
+        engine.register_merge_operator();
+        engine
+    }
+
+    fn register_merge_operator(&self) {
+        if let Some(operator) = &self.merge_operator {
+            let operator = Arc::clone(operator);
+            self.tree.set_merge_operator(move |key: &[u8], old: Option<&[u8]>, operand: &[u8]| {
+                let key = String::from_utf8_lossy(key);
+                let old = old.map(String::from_utf8_lossy);
+                let operand = String::from_utf8_lossy(operand);
+                Some(operator.merge(&key, old.as_deref(), &operand).into_bytes())
+            });
+        }
+    }
+
+    /// Makes `write` of `keys`, recording the values they have for the open
+    /// transactions that may read them first.
+    fn tracked<'a, T>(
+        &self,
+        keys: impl IntoIterator<Item = &'a str>,
+        write: impl FnOnce(&Tree) -> Result<T>,
+    ) -> Result<T> {
+        let _gate = self.txns.gate.read().unwrap();
+        for key in keys {
+            let needs_replaced = self.txns.tracker.lock().unwrap().needs_replaced(key);
+            let replaced = if needs_replaced {
+                Some(self.get(key.to_owned())?)
+            } else {
+                None
+            };
+            self.txns.tracker.lock().unwrap().record(key, replaced);
+        }
+        write(&self.tree)
+    }
+
+    fn has_keyspace(&self, name: &str) -> bool {
+        self.db
+            .tree_names()
+            .iter()
+            .any(|tree_name| tree_name.as_ref() == name.as_bytes())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.insert(key, value.into_bytes()).map(|_|())?;
-        tree.flush()?;
-        Ok(())
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.tracked(Some(key.as_str()), |tree| {
+            tree.insert(key.as_str(), value.into_bytes()).map(|_|())?;
+            tree.flush()?;
+            Ok(())
+        })
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.0;
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let tree: &Tree = &self.tree;
         Ok(tree.get(key)?
             .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
             .map(String::from_utf8)
@@ -30,10 +125,287 @@ impl KvsEngine for SledKvsEngine {
         )
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+    fn remove(&self, key: String) -> Result<()> {
+        self.tracked(Some(key.as_str()), |tree| {
+            tree.remove(key.as_str())?.ok_or(KvsError::KeyNotFound)?;
+            tree.flush()?;
+            Ok(())
+        })
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let tree: &Tree = &self.tree;
+        tree.range(range)
+            .map(|res| {
+                let (key, value) = res?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Removes the keys in `range` in a single atomic batch.
+    fn delete_range<R: RangeBounds<String>>(&self, range: R) -> Result<()> {
+        if is_empty_range(&range.start_bound().cloned(), &range.end_bound().cloned()) {
+            return Ok(());
+        }
+        let keys = self
+            .tree
+            .range(range)
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect::<Result<Vec<_>>>()?;
+        self.tracked(keys.iter().map(String::as_str), |tree| {
+            let mut batch = Batch::default();
+            for key in &keys {
+                batch.remove(key.as_str());
+            }
+            tree.apply_batch(batch)?;
+            tree.flush()?;
+            Ok(())
+        })
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.tracked(Some(key.as_str()), |tree| {
+            // the closure may run several times, only the last result counts
+            let mut result = Ok(0);
+            tree.update_and_fetch(key.as_str(), |old| {
+                let value = old
+                    .map(|old| {
+                        std::str::from_utf8(old).map_err(|_| {
+                            KvsError::NotAnInteger(format!("{:?}", String::from_utf8_lossy(old)))
+                        })
+                    })
+                    .transpose()
+                    .and_then(|old| add_to_value(old, delta));
+                let new = match &value {
+                    Ok(value) => Some(value.to_string().into_bytes()),
+                    // leave the value alone
+                    Err(_) => old.map(<[u8]>::to_vec),
+                };
+                result = value;
+                new
+            })?;
+            tree.flush()?;
+            result
+        })
+    }
+
+    /// Merges with sled's native merge operator.
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        if self.merge_operator.is_none() {
+            return Err(KvsError::Unsupported("merge without a merge operator".to_owned()));
+        }
+        self.tracked(Some(key.as_str()), |tree| {
+            tree.merge(key.as_str(), operand.as_str())?;
+            tree.flush()?;
+            Ok(())
+        })
+    }
+
+    fn create_keyspace(&self, name: &str) -> Result<()> {
+        check_keyspace_name(name)?;
+        self.db.open_tree(name)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        check_keyspace_name(name)?;
+        if !self.has_keyspace(name) {
+            return Err(KvsError::KeyspaceNotFound(name.to_owned()));
+        }
+        let txns = Arc::clone(self.trees.lock().unwrap().entry(name.to_owned()).or_default());
+        let engine = SledKvsEngine {
+            db: self.db.clone(),
+            tree: self.db.open_tree(name)?,
+            merge_operator: self.merge_operator.clone(),
+            txns,
+            trees: Arc::clone(&self.trees),
+            watch_seq: Arc::clone(&self.watch_seq),
+        };
+        engine.register_merge_operator();
+        Ok(engine)
+    }
+
+    fn drop_keyspace(&self, name: &str) -> Result<()> {
+        check_keyspace_name(name)?;
+        if !self.db.drop_tree(name)? {
+            return Err(KvsError::KeyspaceNotFound(name.to_owned()));
+        }
+        self.trees.lock().unwrap().remove(name);
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let mut names = self
+            .db
+            .tree_names()
+            .into_iter()
+            .filter(|name| name.as_ref() != DEFAULT_TREE)
+            .map(|name| Ok(String::from_utf8(name.to_vec())?))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    /// Starts a transaction whose commit checks the values it read and
+    /// writes in a single `Tree::transaction`.
+    ///
+    /// sled has no snapshots, so the engine records the values its writes
+    /// replace while transactions are open. Writes made through another
+    /// `SledKvsEngine` of the same `Db` are not seen as of `begin`, though
+    /// the commit still checks the values read.
+    fn begin(&self) -> Result<Transaction> {
+        let _gate = self.txns.gate.write().unwrap();
+        let start = self.txns.tracker.lock().unwrap().begin();
+        Ok(Transaction::new(SledTransaction {
+            engine: self.clone(),
+            start,
+        }))
+    }
+
+    /// Watches the keys with `prefix` through `Tree::watch_prefix`.
+    ///
+    /// sled does not number its events, so they take the next number of a
+    /// counter shared by the watchers of the engine as they arrive. The
+    /// watchers of a change each see a different number for it.
+    fn watch_prefix(&self, prefix: &str) -> Result<Watcher> {
+        let mut subscriber = self.tree.watch_prefix(prefix.as_bytes());
+        let watch_seq = Arc::clone(&self.watch_seq);
+        Ok(Watcher::spawn(move |timeout| {
+            let (key, value) = match next_event(&mut subscriber, timeout)? {
+                Some(Event::Insert { key, value }) => (key, Some(value)),
+                Some(Event::Remove { key }) => (key, None),
+                None => return Ok(None),
+            };
+            Ok(Some(WatchEvent {
+                key: String::from_utf8(key.to_vec())?,
+                value: value.map(|value| String::from_utf8(value.to_vec())).transpose()?,
+                seq: watch_seq.fetch_add(1, Ordering::SeqCst) + 1,
+            }))
+        }))
+    }
+}
+
+/// Waits up to `timeout` for the next event of `subscriber`, returning
+/// `None` if there is none by then. It fails once the database is closed.
+///
+/// `Subscriber::next_timeout` reports a timeout like a closed database, so
+/// the subscriber is polled as a future instead.
+fn next_event(subscriber: &mut Subscriber, timeout: Duration) -> Result<Option<Event>> {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let deadline = Instant::now() + timeout;
+    loop {
+        match Pin::new(&mut *subscriber).poll(&mut cx) {
+            Poll::Ready(Some(event)) => return Ok(Some(event)),
+            Poll::Ready(None) => {
+                return Err(KvsError::StringError("the sled database has been closed".to_owned()))
+            }
+            Poll::Pending => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(None);
+                }
+                thread::park_timeout(deadline - now);
+            }
+        }
+    }
+}
+
+/// Wakes the thread waiting in `next_event`.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+struct SledTransaction {
+    engine: SledKvsEngine,
+    // the number of writes tracked when the transaction began
+    start: u64,
+}
+
+impl TransactionDriver for SledTransaction {
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        // writes are tracked before they are made, or by a commit holding the
+        // tracker meanwhile, so looking them up after reading replaces any
+        // value newer than the start
+        let value = self.engine.get(key.to_owned())?;
+        let tracker = self.engine.txns.tracker.lock().unwrap();
+        Ok(tracker.read(key, self.start, value))
+    }
+
+    fn commit(
+        &mut self,
+        reads: &BTreeMap<String, Option<String>>,
+        writes: &BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        let _gate = self.engine.txns.gate.read().unwrap();
+        // held until the writes are tracked, so that a reader finding them
+        // made waits to find them tracked too
+        let mut tracker = self.engine.txns.tracker.lock().unwrap();
+        for key in reads.keys().chain(writes.keys()) {
+            tracker.check(key, self.start)?;
+        }
+        let tree = &self.engine.tree;
+        // sled may run the closure more than once, so it only returns the
+        // keys written with the values replaced, tracked once it commits
+        let res = tree.transaction(|tree| {
+            for (key, seen) in reads {
+                let value = tree.get(key.as_bytes())?;
+                if value.as_deref() != seen.as_ref().map(String::as_bytes) {
+                    return abort(KvsError::TransactionConflict(format!(
+                        "{:?} has been written since the transaction began",
+                        key
+                    )));
+                }
+            }
+            let mut written = Vec::new();
+            for (key, value) in writes {
+                let old = match value {
+                    Some(value) => tree.insert(key.as_bytes(), value.as_bytes())?,
+                    // removing a missing key writes nothing
+                    None => match tree.remove(key.as_bytes())? {
+                        Some(old) => Some(old),
+                        None => continue,
+                    },
+                };
+                let replaced = if tracker.needs_replaced(key) {
+                    match old.map(|old| String::from_utf8(old.to_vec())).transpose() {
+                        Ok(old) => Some(old),
+                        Err(e) => return abort(e.into()),
+                    }
+                } else {
+                    None
+                };
+                written.push((key, replaced));
+            }
+            Ok(written)
+        });
+        let written = match res {
+            Ok(written) => written,
+            Err(TransactionError::Abort(e)) => return Err(e),
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        };
+        for (key, replaced) in written {
+            tracker.record(key, replaced);
+        }
+        drop(tracker);
         tree.flush()?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl Drop for SledTransaction {
+    fn drop(&mut self) {
+        self.engine.txns.tracker.lock().unwrap().end(self.start);
+    }
+}
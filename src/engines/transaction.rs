@@ -0,0 +1,186 @@
+use std::collections::{btree_map, BTreeMap, HashMap};
+
+use crate::{KvsError, Result};
+
+/// An optimistic transaction started by `KvsEngine::begin`.
+///
+/// Writes are buffered until `commit` applies them all at once. Reads see
+/// the transaction's own writes, and otherwise the engine as of `begin`. The
+/// commit fails with `KvsError::TransactionConflict` if a key the
+/// transaction read has been written since, and the transaction should then
+/// be retried from the start. Dropping a transaction discards its writes.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let store = KvStore::open(current_dir()?)?;
+/// let mut txn = store.begin()?;
+/// let balance = txn.get("balance".to_owned())?.unwrap_or_default();
+/// txn.set("previous".to_owned(), balance);
+/// txn.set("balance".to_owned(), "0".to_owned());
+/// txn.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction {
+    driver: Box<dyn TransactionDriver>,
+    // the keys read from the engine, with the values seen
+    reads: BTreeMap<String, Option<String>>,
+    // the buffered writes, `None` removing the key
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(driver: impl TransactionDriver) -> Transaction {
+        Transaction {
+            driver: Box::new(driver),
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of `key` within the transaction.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key).or_else(|| self.reads.get(&key)) {
+            return Ok(value.clone());
+        }
+        let value = self.driver.get(&key)?;
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Sets `key` to `value` once the transaction commits.
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes `key` once the transaction commits.
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key has no value within the
+    /// transaction.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Applies the writes of the transaction atomically.
+    ///
+    /// It returns `KvsError::TransactionConflict`, writing nothing, if a key
+    /// the transaction read has been written since it began.
+    pub fn commit(mut self) -> Result<()> {
+        self.driver.commit(&self.reads, &self.writes)
+    }
+}
+
+/// The engine side of a `Transaction`.
+pub(crate) trait TransactionDriver: Send + 'static {
+    /// Reads the value of `key` as of the start of the transaction.
+    fn get(&mut self, key: &str) -> Result<Option<String>>;
+
+    /// Applies `writes` atomically unless a key of `reads` no longer has the
+    /// value seen.
+    fn commit(
+        &mut self,
+        reads: &BTreeMap<String, Option<String>>,
+        writes: &BTreeMap<String, Option<String>>,
+    ) -> Result<()>;
+}
+
+/// Tracks the keys written while transactions are open, with the values
+/// they replaced, for engines reading a snapshot as of `begin`.
+///
+/// Writes are recorded before they become visible, and no write may be
+/// amid recording and becoming visible while a transaction begins.
+#[derive(Default)]
+pub(crate) struct WriteTracker {
+    // the sequence number of the latest write
+    seq: u64,
+    // the sequence numbers open transactions started at, with their count
+    open: BTreeMap<u64, usize>,
+    // the writes of each key, kept while any transaction is open
+    written: HashMap<String, Writes>,
+}
+
+#[derive(Default)]
+struct Writes {
+    // the sequence number of the latest write
+    latest: u64,
+    // the values replaced by the writes an open transaction may read past,
+    // with the sequence numbers of those writes
+    replaced: Vec<(u64, Option<String>)>,
+}
+
+impl WriteTracker {
+    /// Whether the value `key` has before its next write must be recorded,
+    /// because an open transaction began since the key was last written.
+    pub(crate) fn needs_replaced(&self, key: &str) -> bool {
+        match self.open.keys().next_back() {
+            Some(&newest) => self
+                .written
+                .get(key)
+                .is_none_or(|writes| writes.latest <= newest),
+            None => false,
+        }
+    }
+
+    /// Counts a write of `key`, before it becomes visible to readers.
+    /// `replaced` is the value it replaces, if `needs_replaced` asked for it.
+    pub(crate) fn record(&mut self, key: &str, replaced: Option<Option<String>>) {
+        self.seq += 1;
+        if !self.open.is_empty() {
+            let writes = self.written.entry(key.to_owned()).or_default();
+            writes.latest = self.seq;
+            if let Some(value) = replaced {
+                writes.replaced.push((self.seq, value));
+            }
+        }
+    }
+
+    /// Opens a transaction and returns the sequence number it starts at.
+    pub(crate) fn begin(&mut self) -> u64 {
+        *self.open.entry(self.seq).or_insert(0) += 1;
+        self.seq
+    }
+
+    pub(crate) fn end(&mut self, start: u64) {
+        if let btree_map::Entry::Occupied(mut entry) = self.open.entry(start) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+        if self.open.is_empty() {
+            self.written.clear();
+        }
+    }
+
+    /// Returns the value `key` had at `start`, given the `current` value
+    /// read from the engine before calling this.
+    pub(crate) fn read(&self, key: &str, start: u64, current: Option<String>) -> Option<String> {
+        match self.written.get(key) {
+            Some(writes) if writes.latest > start => writes
+                .replaced
+                .iter()
+                .find(|(seq, _)| *seq > start)
+                .map(|(_, value)| value.clone())
+                .expect("the first write since an open transaction began is recorded"),
+            _ => current,
+        }
+    }
+
+    /// Fails if `key` has been written since `start`.
+    pub(crate) fn check(&self, key: &str, start: u64) -> Result<()> {
+        match self.written.get(key) {
+            Some(writes) if writes.latest > start => Err(KvsError::TransactionConflict(format!(
+                "{:?} has been written since the transaction began",
+                key
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
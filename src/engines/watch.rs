@@ -0,0 +1,174 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, Select, Sender, TryRecvError};
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+// how long a thread feeding a watcher waits for an event before it checks
+// whether the watcher is still there
+const SPAWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A change of a key reported to a `Watcher`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchEvent {
+    pub key: String,
+    /// The new value of the key, `None` if it has been removed.
+    pub value: Option<String>,
+    /// Orders the events of a watcher, see `KvsEngine::watch_prefix`.
+    pub seq: u64,
+}
+
+/// The changes of the keys with a prefix, returned by
+/// `KvsEngine::watch_prefix`.
+///
+/// Events queue up until they are read, so a watcher should be read or
+/// dropped. Iterating blocks until the next event, and ends once the engine
+/// has been closed.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let store = KvStore::open(current_dir()?)?;
+/// let mut watcher = store.watch_prefix("user:")?;
+/// store.set("user:1".to_owned(), "alice".to_owned())?;
+/// let event = watcher.next().unwrap();
+/// assert_eq!(event.key, "user:1");
+/// assert_eq!(event.value, Some("alice".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Watcher {
+    // one receiver per source, dropped once the source is gone
+    events: Vec<Receiver<WatchEvent>>,
+    // never sent on, the threads feeding the watcher see it dropped
+    hang_ups: Vec<Sender<()>>,
+}
+
+impl Watcher {
+    /// Returns a watcher fed by a thread that moves the events `poll`
+    /// returns over until it fails or the watcher is dropped.
+    ///
+    /// `poll` waits up to the given timeout for the next event, returning
+    /// `None` if there is none by then.
+    pub(crate) fn spawn<F>(mut poll: F) -> Watcher
+    where
+        F: FnMut(Duration) -> Result<Option<WatchEvent>> + Send + 'static,
+    {
+        let (sender, events) = channel::unbounded();
+        let (hang_up, hung_up) = channel::bounded::<()>(0);
+        thread::spawn(move || loop {
+            match poll(SPAWN_POLL_INTERVAL) {
+                Ok(Some(event)) => {
+                    if sender.send(event).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => {
+                    if let Err(TryRecvError::Disconnected) = hung_up.try_recv() {
+                        return;
+                    }
+                }
+                // the source failed
+                Err(_) => return,
+            }
+        });
+        Watcher {
+            events: vec![events],
+            hang_ups: vec![hang_up],
+        }
+    }
+
+    /// Returns a watcher receiving the events of all `watchers`. Events of
+    /// different watchers are not ordered.
+    pub(crate) fn merge(watchers: Vec<Watcher>) -> Watcher {
+        let mut merged = Watcher {
+            events: Vec::new(),
+            hang_ups: Vec::new(),
+        };
+        for watcher in watchers {
+            merged.events.extend(watcher.events);
+            merged.hang_ups.extend(watcher.hang_ups);
+        }
+        merged
+    }
+
+    /// Waits up to `timeout` for the next event, returning `None` if there
+    /// is none by then.
+    ///
+    /// It returns an error once the engine has been closed.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>> {
+        self.next_before(Some(Instant::now() + timeout))
+    }
+
+    fn next_before(&mut self, deadline: Option<Instant>) -> Result<Option<WatchEvent>> {
+        while !self.events.is_empty() {
+            let mut select = Select::new();
+            for events in &self.events {
+                select.recv(events);
+            }
+            let op = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match select.select_timeout(timeout) {
+                        Ok(op) => op,
+                        Err(_) => return Ok(None),
+                    }
+                }
+                None => select.select(),
+            };
+            let index = op.index();
+            match op.recv(&self.events[index]) {
+                Ok(event) => return Ok(Some(event)),
+                Err(_) => {
+                    self.events.remove(index);
+                }
+            }
+        }
+        Err(KvsError::StringError("the watched engine has been closed".to_owned()))
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        self.next_before(None).ok().flatten()
+    }
+}
+
+/// The watchers of an engine, with their prefixes.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    senders: Mutex<Vec<(String, Sender<WatchEvent>)>>,
+}
+
+impl Watchers {
+    pub(crate) fn watch(&self, prefix: &str) -> Watcher {
+        let (sender, events) = channel::unbounded();
+        self.senders.lock().unwrap().push((prefix.to_owned(), sender));
+        Watcher {
+            events: vec![events],
+            hang_ups: Vec::new(),
+        }
+    }
+
+    /// Returns whether a watcher may be interested in `key`.
+    pub(crate) fn is_watched(&self, key: &str) -> bool {
+        let senders = self.senders.lock().unwrap();
+        senders.iter().any(|(prefix, _)| key.starts_with(prefix.as_str()))
+    }
+
+    /// Sends `event` to the watchers of its key, forgetting the watchers
+    /// that have been dropped.
+    pub(crate) fn send(&self, event: WatchEvent) {
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|(prefix, sender)| {
+            !event.key.starts_with(prefix.as_str()) || sender.send(event.clone()).is_ok()
+        });
+    }
+}
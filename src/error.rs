@@ -1,6 +1,7 @@
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::io;
-use std::error::Error;
 use std::string::FromUtf8Error;
 
 #[derive(Fail, Debug)]
@@ -19,8 +20,35 @@ pub enum KvsError {
     Utf8(#[cause] FromUtf8Error),
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
-    #[fail(display = "{}"), _0]
+    #[fail(display = "{}", _0)]
     StringError(String),
+    #[fail(display = "Write stalled: {}", _0)]
+    WriteStall(String),
+    #[fail(display = "Store is read-only: {}", _0)]
+    ReadOnly(String),
+    #[fail(display = "Capacity exceeded: {}", _0)]
+    CapacityExceeded(String),
+    #[fail(display = "Shard count mismatch: {}", _0)]
+    ShardCountMismatch(String),
+    #[fail(display = "Keyspace not found: {}", _0)]
+    KeyspaceNotFound(String),
+    #[fail(display = "Not supported: {}", _0)]
+    Unsupported(String),
+    #[fail(display = "Value is not an integer: {}", _0)]
+    NotAnInteger(String),
+    #[fail(display = "Integer overflow: {}", _0)]
+    IntegerOverflow(String),
+    // retrying the transaction from the start may succeed
+    #[fail(display = "Transaction conflict: {}", _0)]
+    TransactionConflict(String),
+    // the records asked for are older than the oldest one kept
+    #[fail(display = "History compacted away: {}", _0)]
+    HistoryCompacted(String),
+    #[fail(display = "Index not found: {}", _0)]
+    IndexNotFound(String),
+    // an index of the name exists with another pointer
+    #[fail(display = "Index exists: {}", _0)]
+    IndexExists(String),
 }
 
 impl From<io::Error> for KvsError {
@@ -0,0 +1,102 @@
+//! Entry points of the fuzz targets in `fuzz/`, only built with
+//! `--cfg fuzzing` as `cargo fuzz` passes it.
+//!
+//! Errors are expected of arbitrary input, so they are ignored. Only panics,
+//! endless loops and runaway allocations count.
+
+use crate::common::{
+    ChangesResponse, GetResponse, IncrResponse, IndexResponse, IndexScanResponse, KeyspaceResponse,
+    LimitedReader, ListIndexesResponse, ListKeyspacesResponse, RemoveResponse, SetResponse,
+    TransactionResponse, VersionsResponse, WatchResponse,
+};
+use crate::{conformance, FileSystem, KvStore, KvStoreOptions, KvsEngine, MemoryFileSystem, Result};
+use serde::de::DeserializeOwned;
+use serde_json::Deserializer;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Opens a store whose only log file holds `log`, with the index in memory
+/// and on disk, then reads and compacts all it holds.
+pub fn replay_log(log: &[u8]) {
+    for &budget in &[None, Some(256)] {
+        let _ = replay_log_with(log, budget);
+    }
+}
+
+fn replay_log_with(log: &[u8], budget: Option<u64>) -> Result<()> {
+    let fs = MemoryFileSystem::new();
+    let path = Path::new("store");
+    fs.create_dir_all(path)?;
+    fs.create(&path.join("1.log"))?.write_all(log)?;
+
+    let reader = KvStore::open_read_only_with_file_system(path, fs.clone())?;
+    read_all(&reader)?;
+    let mut options = KvStoreOptions::default()
+        .merge_operator(conformance::append)
+        .keep_versions(4)
+        .file_system(fs);
+    if let Some(budget) = budget {
+        options = options.index_memory_budget(budget);
+    }
+    let store = KvStore::open_with_options(path, options.clone())?;
+    read_all(&store)?;
+    store.compact()?;
+    drop(store);
+    read_all(&KvStore::open_with_options(path, options)?)
+}
+
+fn read_all(store: &KvStore) -> Result<()> {
+    for (key, _) in store.scan(..)? {
+        store.get(key.clone())?;
+        store.get_versions(key)?;
+    }
+    for change in store.changes_since(0)? {
+        change?;
+    }
+    Ok(())
+}
+
+/// Reads the value and the versions of a key whose latest command is at
+/// `pos` in the log file holding `log`.
+pub fn read_record(log: &[u8], pos: u64, len: u64) {
+    let _ = crate::engines::read_record(log, pos, len);
+}
+
+/// Answers the requests of `input` as a server does, with a store held in
+/// memory.
+pub fn serve_requests(input: &[u8]) {
+    let options = KvStoreOptions::default().file_system(MemoryFileSystem::new());
+    if let Ok(store) = KvStore::open_with_options("store", options) {
+        let _ = crate::server::handle_requests(store, input, io::sink(), "fuzzer");
+    }
+}
+
+/// Decodes `input` as every kind of response a client reads.
+pub fn decode_responses(input: &[u8]) {
+    decode::<GetResponse>(input);
+    decode::<VersionsResponse>(input);
+    decode::<SetResponse>(input);
+    decode::<RemoveResponse>(input);
+    decode::<IncrResponse>(input);
+    decode::<TransactionResponse>(input);
+    decode::<WatchResponse>(input);
+    decode::<ChangesResponse>(input);
+    decode::<IndexResponse>(input);
+    decode::<ListIndexesResponse>(input);
+    decode::<IndexScanResponse>(input);
+    decode::<KeyspaceResponse>(input);
+    decode::<ListKeyspacesResponse>(input);
+}
+
+/// Decodes the responses of type `T` in `input` one after another, as the
+/// client reads them off a connection.
+fn decode<T: DeserializeOwned>(input: &[u8]) {
+    let (reader, limit) = LimitedReader::new(input);
+    let mut reader = Deserializer::from_reader(reader);
+    loop {
+        limit.reset();
+        if T::deserialize(&mut reader).is_err() {
+            return;
+        }
+    }
+}
@@ -1,12 +1,29 @@
+mod bloom;
 mod error;
 mod client;
 mod common;
+pub mod conformance;
 mod server;
 mod engines;
-mod thread_pool;
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzzing;
+mod rate_limiter;
+pub mod thread_pool;
+mod vfs;
 
 pub use error::{KvsError, Result};
-pub use kv::KvStore;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    BloomStats, Change, ChangeKind, Changes, GenerationStats, IndexDefinition, KeyVersion,
+    KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, LsmOptions, MemoryKvsEngine, MergeOperator,
+    ShardedEngine, SledKvsEngine, StallStats, Transaction, VersionSpec, WatchEvent, Watcher,
+};
+// checked by the loom tests
+#[cfg(loom)]
+#[doc(hidden)]
+pub use engines::LiveGens;
 pub use client::KvsClient;
 pub use server::KvsServer;
+pub use rate_limiter::{RateLimited, RateLimiter, RateLimiterStats};
+pub use thread_pool::{NaiveThreadPool, RayonThreadPool, ShareQueueThreadPool, ThreadPool};
+pub use vfs::{DiskFileSystem, FileSystem, MemoryFileSystem, VfsFile};
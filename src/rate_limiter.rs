@@ -0,0 +1,182 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket that limits how many bytes per second background work
+/// such as compaction may read or write.
+///
+/// Clones share the same bucket, so the rate can be adjusted at runtime from
+/// any handle. A rate of `0` disables throttling.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, RateLimiter, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let limiter = RateLimiter::new(16 * 1024 * 1024);
+/// let options = KvStoreOptions::default().rate_limiter(limiter.clone());
+/// let store = KvStore::open_with_options(current_dir()?, options)?;
+/// // later, when clients are idle
+/// limiter.set_rate(0);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+    stats: Arc<Stats>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    // may go negative when a request is larger than what is available,
+    // the caller then sleeps until the debt is paid back
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    bytes: AtomicU64,
+    throttles: AtomicU64,
+    throttled_nanos: AtomicU64,
+}
+
+/// Counters of a `RateLimiter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimiterStats {
+    /// Bytes that went through the limiter.
+    pub bytes: u64,
+    /// How many requests had to wait for tokens.
+    pub throttles: u64,
+    /// Total time requests spent waiting for tokens.
+    pub throttled: Duration,
+}
+
+impl RateLimiter {
+    /// Creates a limiter that allows `bytes_per_sec` bytes per second.
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate: bytes_per_sec,
+                tokens: bytes_per_sec as f64,
+                refilled_at: Instant::now(),
+            })),
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    /// Creates a limiter that never throttles.
+    pub fn unlimited() -> RateLimiter {
+        RateLimiter::new(0)
+    }
+
+    /// Returns the current rate in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Changes the rate in bytes per second. `0` disables throttling.
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.rate = bytes_per_sec;
+        bucket.tokens = bucket.tokens.min(bytes_per_sec as f64);
+    }
+
+    /// Takes `bytes` tokens from the bucket, blocking until the bucket can
+    /// afford them.
+    pub fn request(&self, bytes: u64) {
+        self.stats.bytes.fetch_add(bytes, Ordering::Relaxed);
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            if bucket.rate == 0 {
+                return;
+            }
+            bucket.refill();
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64)
+        };
+        self.stats.throttles.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .throttled_nanos
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+        thread::sleep(wait);
+    }
+
+    /// Returns the counters of this limiter and all its clones.
+    pub fn stats(&self) -> RateLimiterStats {
+        RateLimiterStats {
+            bytes: self.stats.bytes.load(Ordering::Relaxed),
+            throttles: self.stats.throttles.load(Ordering::Relaxed),
+            throttled: Duration::from_nanos(self.stats.throttled_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::unlimited()
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.refilled_at = now;
+        // allow at most one second of burst
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+/// Wraps a reader or writer so that every byte moved through it is paid for
+/// with tokens of a `RateLimiter`.
+pub struct RateLimited<T> {
+    inner: T,
+    limiter: RateLimiter,
+}
+
+impl<T> RateLimited<T> {
+    /// Wraps `inner` with `limiter`.
+    pub fn new(inner: T, limiter: RateLimiter) -> RateLimited<T> {
+        RateLimited { inner, limiter }
+    }
+
+    /// Unwraps the inner reader or writer.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for RateLimited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.limiter.request(len as u64);
+        Ok(len)
+    }
+}
+
+impl<W: Write> Write for RateLimited<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // a short write only pays for the bytes it took
+        let len = self.inner.write(buf)?;
+        self.limiter.request(len as u64);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Seek> Seek for RateLimited<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
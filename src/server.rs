@@ -1,11 +1,22 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{Result, KvsError, KvsEngine};
+use crate::common::{
+    ChangesResponse, GetResponse, IncrResponse, IndexResponse, IndexScanResponse, KeyspaceResponse,
+    LimitedReader, ListIndexesResponse, ListKeyspacesResponse, MessageLimit, RemoveResponse,
+    Request, SetResponse, TransactionResponse, VersionsResponse, WatchResponse,
+};
+use crate::{KvsEngine, KvsError, Result, Transaction, Watcher};
 use log::{debug, error};
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
+use std::fmt::Display;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
 use crate::thread_pool::ThreadPool;
 
+// how often a watching connection checks whether the client hung up
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
@@ -18,7 +29,7 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
         KvsServer { engine, pool }
     }
 
-    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
 
         for stream in listener.incoming() {
@@ -29,49 +40,430 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
                         error!("Error on serving client: {}", e);
                     }
                 }
-                Err(e) => error!("Connection failed: {}", e);
+                Err(e) => error!("Connection failed: {}", e),
             });
         }
         Ok(())
     }
+}
+
+
+fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    let mut session = Session::new(engine, tcp.try_clone()?, tcp.try_clone()?, peer_addr, true);
+    match session.run()? {
+        None => {}
+        // a watch or a transaction may last long, so it leaves the pool to
+        // other connections
+        Some(Handoff::Watch(watcher)) => {
+            thread::spawn(move || {
+                if let Err(e) = push_events(tcp, watcher) {
+                    error!("Error on watching for {}: {}", peer_addr, e);
+                }
+            });
+        }
+        Some(Handoff::Transaction) => {
+            thread::spawn(move || {
+                let res = session.run().and_then(|handoff| match handoff {
+                    Some(Handoff::Watch(watcher)) => push_events(tcp, watcher),
+                    _ => Ok(()),
+                });
+                if let Err(e) = res {
+                    error!("Error on serving client: {}", e);
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Answers the requests read from `reader` on the calling thread until it
+/// ends, or until a watch request takes over the connection. Then it returns
+/// the watcher, whose events are to be pushed to the client.
+#[cfg(fuzzing)]
+pub(crate) fn handle_requests<E, R, W>(
+    engine: E,
+    reader: R,
+    writer: W,
+    peer_addr: impl Display,
+) -> Result<Option<Watcher>>
+where
+    E: KvsEngine,
+    R: Read,
+    W: Write,
+{
+    match Session::new(engine, reader, writer, peer_addr, false).run()? {
+        Some(Handoff::Watch(watcher)) => Ok(Some(watcher)),
+        _ => Ok(None),
+    }
+}
+
+/// What a session stops for, leaving the rest of the connection to its
+/// caller.
+enum Handoff {
+    /// A watch took over the connection.
+    Watch(Watcher),
+    /// A transaction began on a session that runs on the thread pool.
+    Transaction,
+}
 
+/// The requests of a connection, answered one by one.
+struct Session<E, R: Read, W: Write> {
+    engine: E,
+    requests: StreamDeserializer<'static, IoRead<LimitedReader<BufReader<R>>>, Request>,
+    limit: MessageLimit,
+    writer: BufWriter<W>,
+    peer_addr: String,
+    // whether the session runs on the thread pool, which it leaves when a
+    // transaction begins
+    pooled: bool,
+    // the transaction of the connection, which works on the keyspace it
+    // began in
+    txn: Option<Transaction>,
+    txn_keyspace: Option<String>,
+}
 
-    fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
-        let peer_addr = tcp.peer_addr()?;
-        let reader = BufReader::new(&tcp);
-        let mut writer = BufWriter::new(&tcp);
-        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+impl<E, R, W> Session<E, R, W>
+where
+    E: KvsEngine,
+    R: Read,
+    W: Write,
+{
+    fn new(engine: E, reader: R, writer: W, peer_addr: impl Display, pooled: bool) -> Self {
+        let (reader, limit) = LimitedReader::new(BufReader::new(reader));
+        Session {
+            engine,
+            requests: Deserializer::from_reader(reader).into_iter::<Request>(),
+            limit,
+            writer: BufWriter::new(writer),
+            peer_addr: peer_addr.to_string(),
+            pooled,
+            txn: None,
+            txn_keyspace: None,
+        }
+    }
+
+    /// Answers requests until they end or until one hands the connection
+    /// off. A session handed off for a transaction goes on when run again.
+    fn run(&mut self) -> Result<Option<Handoff>> {
+        let Session {
+            engine,
+            requests,
+            limit,
+            writer,
+            peer_addr,
+            pooled,
+            txn,
+            txn_keyspace,
+        } = self;
 
         macro_rules ! send_resp {
             ($resp:expr) => {{
               let resp = $resp;
-              serde_json::to_writer(&mut writer, &resp)?;
+              serde_json::to_writer(&mut *writer, &resp)?;
               writer.flush()?;
               debug!("Response sent to {}: {:?}",peer_addr, resp);
-            };};
+            }};
         }
 
-
-        for req in req_reader {
+        for req in requests {
             let req = req?;
+            limit.reset();
             debug!("Receive request from {}: {:?}", peer_addr, req);
             match req {
-                Request::Get { key} => send_resp!(match engine.get(key) {
+                Request::Get { key, keyspace } if txn.is_some() => {
+                    let res = check_txn_keyspace(&keyspace, txn_keyspace)
+                        .and_then(|()| txn.as_mut().unwrap().get(key));
+                    send_resp!(match res {
+                        Ok(value) => GetResponse::Ok(value),
+                        Err(KvsError::TransactionConflict(msg)) => GetResponse::Conflict(msg),
+                        Err(e) => GetResponse::Err(format!("{}", e)),
+                    })
+                }
+                Request::Set { key, value, keyspace } if txn.is_some() => {
+                    send_resp!(match check_txn_keyspace(&keyspace, txn_keyspace) {
+                        Ok(()) => {
+                            txn.as_mut().unwrap().set(key, value);
+                            SetResponse::Ok(())
+                        }
+                        Err(e) => SetResponse::Err(format!("{}", e)),
+                    })
+                }
+                Request::Remove { key, keyspace } if txn.is_some() => {
+                    let res = check_txn_keyspace(&keyspace, txn_keyspace)
+                        .and_then(|()| txn.as_mut().unwrap().remove(key));
+                    send_resp!(match res {
+                        Ok(_) => RemoveResponse::Ok(()),
+                        Err(KvsError::TransactionConflict(msg)) => RemoveResponse::Conflict(msg),
+                        Err(e) => RemoveResponse::Err(format!("{}", e)),
+                    })
+                }
+                // the rest would bypass the transaction, so they wait for it
+                // to end
+                req if txn.is_some()
+                    && !matches!(req, Request::Begin { .. } | Request::Commit | Request::Rollback) =>
+                {
+                    let msg = format!(
+                        "{}",
+                        KvsError::Unsupported("this request inside a transaction".to_owned())
+                    );
+                    match req {
+                        Request::RemoveRange { .. } | Request::RemovePrefix { .. } => {
+                            send_resp!(RemoveResponse::Err(msg))
+                        }
+                        Request::Incr { .. } | Request::Decr { .. } => send_resp!(IncrResponse::Err(msg)),
+                        Request::GetVersions { .. } => send_resp!(VersionsResponse::Err(msg)),
+                        Request::GetAt { .. } => send_resp!(GetResponse::Err(msg)),
+                        Request::Watch { .. } => send_resp!(WatchResponse::Err(msg)),
+                        Request::Changes { .. } => send_resp!(ChangesResponse::Err(msg)),
+                        Request::CreateIndex { .. } | Request::DropIndex { .. } => {
+                            send_resp!(IndexResponse::Err(msg))
+                        }
+                        Request::ListIndexes { .. } => send_resp!(ListIndexesResponse::Err(msg)),
+                        Request::IndexScan { .. } => send_resp!(IndexScanResponse::Err(msg)),
+                        Request::CreateKeyspace { .. } | Request::DropKeyspace { .. } => {
+                            send_resp!(KeyspaceResponse::Err(msg))
+                        }
+                        Request::ListKeyspaces => send_resp!(ListKeyspacesResponse::Err(msg)),
+                        Request::Get { .. }
+                        | Request::Set { .. }
+                        | Request::Remove { .. }
+                        | Request::Begin { .. }
+                        | Request::Commit
+                        | Request::Rollback => unreachable!(),
+                    }
+                }
+                Request::Get { key, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.get(key),
+                ) {
                    Ok(value) => GetResponse::Ok(value),
                     Err(e) => GetResponse::Err(format!("{}", e)),
                 }),
-                Request::Set {key, value} => send_resp!(match engine.set(key, value) {
+                Request::Set { key, value, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.set(key, value),
+                ) {
                    Ok(_) => SetResponse::Ok(()),
                    Err(e) => SetResponse::Err(format!("{}", e)),
                 }),
-                Request::Remove {key} => send_resp!(match engine.remove(key) {
+                Request::Remove { key, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.remove(key),
+                ) {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(e) => RemoveResponse::Err(format!("{}", e)),
+                }),
+                Request::Begin { keyspace } => send_resp!(if txn.is_some() {
+                    TransactionResponse::Err("a transaction is open already".to_owned())
+                } else {
+                    match with_keyspace(engine, keyspace.clone(), |engine| engine.begin()) {
+                        Ok(begun) => {
+                            *txn = Some(begun);
+                            *txn_keyspace = keyspace;
+                            TransactionResponse::Ok(())
+                        }
+                        Err(e) => TransactionResponse::Err(format!("{}", e)),
+                    }
+                }),
+                Request::Commit => send_resp!(match txn.take().map(Transaction::commit) {
+                    Some(Ok(_)) => TransactionResponse::Ok(()),
+                    Some(Err(KvsError::TransactionConflict(msg))) => TransactionResponse::Conflict(msg),
+                    Some(Err(e)) => TransactionResponse::Err(format!("{}", e)),
+                    None => TransactionResponse::Err("no transaction is open".to_owned()),
+                }),
+                Request::Rollback => send_resp!(match txn.take() {
+                    Some(_) => TransactionResponse::Ok(()),
+                    None => TransactionResponse::Err("no transaction is open".to_owned()),
+                }),
+                Request::Watch { prefix, keyspace } => {
+                    match with_keyspace(engine, keyspace, |engine| engine.watch_prefix(&prefix)) {
+                        Ok(watcher) => {
+                            send_resp!(WatchResponse::Ok(()));
+                            return Ok(Some(Handoff::Watch(watcher)));
+                        }
+                        Err(e) => send_resp!(WatchResponse::Err(format!("{}", e))),
+                    }
+                }
+                Request::Changes { since, keyspace } => {
+                    match with_keyspace(engine, keyspace, |engine| engine.changes_since(since)) {
+                        Ok(changes) => {
+                            let mut sent = 0;
+                            let end = changes
+                                .map(|change| -> Result<()> {
+                                    serde_json::to_writer(&mut *writer, &ChangesResponse::Change(change?))?;
+                                    sent += 1;
+                                    Ok(())
+                                })
+                                .find(Result::is_err)
+                                .map_or(ChangesResponse::End, |e| {
+                                    ChangesResponse::Err(format!("{}", e.unwrap_err()))
+                                });
+                            debug!("Sent {} changes to {}", sent, peer_addr);
+                            send_resp!(end)
+                        }
+                        Err(KvsError::HistoryCompacted(msg)) => send_resp!(ChangesResponse::Compacted(msg)),
+                        Err(e) => send_resp!(ChangesResponse::Err(format!("{}", e))),
+                    }
+                }
+                Request::RemoveRange { start, end, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.delete_range(start..end),
+                ) {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(e) => RemoveResponse::Err(format!("{}", e)),
+                }),
+                Request::RemovePrefix { prefix, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.delete_prefix(&prefix),
+                ) {
                     Ok(_) => RemoveResponse::Ok(()),
                     Err(e) => RemoveResponse::Err(format!("{}", e)),
                 }),
+                Request::Incr { key, delta, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.incr_by(key, delta),
+                ) {
+                    Ok(value) => IncrResponse::Ok(value),
+                    Err(e) => IncrResponse::Err(format!("{}", e)),
+                }),
+                Request::Decr { key, delta, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| {
+                        let delta = delta.checked_neg().ok_or_else(|| {
+                            KvsError::IntegerOverflow(format!("cannot decrement by {}", delta))
+                        })?;
+                        engine.incr_by(key, delta)
+                    },
+                ) {
+                    Ok(value) => IncrResponse::Ok(value),
+                    Err(e) => IncrResponse::Err(format!("{}", e)),
+                }),
+                Request::GetVersions { key, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.get_versions(key),
+                ) {
+                    Ok(versions) => VersionsResponse::Ok(versions),
+                    Err(e) => VersionsResponse::Err(format!("{}", e)),
+                }),
+                Request::GetAt { key, at, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.get_at(key, at),
+                ) {
+                    Ok(value) => GetResponse::Ok(value),
+                    Err(e) => GetResponse::Err(format!("{}", e)),
+                }),
+                Request::CreateIndex { name, pointer, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.create_index(&name, &pointer),
+                ) {
+                    Ok(_) => IndexResponse::Ok(()),
+                    Err(e) => IndexResponse::Err(format!("{}", e)),
+                }),
+                Request::DropIndex { name, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.drop_index(&name),
+                ) {
+                    Ok(_) => IndexResponse::Ok(()),
+                    Err(e) => IndexResponse::Err(format!("{}", e)),
+                }),
+                Request::ListIndexes { keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.indexes(),
+                ) {
+                    Ok(indexes) => ListIndexesResponse::Ok(indexes),
+                    Err(e) => ListIndexesResponse::Err(format!("{}", e)),
+                }),
+                Request::IndexScan { name, start, end, keyspace } => send_resp!(match with_keyspace(
+                    engine,
+                    keyspace,
+                    |engine| engine.index_scan(&name, (start, end)),
+                ) {
+                    Ok(pairs) => IndexScanResponse::Ok(pairs),
+                    Err(e) => IndexScanResponse::Err(format!("{}", e)),
+                }),
+                Request::CreateKeyspace { name } => send_resp!(match engine.create_keyspace(&name) {
+                    Ok(_) => KeyspaceResponse::Ok(()),
+                    Err(e) => KeyspaceResponse::Err(format!("{}", e)),
+                }),
+                Request::DropKeyspace { name } => send_resp!(match engine.drop_keyspace(&name) {
+                    Ok(_) => KeyspaceResponse::Ok(()),
+                    Err(e) => KeyspaceResponse::Err(format!("{}", e)),
+                }),
+                Request::ListKeyspaces => send_resp!(match engine.keyspaces() {
+                    Ok(names) => ListKeyspacesResponse::Ok(names),
+                    Err(e) => ListKeyspacesResponse::Err(format!("{}", e)),
+                }),
             };
+            if *pooled && txn.is_some() {
+                *pooled = false;
+                return Ok(Some(Handoff::Transaction));
+            }
         }
-        Ok(())
+        Ok(None)
     }
+}
 
+/// Sends the events of `watcher` to the client until it hangs up.
+fn push_events(tcp: TcpStream, mut watcher: Watcher) -> Result<()> {
+    let mut writer = BufWriter::new(&tcp);
+    loop {
+        match watcher.next_timeout(WATCH_POLL_INTERVAL)? {
+            Some(event) => {
+                serde_json::to_writer(&mut writer, &WatchResponse::Event(event))?;
+                writer.flush()?;
+            }
+            None if hung_up(&tcp)? => return Ok(()),
+            None => {}
+        }
+    }
 }
 
+/// Returns whether the peer of `tcp` has closed the connection, without
+/// blocking.
+fn hung_up(tcp: &TcpStream) -> Result<bool> {
+    tcp.set_nonblocking(true)?;
+    let peeked = tcp.peek(&mut [0]);
+    tcp.set_nonblocking(false)?;
+    match peeked {
+        Ok(0) => Ok(true),
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Fails for a request to `keyspace` within a transaction that began in
+/// `txn_keyspace`.
+fn check_txn_keyspace(keyspace: &Option<String>, txn_keyspace: &Option<String>) -> Result<()> {
+    if keyspace == txn_keyspace {
+        Ok(())
+    } else {
+        Err(KvsError::Unsupported("transactions across keyspaces".to_owned()))
+    }
+}
+
+/// Runs `f` on the keyspace `keyspace` of `engine`, or on `engine` itself if
+/// it is `None`.
+fn with_keyspace<E, T, F>(engine: &E, keyspace: Option<String>, f: F) -> Result<T>
+where
+    E: KvsEngine,
+    F: FnOnce(&E) -> Result<T>,
+{
+    match keyspace {
+        Some(name) => f(&engine.keyspace(&name)?),
+        None => f(engine),
+    }
+}
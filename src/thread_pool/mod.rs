@@ -4,6 +4,10 @@ mod naive;
 mod rayon;
 mod shared_queue;
 
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::ShareQueueThreadPool;
+
 
 pub trait ThreadPool {
     fn new(threads: u32) -> Result<Self>
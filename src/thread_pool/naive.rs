@@ -6,11 +6,14 @@ use crate::Result;
 pub struct NaiveThreadPool;
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(threads: u32) -> Result<Self> {
+    fn new(_threads: u32) -> Result<Self> {
         Ok(NaiveThreadPool)
     }
 
-    fn spawn<F>(&self, job: F) {
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
         thread::spawn(job);
     }
 }
\ No newline at end of file
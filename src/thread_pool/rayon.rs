@@ -12,7 +12,10 @@ impl ThreadPool for RayonThreadPool {
         Ok(RayonThreadPool(pool))
     }
 
-    fn spawn<F>(&self, job: F) {
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
         self.0.spawn(job);
     }
 }
\ No newline at end of file
@@ -15,7 +15,7 @@ pub struct ShareQueueThreadPool {
 impl ThreadPool for ShareQueueThreadPool {
     fn new(threads: u32) -> Result<Self> {
         let (tx, rx) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
-        for i in 0..threads {
+        for _ in 0..threads {
             let rx = TaskReceiver(rx.clone());
             thread::Builder::new().spawn(move || run_tasks(rx))?;
         }
@@ -24,7 +24,9 @@ impl ThreadPool for ShareQueueThreadPool {
 
     fn spawn<F>(&self, job: F) where
         F: FnOnce() + Send + 'static {
-        todo!()
+        self.tx
+            .send(Box::new(job))
+            .expect("The thread pool has no thread.");
     }
 }
 
@@ -49,7 +51,10 @@ fn run_tasks(rx: TaskReceiver) {
             Ok(task) => {
                 task();
             }
-            Err(_) => debug!("Thread exists because the thread pool is destroyed."),
+            Err(_) => {
+                debug!("Thread exits because the thread pool is destroyed.");
+                return;
+            }
         }
     }
 
@@ -0,0 +1,533 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The files a `KvStore` keeps its data in.
+///
+/// `DiskFileSystem` is used unless the options name another one, such as a
+/// `MemoryFileSystem` in tests.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, MemoryFileSystem, Result};
+/// # fn try_main() -> Result<()> {
+/// use kvs::{FileSystem, KvsEngine};
+/// let fs = MemoryFileSystem::new();
+/// let options = KvStoreOptions::default().file_system(fs.clone());
+/// let store = KvStore::open_with_options("/db", options)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// assert!(fs.list("/db".as_ref())?.len() > 0);
+/// # Ok(())
+/// # }
+/// ```
+pub trait FileSystem: Send + Sync + 'static {
+    /// Creates the file at `path` to write, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Creates the file at `path` to write, failing if it exists.
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Opens the file at `path` to append to, creating it if it is missing.
+    fn append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Opens the existing file at `path` to read.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Renames the file at `from` to `to`, replacing any file there.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates the directory at `path` and all its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Removes the directory at `path` with everything in it.
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Makes the files created, renamed or removed in the directory at
+    /// `path` so far durable, as syncing a file does not.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Returns the paths of the files and directories in the directory at
+    /// `path`, in no particular order.
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Returns how many bytes may still be written below `path`.
+    fn available_space(&self, path: &Path) -> io::Result<u64>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.is_file(path) || self.is_dir(path)
+    }
+
+    /// Reads the whole file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.open(path)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl fmt::Debug for dyn FileSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("FileSystem")
+    }
+}
+
+/// A file opened by a `FileSystem`.
+pub trait VfsFile: Read + Write + Seek + Send {
+    /// Reads from `offset` into `buf`, returning how many bytes were read.
+    ///
+    /// The files of `MemoryFileSystem`, and of `DiskFileSystem` on Unix,
+    /// read without moving their position. Otherwise this seeks first, and
+    /// leaves the position after the bytes read.
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read(buf)
+    }
+
+    /// Makes the bytes written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Cuts or extends the file to `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+/// The file system of the local disk.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskFileSystem;
+
+impl FileSystem for DiskFileSystem {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new().create_new(true).append(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    // only Unix opens directories as files
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<u64> {
+        fs2::available_space(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+}
+
+impl VfsFile for File {
+    #[cfg(unix)]
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+/// A file system held in memory, gone once the last clone is dropped.
+///
+/// Clones share the same files, so a store can be reopened on a clone.
+/// Removed files stay readable through the handles opened before.
+///
+/// It keeps track of what has been synced, so that `crash` can simulate a
+/// power loss.
+#[derive(Clone, Default)]
+pub struct MemoryFileSystem {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    dirs: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, Arc<Mutex<MemoryData>>>,
+    // the files as of the last sync of their directory
+    durable: BTreeMap<PathBuf, Arc<Mutex<MemoryData>>>,
+}
+
+/// The content of a file of a `MemoryFileSystem`.
+#[derive(Default)]
+struct MemoryData {
+    bytes: Vec<u8>,
+    // the bytes before it have been synced
+    synced_len: usize,
+    // the synced content, once a write or truncation has changed it
+    synced: Option<Vec<u8>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem::default()
+    }
+
+    /// Simulates a power loss. Every file is left with the content it had
+    /// when last synced, followed by the first `keep(path, unsynced)` bytes
+    /// of the `unsynced` ones appended since.
+    ///
+    /// The files are found under the names they had when their directory
+    /// was last synced: files created since are gone, and files renamed or
+    /// removed since are back. Directories are kept as they are. Handles
+    /// opened before the crash no longer reach the files.
+    pub fn crash(&self, mut keep: impl FnMut(&Path, u64) -> u64) {
+        let mut state = self.state.lock().unwrap();
+        state.files = state.durable.clone();
+        for (path, data) in state.files.iter_mut() {
+            let old = data.lock().unwrap();
+            let bytes = match &old.synced {
+                Some(synced) => synced.clone(),
+                None => {
+                    let unsynced = (old.bytes.len() - old.synced_len) as u64;
+                    let kept = keep(path, unsynced).min(unsynced) as usize;
+                    old.bytes[..old.synced_len + kept].to_vec()
+                }
+            };
+            let new = MemoryData {
+                synced_len: bytes.len(),
+                bytes,
+                synced: None,
+            };
+            drop(old);
+            *data = Arc::new(Mutex::new(new));
+        }
+        state.durable = state.files.clone();
+    }
+
+    /// Opens the file at `path` in `mode`, creating it if `create` allows.
+    fn open_file(&self, path: &Path, mode: Mode, create: Create) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.state.lock().unwrap();
+        if state.dirs.contains(path) {
+            return Err(io::Error::other("is a directory"));
+        }
+        let data = match (state.files.get(path), create) {
+            (Some(_), Create::New) => return Err(io::ErrorKind::AlreadyExists.into()),
+            (Some(data), Create::Truncate) => {
+                data.lock().unwrap().set_len(0);
+                Arc::clone(data)
+            }
+            (Some(data), _) => Arc::clone(data),
+            (None, Create::Never) => return Err(not_found(path)),
+            (None, _) => {
+                state.check_parent(path)?;
+                let data = Arc::new(Mutex::new(MemoryData::default()));
+                state.files.insert(path.to_owned(), Arc::clone(&data));
+                data
+            }
+        };
+        Ok(Box::new(MemoryFile { data, pos: 0, mode }))
+    }
+}
+
+impl MemoryData {
+    /// Keeps a copy of the synced content before it is changed from `pos`
+    /// on.
+    fn preserve_synced(&mut self, pos: usize) {
+        if pos < self.synced_len && self.synced.is_none() {
+            self.synced = Some(self.bytes[..self.synced_len].to_vec());
+        }
+    }
+
+    fn write_at(&mut self, pos: usize, buf: &[u8]) {
+        self.preserve_synced(pos);
+        let end = pos + buf.len();
+        if self.bytes.len() < end {
+            self.bytes.resize(end, 0);
+        }
+        self.bytes[pos..end].copy_from_slice(buf);
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.preserve_synced(len);
+        self.bytes.resize(len, 0);
+    }
+
+    fn sync(&mut self) {
+        self.synced_len = self.bytes.len();
+        self.synced = None;
+    }
+}
+
+impl MemoryState {
+    fn is_dir(&self, path: &Path) -> bool {
+        // the current directory of relative paths always exists
+        path.as_os_str().is_empty() || self.dirs.contains(path)
+    }
+
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !self.is_dir(parent) => Err(not_found(parent)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Read,
+    Write,
+    Append,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Create {
+    Never,
+    IfMissing,
+    New,
+    Truncate,
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.open_file(path, Mode::Write, Create::Truncate)
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.open_file(path, Mode::Append, Create::New)
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.open_file(path, Mode::Append, Create::IfMissing)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.open_file(path, Mode::Read, Create::Never)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.files.contains_key(from) {
+            return Err(not_found(from));
+        }
+        state.check_parent(to)?;
+        if state.dirs.contains(to) {
+            return Err(io::Error::other("is a directory"));
+        }
+        let data = state.files.remove(from).expect("file vanished");
+        state.files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.state.lock().unwrap().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(file) = path.ancestors().find(|path| state.files.contains_key(*path)) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is a file", file.display()),
+            ));
+        }
+        for dir in path.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+            state.dirs.insert(dir.to_owned());
+        }
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        state.dirs.retain(|dir| !dir.starts_with(path));
+        state.files.retain(|file, _| !file.starts_with(path));
+        state.durable.retain(|file, _| !file.starts_with(path));
+        Ok(())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.is_dir(path) {
+            return Err(not_found(path));
+        }
+        let MemoryState { files, durable, .. } = &mut *state;
+        durable.retain(|file, _| file.parent() != Some(path));
+        for (file, data) in files.iter().filter(|(file, _)| file.parent() == Some(path)) {
+            durable.insert(file.clone(), Arc::clone(data));
+        }
+        Ok(())
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        if !state.is_dir(path) {
+            return Err(not_found(path));
+        }
+        Ok(state
+            .dirs
+            .iter()
+            .chain(state.files.keys())
+            .filter(|entry| entry.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.state.lock().unwrap().files.contains_key(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.state.lock().unwrap().is_dir(path)
+    }
+
+    fn available_space(&self, _path: &Path) -> io::Result<u64> {
+        Ok(u64::MAX)
+    }
+}
+
+/// A handle of a file of a `MemoryFileSystem`.
+struct MemoryFile {
+    data: Arc<Mutex<MemoryData>>,
+    pos: u64,
+    mode: Mode,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.mode != Mode::Read {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "opened to write"));
+        }
+        let data = &self.data.lock().unwrap().bytes;
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.mode == Mode::Read {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "opened to read"));
+        }
+        let mut data = self.data.lock().unwrap();
+        if self.mode == Mode::Append {
+            self.pos = data.bytes.len() as u64;
+        }
+        data.write_at(self.pos as usize, buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.data.lock().unwrap().bytes.len() as u64, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        match base.checked_add_signed(offset) {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            )),
+        }
+    }
+}
+
+impl VfsFile for MemoryFile {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let pos = self.pos;
+        self.pos = offset;
+        let read = self.read(buf);
+        self.pos = pos;
+        read
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.data.lock().unwrap().sync();
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if self.mode == Mode::Read {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "opened to read"));
+        }
+        self.data.lock().unwrap().set_len(len as usize);
+        Ok(())
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
@@ -1,7 +1,10 @@
 use assert_cmd::prelude::*;
-use predicates::str::{contains, is_empty};
+use kvs::KvsClient;
+use predicates::prelude::*;
+use predicates::str::{contains, ends_with, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -20,28 +23,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +55,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +94,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +126,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +137,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +148,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +160,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +182,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,39 +202,64 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
+// Options of another engine should be refused rather than ignored.
+#[test]
+fn cli_engine_options() {
+    let temp_dir = TempDir::new().unwrap();
+    let cases: [&[&str]; 4] = [
+        &["--engine", "sled", "--compaction-rate", "1024"],
+        &["--engine", "lsm", "--keep-versions", "3"],
+        &["--engine", "kvs", "--snapshot", "data.json"],
+        &["--max-bytes", "1024"],
+    ];
+    for args in cases {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .args(["--addr", "127.0.0.1:4018"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("only applies to the"));
+    }
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +267,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +275,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +283,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +291,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +299,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +307,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +315,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +328,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +366,566 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4007");
+}
+
+#[test]
+fn cli_access_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key2", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    // what the engine lacks is refused, not faked
+    let unsupported: [&[&str]; 3] = [
+        &["versions", "key1"],
+        &["create-keyspace", "users"],
+        &["get", "key1", "--keyspace", "users"],
+    ];
+    for args in unsupported {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", "127.0.0.1:4006"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("Not supported"));
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["txn", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("get key1\n")
+        .assert()
+        .failure()
+        .stderr(contains("Not supported"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+}
+
+// The memory engine should keep its data in the snapshot file across a
+// terminated server, and refuse to grow beyond its size limit.
+#[test]
+#[cfg(unix)]
+fn cli_memory_snapshot() {
+    let temp_dir = TempDir::new().unwrap();
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client.args(args).args(["--addr", "127.0.0.1:4017"]).current_dir(&temp_dir);
+        client
+    };
+    let start = || {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "memory", "--addr", "127.0.0.1:4017"])
+            .args(["--snapshot", "data.json", "--max-bytes", "20"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+
+    let mut child = start();
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key2", "a value too long"])
+        .assert()
+        .failure()
+        .stderr(contains("Capacity exceeded"));
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().expect("server did not exit").success());
+    assert!(temp_dir.path().join("data.json").exists());
+
+    let mut child = start();
+    client(&["get", "key1"]).assert().success().stdout("value1\n");
+    client(&["get", "key2"]).assert().success().stdout("Key not found\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+}
+
+#[test]
+fn cli_shards() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--shards", "4", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    child.wait().unwrap();
+    assert!(temp_dir.path().join("shard-3").is_dir());
+
+    // The keys would be looked up in the wrong shards
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // An unsharded store is not split into shards
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--shards", "4", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("shards").exists());
+}
+
+#[test]
+fn cli_keyspaces() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4009"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set", "key1", "value1", "--keyspace", "users"])
+        .assert()
+        .failure();
+    client(&["create-keyspace", "users"]).assert().success();
+    client(&["create-keyspace", "orders"]).assert().success();
+    client(&["set", "key1", "value1", "--keyspace", "users"])
+        .assert()
+        .success();
+    client(&["set", "key1", "default"]).assert().success();
+    client(&["get", "key1", "--keyspace", "users"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["get", "key1", "--keyspace", "orders"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    client(&["get", "key1"]).assert().success().stdout("default\n");
+    client(&["keyspaces"])
+        .assert()
+        .success()
+        .stdout("orders\nusers\n");
+    client(&["drop-keyspace", "users"]).assert().success();
+    client(&["keyspaces"]).assert().success().stdout("orders\n");
+    client(&["rm", "key1", "--keyspace", "users"]).assert().failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_incr() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4010"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["incr", "hits"]).assert().success().stdout("1\n");
+    client(&["incr", "hits", "10"]).assert().success().stdout("11\n");
+    client(&["incr", "hits", "-20"]).assert().success().stdout("-9\n");
+    client(&["get", "hits"]).assert().success().stdout("-9\n");
+    client(&["set", "name", "kvs"]).assert().success();
+    client(&["incr", "name"]).assert().failure();
+    client(&["incr", "hits", "one"]).assert().failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_remove_range() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4011"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    for key in &["a", "b", "c", "tenant1/x", "tenant1/y", "tenant2/x"] {
+        client(&["set", key, "value"]).assert().success();
+    }
+    client(&["rm-range", "a", "c"]).assert().success();
+    client(&["rm-prefix", "tenant1/"]).assert().success();
+    for key in &["a", "b", "tenant1/x", "tenant1/y"] {
+        client(&["get", key]).assert().success().stdout("Key not found\n");
+    }
+    for key in &["c", "tenant2/x"] {
+        client(&["get", key]).assert().success().stdout("value\n");
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_versions() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4012", "--keep-versions", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4012"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set", "key", "v1"]).assert().success();
+    client(&["set", "key", "v2"]).assert().success();
+    client(&["get", "key"]).assert().success().stdout("v2\n");
+    client(&["get", "key", "--version", "1"]).assert().success().stdout("v1\n");
+    client(&["get", "key", "--version", "3"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    client(&["get", "key", "--at", "0"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    client(&["versions", "key"])
+        .assert()
+        .success()
+        .stdout(contains("2\t").and(contains("\tv2\n1\t")).and(ends_with("\tv1\n")));
+    client(&["get", "key", "--version", "1", "--at", "0"]).assert().failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_transaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4013"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4013"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set", "from", "10"]).assert().success();
+    client(&["txn"])
+        .with_stdin()
+        .buffer("get from\nget to\nset from 7\nset to 3\nget from\n")
+        .assert()
+        .success()
+        .stdout("10\nKey not found\n7\n");
+    client(&["get", "from"]).assert().success().stdout("7\n");
+    client(&["get", "to"]).assert().success().stdout("3\n");
+
+    client(&["txn"])
+        .with_stdin()
+        .buffer("rm to\nset from 0\nrollback\n")
+        .assert()
+        .success();
+    client(&["get", "from"]).assert().success().stdout("7\n");
+    client(&["get", "to"]).assert().success().stdout("3\n");
+
+    client(&["txn"])
+        .with_stdin()
+        .buffer("set from 1\nincr from\n")
+        .assert()
+        .failure();
+    client(&["txn"])
+        .with_stdin()
+        .buffer("rm missing\n")
+        .assert()
+        .failure();
+    client(&["get", "from"]).assert().success().stdout("7\n");
+
+    // a transaction works on the keyspace it began in
+    client(&["create-keyspace", "users"]).assert().success();
+    client(&["txn", "--keyspace", "users"])
+        .with_stdin()
+        .buffer("set from 1\nget from\n")
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(&["get", "from", "--keyspace", "users"]).assert().success().stdout("1\n");
+    let mut kvs = KvsClient::connect("127.0.0.1:4013").unwrap();
+    kvs.begin().unwrap();
+    kvs.set_keyspace(Some("users".to_owned()));
+    assert!(kvs.set("from".to_owned(), "2".to_owned()).is_err());
+    assert!(kvs.get("from".to_owned()).is_err());
+    kvs.set_keyspace(None);
+    kvs.set("from".to_owned(), "8".to_owned()).unwrap();
+    // requests the transaction cannot take are refused rather than run
+    // around it
+    assert!(kvs.incr("from".to_owned(), 1).is_err());
+    assert!(kvs.remove_range("a".to_owned(), "z".to_owned()).is_err());
+    assert!(kvs.create_keyspace("orders".to_owned()).is_err());
+    assert!(kvs.keyspaces().is_err());
+    // an open transaction does not keep other clients waiting
+    client(&["get", "from"]).assert().success().stdout("7\n");
+    kvs.commit().unwrap();
+    drop(kvs);
+    client(&["get", "from"]).assert().success().stdout("8\n");
+    client(&["get", "from", "--keyspace", "users"]).assert().success().stdout("1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4014"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4014"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    let mut watcher = client(&["watch", "user:"]).stdout(Stdio::piped()).spawn().unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "user:1", "alice"]).assert().success();
+    client(&["set", "other", "ignored"]).assert().success();
+    client(&["set", "user:1", "bob"]).assert().success();
+    client(&["rm", "user:1"]).assert().success();
+
+    let mut lines = BufReader::new(watcher.stdout.take().unwrap()).lines();
+    let mut next_line = || lines.next().unwrap().unwrap();
+    assert_eq!(next_line(), "1\tset\tuser:1\talice");
+    assert_eq!(next_line(), "3\tset\tuser:1\tbob");
+    assert_eq!(next_line(), "4\trm\tuser:1");
+    watcher.kill().expect("watcher exited before killed");
+    watcher.wait().unwrap();
+
+    client(&["watch", "user:", "--keyspace", "missing"])
+        .assert()
+        .failure()
+        .stderr(contains("Keyspace not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_changes() {
+    let temp_dir = TempDir::new().unwrap();
+    let start_server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", "127.0.0.1:4015"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4015"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    let mut child = start_server();
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "user:1", "alice"]).assert().success();
+    client(&["rm", "user:1"]).assert().success();
+    client(&["set", "a1", "x"]).assert().success();
+    client(&["rm-range", "a", "b"]).assert().success();
+    client(&["changes"])
+        .assert()
+        .success()
+        .stdout("1\tset\tuser:1\talice\n2\trm\tuser:1\n3\tset\ta1\tx\n4\trm-range\t[a\tb)\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    child.wait().unwrap();
+
+    // numbering goes on where the log left off
+    let mut child = start_server();
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "user:2", "bob"]).assert().success();
+    client(&["changes", "--since", "3"])
+        .assert()
+        .success()
+        .stdout("4\trm-range\t[a\tb)\n5\tset\tuser:2\tbob\n");
+    client(&["changes", "--keyspace", "missing"])
+        .assert()
+        .failure()
+        .stderr(contains("Keyspace not found"));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_secondary_indexes() {
+    let temp_dir = TempDir::new().unwrap();
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4016"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4016"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "u1", r#"{"city":"Paris","age":30}"#]).assert().success();
+    client(&["set", "u2", r#"{"city":"Oslo","age":25}"#]).assert().success();
+    client(&["create-index", "city", "/city"]).assert().success();
+    client(&["create-index", "age", "/age"]).assert().success();
+    client(&["create-index", "city", "/age"])
+        .assert()
+        .failure()
+        .stderr(contains("Index exists"));
+    client(&["indexes"]).assert().success().stdout("age\t/age\ncity\t/city\n");
+    client(&["index-get", "city", "Paris"])
+        .assert()
+        .success()
+        .stdout("u1\t{\"city\":\"Paris\",\"age\":30}\n");
+    client(&["set", "u3", r#"{"city":"Paris","age":40}"#]).assert().success();
+    client(&["index-range", "age", "--start", "26"])
+        .assert()
+        .success()
+        .stdout("u1\t{\"city\":\"Paris\",\"age\":30}\nu3\t{\"city\":\"Paris\",\"age\":40}\n");
+    client(&["index-range", "age", "--end", "30"])
+        .assert()
+        .success()
+        .stdout("u2\t{\"city\":\"Oslo\",\"age\":25}\n");
+    client(&["drop-index", "city"]).assert().success();
+    client(&["index-get", "city", "Paris"])
+        .assert()
+        .failure()
+        .stderr(contains("Index not found"));
+    client(&["indexes", "--keyspace", "missing"])
+        .assert()
+        .failure()
+        .stderr(contains("Keyspace not found"));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+    child.wait().unwrap();
+}
@@ -0,0 +1,436 @@
+//! Crash tests of `KvStore`: the store runs on a file system that fails or
+//! cuts short its I/O at random, then loses power, and every write it
+//! acknowledged must survive the reopen while nothing it did not may appear.
+
+use kvs::{FileSystem, KvStore, KvStoreOptions, KvsEngine, KvsError, MemoryFileSystem, VfsFile};
+use rand::prelude::*;
+use rand::rngs::SmallRng;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const SEEDS: u64 = 40;
+const ROUNDS: usize = 12;
+const OPS: usize = 40;
+const KEYS: u32 = 16;
+
+/// The faults a `FaultyFileSystem` injects, shared with its files.
+struct Faults {
+    rng: SmallRng,
+    // per mille of the operations that fail
+    rate: u32,
+    // every operation fails once the power is gone
+    killed: bool,
+}
+
+impl Faults {
+    fn fail(&mut self) -> bool {
+        self.killed || self.rng.gen_range(0, 1000) < self.rate
+    }
+
+    /// Returns whether to cut the `len` bytes of a read or write short, and
+    /// where.
+    fn short(&mut self, len: usize) -> Option<usize> {
+        if len > 1 && self.rng.gen_range(0, 1000) < self.rate {
+            Some(self.rng.gen_range(1, len))
+        } else {
+            None
+        }
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        if self.fail() {
+            Err(io::Error::other("injected fault"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A `MemoryFileSystem` whose operations fail at random.
+#[derive(Clone)]
+struct FaultyFileSystem {
+    inner: MemoryFileSystem,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultyFileSystem {
+    fn new(inner: MemoryFileSystem, seed: u64, rate: u32) -> FaultyFileSystem {
+        let faults = Faults {
+            rng: SmallRng::seed_from_u64(seed),
+            rate,
+            killed: false,
+        };
+        FaultyFileSystem {
+            inner,
+            faults: Arc::new(Mutex::new(faults)),
+        }
+    }
+
+    /// Fails every operation from now on.
+    fn kill(&self) {
+        self.faults.lock().unwrap().killed = true;
+    }
+
+    fn check(&self) -> io::Result<()> {
+        self.faults.lock().unwrap().check()
+    }
+
+    fn wrap(&self, file: io::Result<Box<dyn VfsFile>>) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(FaultyFile {
+            inner: file?,
+            faults: Arc::clone(&self.faults),
+            broken: false,
+        }))
+    }
+}
+
+impl FileSystem for FaultyFileSystem {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.check()?;
+        self.wrap(self.inner.create(path))
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.check()?;
+        self.wrap(self.inner.create_new(path))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.check()?;
+        self.wrap(self.inner.append(path))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.check()?;
+        self.wrap(self.inner.open(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.remove_dir_all(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.sync_dir(path)
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.check()?;
+        self.inner.list(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.inner.is_file(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<u64> {
+        self.check()?;
+        self.inner.available_space(path)
+    }
+}
+
+/// A file of a `FaultyFileSystem`.
+///
+/// Reads and writes may come up short. A write may also put a part of its
+/// bytes down and fail the next write, like a disk filling up.
+struct FaultyFile {
+    inner: Box<dyn VfsFile>,
+    faults: Arc<Mutex<Faults>>,
+    broken: bool,
+}
+
+impl Read for FaultyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut faults = self.faults.lock().unwrap();
+        faults.check()?;
+        let len = faults.short(buf.len()).unwrap_or(buf.len());
+        drop(faults);
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut faults = self.faults.lock().unwrap();
+        if self.broken {
+            self.broken = false;
+            return Err(io::Error::other("injected fault after a short write"));
+        }
+        faults.check()?;
+        let len = match faults.short(buf.len()) {
+            Some(len) => {
+                // either a legal short write, or the last one that works
+                self.broken = faults.rng.gen();
+                len
+            }
+            None => buf.len(),
+        };
+        drop(faults);
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.faults.lock().unwrap().check()?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.faults.lock().unwrap().check()?;
+        self.inner.seek(pos)
+    }
+}
+
+impl VfsFile for FaultyFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.faults.lock().unwrap().check()?;
+        self.inner.sync()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.faults.lock().unwrap().check()?;
+        self.inner.set_len(len)
+    }
+}
+
+type State = BTreeMap<String, String>;
+
+/// A write or read of a round, checked against the expected state.
+#[derive(Debug)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+    RemoveRange(String, String),
+    Batch(Vec<(String, Option<String>)>),
+    Get(String),
+    Compact,
+}
+
+impl Op {
+    fn random(rng: &mut SmallRng, state: &State) -> Op {
+        let key = |rng: &mut SmallRng| format!("key{:02}", rng.gen_range(0, KEYS));
+        let value = |rng: &mut SmallRng| {
+            let len = rng.gen_range(0, 80);
+            rng.sample_iter(&rand::distributions::Alphanumeric).take(len).collect::<String>()
+        };
+        match rng.gen_range(0, 20) {
+            0..=8 => Op::Set(key(rng), value(rng)),
+            9..=11 => Op::Remove(key(rng)),
+            12 => {
+                let (a, b) = (key(rng), key(rng));
+                Op::RemoveRange(a.clone().min(b.clone()), a.max(b))
+            }
+            13..=15 => {
+                let writes = (0..rng.gen_range(1, 5))
+                    .map(|_| {
+                        let key = key(rng);
+                        // a transaction only removes keys that have a value
+                        if state.contains_key(&key) && rng.gen() {
+                            (key, None)
+                        } else {
+                            (key, Some(value(rng)))
+                        }
+                    })
+                    .collect();
+                Op::Batch(writes)
+            }
+            16..=18 => Op::Get(key(rng)),
+            _ => Op::Compact,
+        }
+    }
+
+    /// Applies the op to `state`, as it does to a store that succeeds.
+    fn apply(&self, state: &mut State) {
+        match self {
+            Op::Set(key, value) => {
+                state.insert(key.clone(), value.clone());
+            }
+            Op::Remove(key) => {
+                state.remove(key);
+            }
+            Op::RemoveRange(start, end) => {
+                let keys: Vec<_> = state
+                    .range(start.clone()..end.clone())
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in keys {
+                    state.remove(&key);
+                }
+            }
+            Op::Batch(writes) => {
+                for (key, value) in writes {
+                    match value {
+                        Some(value) => state.insert(key.clone(), value.clone()),
+                        None => state.remove(key),
+                    };
+                }
+            }
+            Op::Get(_) | Op::Compact => {}
+        }
+    }
+
+    /// Runs the op on `store`, checking what it reads against `state`.
+    fn run(&self, store: &KvStore, state: &State) -> kvs::Result<()> {
+        match self {
+            Op::Set(key, value) => store.set(key.clone(), value.clone()),
+            Op::Remove(key) => match store.remove(key.clone()) {
+                Err(KvsError::KeyNotFound) if !state.contains_key(key) => Ok(()),
+                Err(KvsError::KeyNotFound) => panic!("{} has a value", key),
+                res => res,
+            },
+            Op::RemoveRange(start, end) => {
+                store.delete_range((Bound::Included(start.clone()), Bound::Excluded(end.clone())))
+            }
+            Op::Batch(writes) => {
+                let mut txn = store.begin()?;
+                for (key, value) in writes {
+                    match value {
+                        Some(value) => txn.set(key.clone(), value.clone()),
+                        None => match txn.remove(key.clone()) {
+                            // removed by an earlier write of the batch
+                            Err(KvsError::KeyNotFound) => {}
+                            res => res?,
+                        },
+                    }
+                }
+                txn.commit()
+            }
+            Op::Get(key) => {
+                let value = store.get(key.clone())?;
+                assert_eq!(value.as_ref(), state.get(key), "value of {}", key);
+                Ok(())
+            }
+            Op::Compact => store.compact(),
+        }
+    }
+}
+
+fn options(fs: impl FileSystem, persistent: bool, sync: bool) -> KvStoreOptions {
+    let options = KvStoreOptions::default()
+        .compaction_threshold(512)
+        .sync_writes(sync)
+        .file_system(fs);
+    if persistent {
+        options.index_memory_budget(256)
+    } else {
+        options
+    }
+}
+
+/// Runs rounds of random ops on a store that faults at random and then
+/// crashes, checking the state found after every crash.
+///
+/// With `sync` the state must be the last one acknowledged, without it any
+/// state acknowledged in the round. Either may also have the op that failed
+/// applied.
+fn crash_rounds(seed: u64, persistent: bool, sync: bool) {
+    let path = Path::new("store");
+    let mem = MemoryFileSystem::new();
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut state = State::new();
+
+    for round in 0..ROUNDS {
+        let context = format!("seed {}, round {}", seed, round);
+        let fs = FaultyFileSystem::new(mem.clone(), rng.gen(), rng.gen_range(0, 30));
+        let mut acknowledged = vec![state.clone()];
+        let mut failed = None;
+        match KvStore::open_with_options(path, options(fs.clone(), persistent, sync)) {
+            Ok(store) => {
+                for _ in 0..OPS {
+                    let op = Op::random(&mut rng, &state);
+                    let mut next = state.clone();
+                    op.apply(&mut next);
+                    if op.run(&store, &state).is_err() {
+                        failed = Some(next);
+                        break;
+                    }
+                    state = next;
+                    if !sync {
+                        acknowledged.push(state.clone());
+                    }
+                }
+                fs.kill();
+                mem.crash(|_, unsynced| rng.gen_range(0, unsynced + 1));
+            }
+            Err(_) => {
+                fs.kill();
+                mem.crash(|_, unsynced| rng.gen_range(0, unsynced + 1));
+            }
+        }
+        if sync {
+            acknowledged = vec![state.clone()];
+        }
+        acknowledged.extend(failed);
+
+        let store = KvStore::open_with_options(path, options(mem.clone(), persistent, sync))
+            .unwrap_or_else(|e| panic!("{}: reopening failed: {}", context, e));
+        let found: State = store
+            .scan(..)
+            .unwrap_or_else(|e| panic!("{}: scan failed: {}", context, e))
+            .into_iter()
+            .collect();
+        assert!(
+            acknowledged.contains(&found),
+            "{}: found {:?}, expected one of {:?}",
+            context,
+            found,
+            acknowledged
+        );
+        for (key, value) in &found {
+            assert_eq!(store.get(key.clone()).unwrap().as_ref(), Some(value), "{}", context);
+        }
+        state = found;
+    }
+}
+
+#[test]
+fn crash_memory_index() {
+    for seed in 0..SEEDS {
+        crash_rounds(seed, false, false);
+    }
+}
+
+#[test]
+fn crash_memory_index_synced() {
+    for seed in 0..SEEDS {
+        crash_rounds(seed, false, true);
+    }
+}
+
+#[test]
+fn crash_persistent_index() {
+    for seed in 0..SEEDS {
+        crash_rounds(seed, true, false);
+    }
+}
+
+#[test]
+fn crash_persistent_index_synced() {
+    for seed in 0..SEEDS {
+        crash_rounds(seed, true, true);
+    }
+}
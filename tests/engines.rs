@@ -0,0 +1,236 @@
+use kvs::{
+    conformance, KvStore, KvStoreOptions, KvsEngine, KvsError, LsmKvsEngine, LsmOptions,
+    MemoryFileSystem, MemoryKvsEngine, Result, ShardedEngine, SledKvsEngine,
+};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Returns the file system of the stores kept in memory. Every check uses a
+/// directory of its own, so they all share one.
+fn memory_fs() -> MemoryFileSystem {
+    static FS: OnceLock<MemoryFileSystem> = OnceLock::new();
+    FS.get_or_init(MemoryFileSystem::new).clone()
+}
+
+kvs::kvs_engine_conformance!(kv_store, |path| KvStore::open(path));
+
+kvs::kvs_engine_conformance!(kv_store_in_memory, |path| {
+    KvStore::open_with_options(path, KvStoreOptions::default().file_system(memory_fs()))
+});
+
+kvs::kvs_engine_conformance!(kv_store_persistent_index, |path| {
+    KvStore::open_with_options(path, KvStoreOptions::default().index_memory_budget(1024))
+});
+
+kvs::kvs_engine_conformance!(sled_engine, |path| {
+    Ok(SledKvsEngine::new(conformance::open_sled(path)?))
+});
+
+kvs::kvs_engine_conformance!(memory_engine, |path| {
+    MemoryKvsEngine::with_snapshot(path.join("snapshot"), None)
+});
+
+kvs::kvs_engine_conformance!(lsm_engine, |path| LsmKvsEngine::open(path));
+
+// tiny limits so that every check goes through flushes and compactions
+kvs::kvs_engine_conformance!(lsm_engine_compacting, |path| {
+    let options = LsmOptions::default()
+        .memtable_size(256)
+        .block_size(64)
+        .table_size(512)
+        .level0_limit(2)
+        .level_base_size(1024);
+    LsmKvsEngine::open_with_options(path, options)
+});
+
+kvs::kvs_engine_conformance!(sharded_kv_store, |path| {
+    ShardedEngine::open(path, 4, |path| KvStore::open(path))
+});
+
+#[test]
+fn kv_store_keyspaces() -> Result<()> {
+    conformance::keyspaces(|path| KvStore::open(path))
+}
+
+#[test]
+fn kv_store_persistent_index_keyspaces() -> Result<()> {
+    conformance::keyspaces(|path| {
+        KvStore::open_with_options(path, KvStoreOptions::default().index_memory_budget(1024))
+    })
+}
+
+#[test]
+fn kv_store_in_memory_keyspaces() -> Result<()> {
+    conformance::keyspaces(|path| {
+        let options = KvStoreOptions::default()
+            .index_memory_budget(1024)
+            .file_system(memory_fs());
+        KvStore::open_with_options(path, options)
+    })
+}
+
+#[test]
+fn sled_engine_keyspaces() -> Result<()> {
+    conformance::keyspaces(|path| Ok(SledKvsEngine::new(conformance::open_sled(path)?)))
+}
+
+#[test]
+fn sharded_kv_store_keyspaces() -> Result<()> {
+    conformance::keyspaces(|path| ShardedEngine::open(path, 4, |path| KvStore::open(path)))
+}
+
+#[test]
+fn kv_store_merge() -> Result<()> {
+    conformance::merge(|path| {
+        KvStore::open_with_options(path, KvStoreOptions::default().merge_operator(conformance::append))
+    })
+}
+
+#[test]
+fn kv_store_persistent_index_merge() -> Result<()> {
+    conformance::merge(|path| {
+        let options = KvStoreOptions::default()
+            .index_memory_budget(1024)
+            .merge_operator(conformance::append);
+        KvStore::open_with_options(path, options)
+    })
+}
+
+#[test]
+fn sled_engine_merge() -> Result<()> {
+    conformance::merge(|path| {
+        Ok(SledKvsEngine::with_merge_operator(conformance::open_sled(path)?, conformance::append))
+    })
+}
+
+#[test]
+fn sharded_kv_store_merge() -> Result<()> {
+    conformance::merge(|path| {
+        ShardedEngine::open(path, 4, |path| {
+            let options = KvStoreOptions::default().merge_operator(conformance::append);
+            KvStore::open_with_options(path, options)
+        })
+    })
+}
+
+#[test]
+fn kv_store_transactions() -> Result<()> {
+    conformance::transactions(|path| KvStore::open(path))
+}
+
+#[test]
+fn kv_store_persistent_index_transactions() -> Result<()> {
+    conformance::transactions(|path| {
+        KvStore::open_with_options(path, KvStoreOptions::default().index_memory_budget(1024))
+    })
+}
+
+#[test]
+fn sled_engine_transactions() -> Result<()> {
+    conformance::transactions(|path| Ok(SledKvsEngine::new(conformance::open_sled(path)?)))
+}
+
+// transactions need a single shard
+#[test]
+fn sharded_kv_store_transactions() -> Result<()> {
+    conformance::transactions(|path| ShardedEngine::open(path, 1, |path| KvStore::open(path)))?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = ShardedEngine::open(temp_dir.path(), 4, |path| KvStore::open(path))?;
+    match engine.begin() {
+        Err(KvsError::Unsupported(_)) => {}
+        res => panic!("expected Unsupported, got {:?}", res.map(|_| ()).map_err(|e| e.to_string())),
+    }
+    Ok(())
+}
+
+#[test]
+fn kv_store_secondary_indexes() -> Result<()> {
+    conformance::secondary_indexes(|path| KvStore::open(path))
+}
+
+#[test]
+fn kv_store_persistent_index_secondary_indexes() -> Result<()> {
+    conformance::secondary_indexes(|path| {
+        KvStore::open_with_options(path, KvStoreOptions::default().index_memory_budget(1024))
+    })
+}
+
+#[test]
+fn sharded_kv_store_secondary_indexes() -> Result<()> {
+    conformance::secondary_indexes(|path| ShardedEngine::open(path, 4, |path| KvStore::open(path)))
+}
+
+#[test]
+fn kv_store_watch_prefix() -> Result<()> {
+    conformance::watch_prefix(|path| KvStore::open(path))
+}
+
+#[test]
+fn kv_store_persistent_index_watch_prefix() -> Result<()> {
+    conformance::watch_prefix(|path| {
+        KvStore::open_with_options(path, KvStoreOptions::default().index_memory_budget(1024))
+    })
+}
+
+#[test]
+fn sled_engine_watch_prefix() -> Result<()> {
+    conformance::watch_prefix(|path| Ok(SledKvsEngine::new(conformance::open_sled(path)?)))
+}
+
+// sled's events are numbered by one counter for all watchers of the engine
+#[test]
+fn sled_engine_watch_seq() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(conformance::open_sled(temp_dir.path())?);
+    engine.create_keyspace("users")?;
+    let users = engine.keyspace("users")?;
+    let mut watchers = vec![
+        engine.watch_prefix("")?,
+        users.watch_prefix("")?,
+        engine.watch_prefix("")?,
+    ];
+    for i in 0..3 {
+        engine.set(format!("key{}", i), "value".to_owned())?;
+        users.set(format!("key{}", i), "value".to_owned())?;
+    }
+
+    let mut seqs = Vec::new();
+    for watcher in &mut watchers {
+        let mut last = 0;
+        for _ in 0..3 {
+            let event = watcher.next_timeout(Duration::from_secs(5))?.expect("no event");
+            assert!(event.seq > last, "{:?} out of order", event);
+            last = event.seq;
+            seqs.push(event.seq);
+        }
+    }
+    seqs.sort_unstable();
+    seqs.dedup();
+    assert_eq!(seqs.len(), 9);
+    Ok(())
+}
+
+#[test]
+fn sharded_kv_store_watch_prefix() -> Result<()> {
+    conformance::watch_prefix(|path| ShardedEngine::open(path, 4, |path| KvStore::open(path)))
+}
+
+// the change history needs a single shard
+#[test]
+fn sharded_kv_store_changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = ShardedEngine::open(temp_dir.path().join("one"), 1, |path| KvStore::open(path))?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    let seqs = engine.changes_since(0)?.map(|change| Ok(change?.seq)).collect::<Result<Vec<_>>>()?;
+    assert_eq!(seqs, vec![1, 2]);
+
+    let engine = ShardedEngine::open(temp_dir.path().join("four"), 4, |path| KvStore::open(path))?;
+    match engine.changes_since(0) {
+        Err(KvsError::Unsupported(_)) => {}
+        res => panic!("expected Unsupported, got {:?}", res.map(|_| ()).map_err(|e| e.to_string())),
+    }
+    Ok(())
+}
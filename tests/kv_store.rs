@@ -1,4 +1,14 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    Change, ChangeKind, FileSystem, IndexDefinition, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    MemoryFileSystem, RateLimited, RateLimiter, Result, VersionSpec,
+};
+use serde_json::json;
+use std::fs;
+use std::io::Write;
+use std::ops::Bound;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +16,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +26,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +37,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +46,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +58,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +74,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +82,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +94,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +124,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +134,1134 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Overwrite a single key until a compaction is triggered.
+// Generations without garbage should be left alone.
+#[test]
+fn partial_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("cold{}", key_id), "value".to_owned())?;
+    }
+
+    // Reopen so that the cold keys are sealed in the first generation
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let cold_gen = store.generation_stats()[0];
+    assert_eq!(cold_gen.dead_bytes, 0);
+    let hot_log = temp_dir.path().join(format!("{}.log", cold_gen.gen + 1));
+    // The removal must outlive the compaction of its generation
+    store.remove("cold0".to_owned())?;
+
+    for iter in 0..1_000_000 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+        if hot_log.exists() {
+            continue;
+        }
+        // Compaction triggered
+        assert_eq!(store.generation_stats()[0].total_bytes, cold_gen.total_bytes);
+
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("hot".to_owned())?, Some(format!("{}", iter)));
+        assert_eq!(store.get("cold0".to_owned())?, None);
+        for key_id in 1..1000 {
+            assert_eq!(store.get(format!("cold{}", key_id))?, Some("value".to_owned()));
+        }
+
+        // A full compaction rewrites every generation
+        store.compact()?;
+        assert!(store.generation_stats().iter().all(|stats| stats.dead_bytes == 0));
+        assert!(!temp_dir.path().join(format!("{}.log", cold_gen.gen)).exists());
+        assert_eq!(store.get("cold0".to_owned())?, None);
+        assert_eq!(store.get("cold1".to_owned())?, Some("value".to_owned()));
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Compaction should pay for its I/O with tokens of the rate limiter.
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limiter = RateLimiter::new(1024 * 1024);
+    let options = KvStoreOptions::default().rate_limiter(limiter.clone());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert_eq!(limiter.stats().bytes, 0);
+
+    store.compact()?;
+    let stats = limiter.stats();
+    assert!(stats.bytes > 0);
+
+    // Lowering the rate caps the bucket, so a larger request has to wait
+    limiter.set_rate(64 * 1024);
+    limiter.request(96 * 1024);
+    let throttled = limiter.stats().throttled - stats.throttled;
+    assert!(throttled >= Duration::from_millis(400));
+    assert_eq!(limiter.stats().throttles, stats.throttles + 1);
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    Ok(())
+}
+
+// A throttled compaction should not hold up writes of other threads, and the
+// keys they overwrite meanwhile should keep their new values.
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_threshold(u64::MAX);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..5 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("{}{:100}", iter, ""))?;
+        }
+    }
+    drop(store);
+
+    let limiter = RateLimiter::new(64 * 1024);
+    let options = KvStoreOptions::default()
+        .compaction_threshold(1024)
+        .rate_limiter(limiter.clone());
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let compaction = {
+        let store = store.clone();
+        thread::spawn(move || store.set("trigger".to_owned(), "value".to_owned()))
+    };
+    while limiter.stats().bytes == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    for key_id in 0..10 {
+        let start = Instant::now();
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+    assert!(!compaction.is_finished());
+    compaction.join().expect("thread panicked")?;
+    assert!(limiter.stats().throttles > 0);
+
+    for _ in 0..2 {
+        for key_id in 0..200 {
+            let expected = if key_id < 10 {
+                "new".to_owned()
+            } else {
+                format!("4{:100}", "")
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(expected));
+        }
+        assert_eq!(store.get("trigger".to_owned())?, Some("value".to_owned()));
+        store.compact()?;
+    }
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key10".to_owned())?, Some(format!("4{:100}", "")));
+    Ok(())
+}
+
+// A rate-limited writer should pay for the bytes written, not for those
+// offered to a short write.
+#[test]
+fn rate_limited_short_writes() -> Result<()> {
+    // takes at most 3 bytes per write
+    struct Short(Vec<u8>);
+    impl Write for Short {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let limiter = RateLimiter::unlimited();
+    let mut writer = RateLimited::new(Short(Vec::new()), limiter.clone());
+    writer.write_all(b"0123456789")?;
+    assert_eq!(writer.into_inner().0, b"0123456789");
+    assert_eq!(limiter.stats().bytes, 10);
+    Ok(())
+}
+
+// Writes should be slowed down and then stopped when garbage or log files
+// pile up faster than compaction reclaims them.
+#[test]
+fn write_stalls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(u64::MAX)
+        .reclaimable_limits(1024, 16 * 1024)
+        .stall_delay(Duration::from_micros(10));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+        let reclaimable: u64 = store
+            .generation_stats()
+            .iter()
+            .map(|stats| stats.dead_bytes)
+            .sum();
+        assert!(reclaimable <= 16 * 1024 + 64);
+    }
+    let stats = store.stall_stats();
+    assert!(stats.slowdowns > 0);
+    assert!(stats.stops > 0);
+    assert_eq!(store.get("key".to_owned())?, Some("999".to_owned()));
+
+    // Every reopen adds a log file
+    drop(store);
+    let options = options.segment_limits(3, 4);
+    for iter in 0..10 {
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set(format!("key{}", iter), "value".to_owned())?;
+        assert!(store.generation_stats().len() <= 4);
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        assert_eq!(store.get(format!("key{}", iter))?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// Writes should be refused while the disk has less free space than required.
+#[test]
+fn min_free_space() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let options = KvStoreOptions::default().min_free_space(u64::MAX);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    match store.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvsError::ReadOnly(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match store.remove("key1".to_owned()) {
+        Err(KvsError::ReadOnly(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A compaction file that was not completed should never be loaded.
+#[test]
+fn unfinished_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let unfinished = temp_dir.path().join("2.log.compacting");
+    fs::write(&unfinished, "{\"Set\":{\"key\":\"key1\",\"val")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!unfinished.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A read-only store should never touch the directory and should pick up
+// what the writer adds when refreshed.
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let files = || {
+        let mut files: Vec<_> = fs::read_dir(temp_dir.path())
+            .expect("unable to list directory")
+            .map(|entry| entry.expect("unable to read directory entry").path())
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files();
+    let read_only = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(files(), before);
+    assert_eq!(read_only.get("key1".to_owned())?, Some("value1".to_owned()));
+    match read_only.set("key3".to_owned(), "value3".to_owned()) {
+        Err(KvsError::ReadOnly(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match read_only.remove("key1".to_owned()) {
+        Err(KvsError::ReadOnly(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert!(read_only.compact().is_err());
+
+    // Appended commands
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(read_only.get("key3".to_owned())?, None);
+    read_only.refresh()?;
+    assert_eq!(read_only.get("key1".to_owned())?, None);
+    assert_eq!(read_only.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // Compacted log files
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value5".to_owned())?;
+    read_only.refresh()?;
+    assert_eq!(read_only.get("key1".to_owned())?, None);
+    assert_eq!(read_only.get("key2".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(read_only.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(read_only.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert!(KvStore::open_read_only(temp_dir.path().join("missing")).is_err());
+    Ok(())
+}
+
+// With an index memory budget, keys of sealed generations should be looked up
+// in index files that survive compaction, reopening and switching modes.
+#[test]
+fn persistent_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::default().index_memory_budget(4 * 1024);
+    let index_files = || {
+        fs::read_dir(temp_dir.path())
+            .expect("unable to list directory")
+            .map(|entry| entry.expect("unable to read directory entry").path())
+            .filter(|path| path.extension() == Some("idx".as_ref()))
+            .count()
+    };
+    let expected = |i: usize| {
+        if i.is_multiple_of(5) {
+            None
+        } else if i.is_multiple_of(3) {
+            Some(format!("new{}", i))
+        } else {
+            Some(format!("value{}", i))
+        }
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..1000 {
+            assert_eq!(store.get(format!("key{}", i))?, expected(i));
+        }
+        Ok(())
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..1000).step_by(3) {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    for i in (0..1000).step_by(5) {
+        store.remove(format!("key{}", i))?;
+    }
+    check(&store)?;
+    assert!(index_files() > 10);
+    assert!(store.index_memory_usage() < 16 * 1024);
+
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    check(&store)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    // Index files are dropped when the store is written without them
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(index_files(), 0);
+    check(&store)?;
+    let memory_usage = store.index_memory_usage();
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert!(index_files() > 0);
+    assert!(store.index_memory_usage() < memory_usage);
+    check(&store)?;
+    Ok(())
+}
+
+// Bloom filters of a persistent index should spare index file reads for
+// absent keys and be rebuilt when missing.
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::default()
+            .index_memory_budget(4 * 1024)
+            .bloom_false_positive_rate(0.01)
+    };
+    let files = |extension: &str| {
+        let mut files: Vec<_> = fs::read_dir(temp_dir.path())
+            .expect("unable to list directory")
+            .map(|entry| entry.expect("unable to read directory entry").path())
+            .filter(|path| path.extension() == Some(extension.as_ref()))
+            .collect();
+        files.sort();
+        files
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(files("bloom").len(), files("idx").len());
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("missing{}", i))?, None);
+    }
+    let stats = store.bloom_stats();
+    assert_eq!(stats.true_positives, 0);
+    assert!(stats.negatives > 10_000);
+    assert!(stats.false_positives * 20 < stats.negatives);
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert!(store.bloom_stats().true_positives > 900);
+    drop(store);
+
+    let bloom = files("bloom").remove(0);
+    fs::remove_file(&bloom)?;
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert!(bloom.exists());
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(KvStore::open(temp_dir.path())?.bloom_stats(), Default::default());
+    assert!(files("bloom").is_empty());
+    Ok(())
+}
+
+// Keyspaces are stores in subdirectories; handles of a dropped keyspace must
+// not write to it anymore, and read-only stores cannot change keyspaces.
+#[test]
+fn keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.create_keyspace("users")?;
+    let users = store.keyspace("users")?;
+    users.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(temp_dir.path().join("keyspaces").join("users").is_dir());
+
+    let read_only = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(
+        read_only.keyspace("users")?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert!(matches!(read_only.create_keyspace("orders"), Err(KvsError::ReadOnly(_))));
+    assert!(matches!(read_only.drop_keyspace("users"), Err(KvsError::ReadOnly(_))));
+    drop(read_only);
+
+    store.drop_keyspace("users")?;
+    assert!(!temp_dir.path().join("keyspaces").join("users").exists());
+    assert!(matches!(
+        users.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::KeyspaceNotFound(_))
+    ));
+    Ok(())
+}
+
+fn append(_key: &str, existing: Option<&str>, operand: &str) -> String {
+    match existing {
+        Some(existing) => format!("{},{}", existing, operand),
+        None => operand.to_owned(),
+    }
+}
+
+// Compactions fold merge commands into their values, whichever of the
+// generations holding them are compacted.
+#[test]
+fn merge_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::default()
+            .compaction_threshold(u64::MAX)
+            .merge_operator(append)
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+
+    for round in 0..3 {
+        for i in 0..100 {
+            store.merge(format!("key{}", i % 10), format!("{}", round))?;
+        }
+        // compacting starts a new generation, so merges span several of them
+        if round == 1 {
+            store.compact()?;
+        }
+    }
+    // every key got ten operands per round
+    let expected = Some(
+        (0..3)
+            .flat_map(|round| vec![round.to_string(); 10])
+            .collect::<Vec<_>>()
+            .join(","),
+    );
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, expected.clone());
+    }
+
+    store.compact()?;
+    let stats = store.generation_stats();
+    assert!(stats.iter().all(|stats| stats.dead_bytes == 0), "{:?}", stats);
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, expected.clone());
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, expected.clone());
+    }
+    // without the operator, merged values cannot be read, but folded ones can
+    store.merge("key0".to_owned(), "3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, expected);
+    assert!(matches!(store.get("key0".to_owned()), Err(KvsError::Unsupported(_))));
+    assert!(matches!(
+        store.merge("key1".to_owned(), "3".to_owned()),
+        Err(KvsError::Unsupported(_))
+    ));
+    drop(store);
+
+    // read-only stores fold merged values with the operator they are given
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.create_keyspace("users")?;
+    store.keyspace("users")?.merge("key0".to_owned(), "4".to_owned())?;
+    drop(store);
+    let read_only = KvStore::open_read_only(temp_dir.path())?;
+    assert!(matches!(read_only.get("key0".to_owned()), Err(KvsError::Unsupported(_))));
+    let read_only = KvStore::open_read_only_with_options(temp_dir.path(), options())?;
+    assert_eq!(read_only.get("key0".to_owned())?, expected.map(|value| value + ",3"));
+    assert_eq!(read_only.keyspace("users")?.get("key0".to_owned())?, Some("4".to_owned()));
+    Ok(())
+}
+
+// Compacting only some generations must not leave merge commands pointing
+// into generations that are gone.
+#[test]
+fn merge_partial_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(2048)
+        .dirty_ratio(0.5)
+        .merge_operator(append);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let mut expected = vec![String::new(); 5];
+    for i in 0..2000 {
+        let key = i % 5;
+        if i % 7 == 0 {
+            store.set(format!("key{}", key), format!("s{}", i))?;
+            expected[key] = format!("s{}", i);
+        } else {
+            store.merge(format!("key{}", key), format!("m{}", i))?;
+            if expected[key].is_empty() {
+                expected[key] = format!("m{}", i);
+            } else {
+                expected[key] = format!("{},m{}", expected[key], i);
+            }
+        }
+        // overwritten keys fill the log with garbage that triggers compactions
+        store.set(format!("filler{}", i % 3), "x".repeat(100))?;
+        if i % 97 == 0 {
+            for (key, value) in expected.iter().enumerate() {
+                if !value.is_empty() {
+                    assert_eq!(store.get(format!("key{}", key))?.as_ref(), Some(value));
+                }
+            }
+        }
+    }
+    assert!(store.generation_stats().len() > 1);
+    for (key, value) in expected.iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", key))?.as_ref(), Some(value));
+    }
+    Ok(())
+}
+
+// A range deletion is a single command in the log. A compaction that keeps
+// older generations must keep it too, without removing keys set again in
+// generations it does not compact.
+#[test]
+fn range_tombstones() -> Result<()> {
+    for &persistent in &[false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = |threshold| {
+            let options = KvStoreOptions::default()
+                .compaction_threshold(threshold)
+                .dirty_ratio(0.5);
+            if persistent {
+                options.index_memory_budget(1024 * 1024)
+            } else {
+                options
+            }
+        };
+        let log_size = || -> u64 {
+            WalkDir::new(temp_dir.path())
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+                .map(|entry| entry.metadata().unwrap().len())
+                .sum()
+        };
+
+        // generation 1 stays mostly live, so it is not compacted
+        let store = KvStore::open_with_options(temp_dir.path(), options(u64::MAX))?;
+        store.set("keep".to_owned(), "x".repeat(10_000))?;
+        for i in 0..10 {
+            store.set(format!("a{}", i), format!("old{}", i))?;
+        }
+        drop(store);
+
+        // generation 2 holds the tombstone and garbage
+        let store = KvStore::open_with_options(temp_dir.path(), options(u64::MAX))?;
+        let size = log_size();
+        store.delete_prefix("a")?;
+        assert!(log_size() - size < 100, "more than one command written");
+        for i in 0..100 {
+            store.set("junk".to_owned(), format!("{:100}", i))?;
+        }
+        drop(store);
+
+        // setting a key of the range in generation 3 compacts generation 2
+        let store = KvStore::open_with_options(temp_dir.path(), options(1000))?;
+        store.set("a5".to_owned(), "new".to_owned())?;
+        let gens: Vec<_> = store.generation_stats().iter().map(|stats| stats.gen).collect();
+        assert_eq!(gens, vec![1, 3, 4, 5]);
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options(u64::MAX))?;
+        assert_eq!(store.get("a5".to_owned())?, Some("new".to_owned()));
+        for i in (0..10).filter(|&i| i != 5) {
+            assert_eq!(store.get(format!("a{}", i))?, None);
+        }
+        assert_eq!(store.get("keep".to_owned())?.map(|value| value.len()), Some(10_000));
+        assert_eq!(store.scan(..)?.len(), 3);
+    }
+    Ok(())
+}
+
+// Old versions stay readable until a compaction drops the ones past the limit.
+#[test]
+fn versions() -> Result<()> {
+    for &persistent in &[false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStoreOptions::default()
+            .keep_versions(3)
+            .merge_operator(append);
+        if persistent {
+            options = options.index_memory_budget(1024 * 1024);
+        }
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+        for i in 1..=4 {
+            store.set("key".to_owned(), format!("v{}", i))?;
+        }
+        store.merge("key".to_owned(), "m".to_owned())?;
+        let values = |store: &KvStore| -> Result<Vec<(u64, String)>> {
+            Ok(store
+                .get_versions("key".to_owned())?
+                .into_iter()
+                .map(|version| (version.version, version.value))
+                .collect())
+        };
+        assert_eq!(
+            values(&store)?,
+            vec![
+                (5, "v4,m".to_owned()),
+                (4, "v4".to_owned()),
+                (3, "v3".to_owned()),
+                (2, "v2".to_owned()),
+                (1, "v1".to_owned()),
+            ]
+        );
+        assert_eq!(
+            store.get_at("key".to_owned(), VersionSpec::Version(2))?,
+            Some("v2".to_owned())
+        );
+        let versions = store.get_versions("key".to_owned())?;
+        assert!(versions.windows(2).all(|pair| pair[0].timestamp >= pair[1].timestamp));
+        assert_eq!(
+            store.get_at("key".to_owned(), VersionSpec::Timestamp(versions[0].timestamp))?,
+            Some("v4,m".to_owned())
+        );
+        assert_eq!(store.get_at("key".to_owned(), VersionSpec::Timestamp(0))?, None);
+
+        store.compact()?;
+        let kept = vec![
+            (5, "v4,m".to_owned()),
+            (4, "v4".to_owned()),
+            (3, "v3".to_owned()),
+        ];
+        assert_eq!(values(&store)?, kept);
+        assert_eq!(store.get_at("key".to_owned(), VersionSpec::Version(2))?, None);
+
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(values(&store)?, kept);
+        store.set("key".to_owned(), "v6".to_owned())?;
+        assert_eq!(store.get_versions("key".to_owned())?[0].version, 6);
+
+        store.remove("key".to_owned())?;
+        assert!(store.get_versions("key".to_owned())?.is_empty());
+        store.set("key".to_owned(), "new".to_owned())?;
+        assert_eq!(values(&store)?, vec![(1, "new".to_owned())]);
+    }
+    Ok(())
+}
+
+// Compactions that leave some generations behind still keep every version
+// within the limit.
+#[test]
+fn versions_partial_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_threshold(2048)
+        .dirty_ratio(0.5)
+        .keep_versions(3);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 5), format!("v{}", i))?;
+        // overwritten keys fill the log with garbage that triggers compactions
+        store.set(format!("filler{}", i % 3), "x".repeat(100))?;
+        if i % 97 == 0 || i == 1999 {
+            for key in 0..5.min(i + 1) {
+                let versions = store.get_versions(format!("key{}", key))?;
+                let newest = i - (i + 5 - key) % 5;
+                assert!(versions.len() >= 3.min(newest / 5 + 1));
+                for (n, version) in versions.iter().take(3).enumerate() {
+                    if newest >= 5 * n {
+                        assert_eq!(version.value, format!("v{}", newest - 5 * n));
+                        assert_eq!(version.version, (newest / 5 + 1 - n) as u64);
+                    }
+                }
+            }
+        }
+    }
+    assert!(store.generation_stats().len() > 1);
+    Ok(())
+}
+
+// Versions within the retention window survive compactions whatever their
+// number.
+#[test]
+fn versions_retention_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .keep_versions(2)
+        .keep_versions_for(Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    store.compact()?;
+    assert_eq!(store.get_versions("key".to_owned())?.len(), 10);
+
+    drop(store);
+    let store =
+        KvStore::open_with_options(temp_dir.path(), KvStoreOptions::default().keep_versions(2))?;
+    store.compact()?;
+    let versions = store.get_versions("key".to_owned())?;
+    let values: Vec<_> = versions.into_iter().map(|version| version.value).collect();
+    assert_eq!(values, vec!["9".to_owned(), "8".to_owned()]);
+    Ok(())
+}
+
+// Transactions read a snapshot, conflict on the keys they write too, and
+// commit as a single command that compaction and reopening keep intact.
+#[test]
+fn transactions() -> Result<()> {
+    for &persistent in &[false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStoreOptions::default().keep_versions(2);
+        if persistent {
+            options = options.index_memory_budget(1024 * 1024);
+        }
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("x".to_owned(), "old".to_owned())?;
+        store.set("z".to_owned(), "old".to_owned())?;
+
+        let mut txn = store.begin()?;
+        store.set("x".to_owned(), "new".to_owned())?;
+        store.set("x".to_owned(), "newer".to_owned())?;
+        store.remove("z".to_owned())?;
+        store.set("w".to_owned(), "new".to_owned())?;
+        assert_eq!(txn.get("x".to_owned())?, Some("old".to_owned()));
+        assert_eq!(txn.get("z".to_owned())?, Some("old".to_owned()));
+        assert_eq!(txn.get("w".to_owned())?, None);
+        txn.set("v".to_owned(), "inside".to_owned());
+        assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict(_))));
+        assert_eq!(store.get("v".to_owned())?, None);
+
+        let mut txn = store.begin()?;
+        store.set("y".to_owned(), "outside".to_owned())?;
+        txn.set("y".to_owned(), "inside".to_owned());
+        assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict(_))));
+        assert_eq!(store.get("y".to_owned())?, Some("outside".to_owned()));
+
+        let mut txn = store.begin()?;
+        for i in 0..100 {
+            txn.set(format!("key{}", i), format!("value{}", i));
+        }
+        txn.remove("y".to_owned())?;
+        txn.set("x".to_owned(), "txn".to_owned());
+        txn.commit()?;
+
+        let check = |store: &KvStore| -> Result<()> {
+            for i in 0..100 {
+                assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+            }
+            assert_eq!(store.get("y".to_owned())?, None);
+            let versions = store.get_versions("x".to_owned())?;
+            assert_eq!(versions[0].value, "txn");
+            assert_eq!(versions[1].value, "newer");
+            Ok(())
+        };
+        check(&store)?;
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        check(&store)?;
+        store.compact()?;
+        check(&store)?;
+        assert!(store.generation_stats().iter().all(|stats| stats.dead_bytes == 0));
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        check(&store)?;
+    }
+    Ok(())
+}
+
+// Watchers hear of merges with the folded value and of every write of a
+// transaction, numbered by the writes of the store they watch.
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let append = |_key: &str, existing: Option<&str>, operand: &str| match existing {
+        Some(existing) => format!("{},{}", existing, operand),
+        None => operand.to_owned(),
+    };
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::default().merge_operator(append),
+    )?;
+    store.create_keyspace("other")?;
+    let other = store.keyspace("other")?;
+
+    let mut watcher = store.watch_prefix("k")?;
+    let mut other_watcher = other.watch_prefix("k")?;
+    store.set("k1".to_owned(), "a".to_owned())?;
+    store.merge("k1".to_owned(), "b".to_owned())?;
+    let mut txn = store.begin()?;
+    txn.set("k2".to_owned(), "c".to_owned());
+    txn.remove("k1".to_owned())?;
+    txn.remove("missing".to_owned()).unwrap_err();
+    txn.commit()?;
+    other.set("k1".to_owned(), "elsewhere".to_owned())?;
+
+    let timeout = Duration::from_secs(5);
+    let mut events = Vec::new();
+    for _ in 0..4 {
+        let event = watcher.next_timeout(timeout)?.expect("watcher missed a write");
+        events.push((event.seq, event.key, event.value));
+    }
+    assert_eq!(
+        events,
+        vec![
+            (1, "k1".to_owned(), Some("a".to_owned())),
+            (2, "k1".to_owned(), Some("a,b".to_owned())),
+            (3, "k1".to_owned(), None),
+            (4, "k2".to_owned(), Some("c".to_owned())),
+        ]
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(100))?, None);
+    let event = other_watcher.next_timeout(timeout)?.expect("watcher missed a write");
+    assert_eq!((event.seq, event.value), (1, Some("elsewhere".to_owned())));
+
+    drop(store);
+    drop(other);
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    match store.watch_prefix("k") {
+        Err(KvsError::ReadOnly(_)) => {}
+        res => panic!("expected ReadOnly, got {:?}", res.map(|_| ()).map_err(|e| e.to_string())),
+    }
+    Ok(())
+}
+
+// Records are numbered across reopens, and changes stay readable until a
+// compaction drops their records.
+#[test]
+fn changes_since() -> Result<()> {
+    for &persistent in &[false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || {
+            let options = KvStoreOptions::default().merge_operator(append);
+            if persistent {
+                options.index_memory_budget(1024 * 1024)
+            } else {
+                options
+            }
+        };
+        let changes = |store: &KvStore, seq| -> Result<Vec<Change>> {
+            store.changes_since(seq)?.collect()
+        };
+        let seqs = |changes: Vec<Change>| -> Vec<u64> { changes.iter().map(|change| change.seq).collect() };
+        let assert_compacted = |res: Result<Vec<Change>>| match res {
+            Err(KvsError::HistoryCompacted(_)) => {}
+            res => panic!("expected HistoryCompacted, got {:?}", res.map_err(|e| e.to_string())),
+        };
+
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        store.set("k1".to_owned(), "a".to_owned())?;
+        store.merge("k1".to_owned(), "b".to_owned())?;
+        store.remove("missing".to_owned()).unwrap_err();
+        let mut txn = store.begin()?;
+        txn.set("k2".to_owned(), "c".to_owned());
+        txn.remove("k1".to_owned())?;
+        txn.commit()?;
+        store.delete_prefix("k")?;
+        assert_eq!(
+            changes(&store, 0)?,
+            vec![
+                Change {
+                    seq: 1,
+                    kind: ChangeKind::Set { key: "k1".to_owned(), value: "a".to_owned() },
+                },
+                Change {
+                    seq: 2,
+                    kind: ChangeKind::Merge { key: "k1".to_owned(), operand: "b".to_owned() },
+                },
+                Change {
+                    seq: 3,
+                    kind: ChangeKind::Remove { key: "k1".to_owned() },
+                },
+                Change {
+                    seq: 4,
+                    kind: ChangeKind::Set { key: "k2".to_owned(), value: "c".to_owned() },
+                },
+                Change {
+                    seq: 5,
+                    kind: ChangeKind::RemoveRange {
+                        start: Bound::Included("k".to_owned()),
+                        end: Bound::Excluded("l".to_owned()),
+                    },
+                },
+            ]
+        );
+        assert_eq!(seqs(changes(&store, 3)?), vec![4, 5]);
+        assert!(changes(&store, 5)?.is_empty());
+        drop(store);
+
+        // numbers go on after a reopen, and read-only stores see them
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        store.set("x".to_owned(), "1".to_owned())?;
+        assert_eq!(seqs(changes(&store, 2)?), vec![3, 4, 5, 6]);
+        let reader = KvStore::open_read_only(temp_dir.path())?;
+        assert_eq!(seqs(changes(&reader, 0)?), vec![1, 2, 3, 4, 5, 6]);
+        drop(reader);
+
+        // compaction drops the history it covers
+        store.compact()?;
+        assert_compacted(changes(&store, 5));
+        assert!(changes(&store, 6)?.is_empty());
+        store.set("y".to_owned(), "2".to_owned())?;
+        assert_eq!(seqs(changes(&store, 6)?), vec![7]);
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        assert_compacted(changes(&store, 0));
+        store.set("z".to_owned(), "3".to_owned())?;
+        assert_eq!(seqs(changes(&store, 6)?), vec![7, 8]);
+
+        // keyspaces number their records apart
+        store.create_keyspace("other")?;
+        let other = store.keyspace("other")?;
+        other.set("k".to_owned(), "v".to_owned())?;
+        assert_eq!(seqs(changes(&other, 0)?), vec![1]);
+    }
+    Ok(())
+}
+
+fn replace(_key: &str, _existing: Option<&str>, operand: &str) -> String {
+    operand.to_owned()
+}
+
+#[test]
+fn secondary_indexes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::default().merge_operator(replace);
+    let keys = |found: Vec<(String, String)>| -> Vec<String> {
+        found.into_iter().map(|(key, _)| key).collect()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("u1".to_owned(), r#"{"city":"Paris","age":30}"#.to_owned())?;
+    store.set("u2".to_owned(), r#"{"city":"Oslo","age":25}"#.to_owned())?;
+    store.set("u3".to_owned(), "not json".to_owned())?;
+    store.set("u4".to_owned(), r#"{"city":["Rome"],"age":-1.5}"#.to_owned())?;
+
+    // existing values are indexed on creation
+    store.create_index("city", "/city")?;
+    store.create_index("age", "/age")?;
+    store.create_index("city", "/city")?;
+    match store.create_index("city", "/age") {
+        Err(KvsError::IndexExists(_)) => {}
+        res => panic!("expected IndexExists, got {:?}", res.map_err(|e| e.to_string())),
+    }
+    assert!(store.create_index("bad", "city").is_err());
+    assert_eq!(
+        store.indexes()?,
+        vec![
+            IndexDefinition { name: "age".to_owned(), pointer: "/age".to_owned() },
+            IndexDefinition { name: "city".to_owned(), pointer: "/city".to_owned() },
+        ]
+    );
+    assert_eq!(
+        store.index_get("city", &json!("Paris"))?,
+        vec![("u1".to_owned(), r#"{"city":"Paris","age":30}"#.to_owned())]
+    );
+    assert!(store.index_get("city", &json!("Rome"))?.is_empty());
+    assert_eq!(keys(store.index_scan("age", ..)?), vec!["u4", "u2", "u1"]);
+    assert_eq!(keys(store.index_scan("age", json!(0)..json!(30))?), vec!["u2"]);
+    assert_eq!(keys(store.index_scan("age", json!(25)..=json!(30))?), vec!["u2", "u1"]);
+    assert!(store.index_get("city", &json!(["Rome"])).is_err());
+    match store.index_get("missing", &json!(1)) {
+        Err(KvsError::IndexNotFound(_)) => {}
+        res => panic!("expected IndexNotFound, got {:?}", res.map_err(|e| e.to_string())),
+    }
+
+    // writes keep the indexes up to date
+    store.set("u1".to_owned(), r#"{"city":"Oslo","age":30}"#.to_owned())?;
+    store.remove("u2".to_owned())?;
+    store.merge("u3".to_owned(), r#"{"city":"Paris","age":"old"}"#.to_owned())?;
+    let mut txn = store.begin()?;
+    txn.set("u5".to_owned(), r#"{"city":"Paris","age":null}"#.to_owned());
+    txn.remove("u4".to_owned())?;
+    txn.commit()?;
+    assert_eq!(keys(store.index_get("city", &json!("Oslo"))?), vec!["u1"]);
+    assert_eq!(keys(store.index_get("city", &json!("Paris"))?), vec!["u3", "u5"]);
+    // fields of different types sort null, booleans, numbers then strings
+    assert_eq!(keys(store.index_scan("age", ..)?), vec!["u5", "u1", "u3"]);
+    store.delete_range("u4".to_owned().."u9".to_owned())?;
+    assert_eq!(keys(store.index_get("city", &json!("Paris"))?), vec!["u3"]);
+    drop(store);
+
+    // indexes survive a reopen, and read-only stores use them
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(keys(store.index_get("city", &json!("Oslo"))?), vec!["u1"]);
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(keys(reader.index_get("city", &json!("Oslo"))?), vec!["u1"]);
+    assert!(reader.create_index("name", "/name").is_err());
+    store.set("u6".to_owned(), r#"{"city":"Oslo"}"#.to_owned())?;
+    reader.refresh()?;
+    assert_eq!(keys(reader.index_get("city", &json!("Oslo"))?), vec!["u1", "u6"]);
+    drop(reader);
+
+    store.drop_index("city")?;
+    match store.drop_index("city") {
+        Err(KvsError::IndexNotFound(_)) => {}
+        res => panic!("expected IndexNotFound, got {:?}", res.map_err(|e| e.to_string())),
+    }
+    assert!(store.index_get("city", &json!("Oslo")).is_err());
+    store.create_index("city", "/city")?;
+    assert_eq!(keys(store.index_get("city", &json!("Oslo"))?), vec!["u1", "u6"]);
+
+    // keyspaces have indexes of their own
+    store.create_keyspace("other")?;
+    let other = store.keyspace("other")?;
+    assert!(other.indexes()?.is_empty());
+    other.create_index("city", "/city")?;
+    other.set("k".to_owned(), r#"{"city":"Oslo"}"#.to_owned())?;
+    assert_eq!(keys(other.index_get("city", &json!("Oslo"))?), vec!["k"]);
+    assert_eq!(keys(store.index_get("city", &json!("Oslo"))?), vec!["u1", "u6"]);
+    Ok(())
+}
+
+// A store on a file system in memory leaves the disk alone, and is found
+// again by stores opened on a clone of the file system.
+#[test]
+fn memory_file_system() -> Result<()> {
+    let fs = MemoryFileSystem::new();
+    let root = Path::new("/kvs-memory-file-system");
+    for &budget in &[None, Some(1024)] {
+        let path = &root.join(format!("{:?}", budget));
+        let options = || {
+            let options = KvStoreOptions::default()
+                .compaction_threshold(4 * 1024)
+                .file_system(fs.clone());
+            match budget {
+                Some(budget) => options.index_memory_budget(budget),
+                None => options,
+            }
+        };
+        let store = KvStore::open_with_options(path, options())?;
+        for iter in 0..20 {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), format!("{}", iter))?;
+            }
+        }
+        store.remove("key0".to_owned())?;
+        store.create_keyspace("other")?;
+        store.keyspace("other")?.set("k".to_owned(), "v".to_owned())?;
+        store.create_index("n", "")?;
+        drop(store);
+
+        let store = KvStore::open_with_options(path, options())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("19".to_owned()));
+        assert_eq!(store.index_get("n", &json!(19))?.len(), 99);
+        let reader = KvStore::open_read_only_with_file_system(path, fs.clone())?;
+        assert_eq!(reader.get("key99".to_owned())?, Some("19".to_owned()));
+        assert_eq!(reader.keyspace("other")?.get("k".to_owned())?, Some("v".to_owned()));
+        drop(reader);
+        store.drop_keyspace("other")?;
+        assert!(!fs.exists(&path.join("keyspaces").join("other")));
+        drop(store);
+        assert!(fs.is_dir(path));
+    }
+    assert!(!root.exists());
+    Ok(())
+}
+
+// Corrupt log files are rejected with errors, never panics or endless reads
+#[test]
+fn corrupt_log_files() -> Result<()> {
+    let fs = MemoryFileSystem::new();
+    let open = |log: &str| -> Result<KvStore> {
+        let path = Path::new("/kvs-corrupt").join(format!("{}", log.len()));
+        fs.create_dir_all(&path)?;
+        fs.create(&path.join("1.log"))?.write_all(log.as_bytes())?;
+        let options = KvStoreOptions::default()
+            .merge_operator(replace)
+            .keep_versions(10)
+            .file_system(fs.clone());
+        KvStore::open_with_options(path, options)
+    };
+
+    // a command that is its own previous version
+    let merge = r#"{"Merge":{"key":"a","operand":"x","prev":{"gen":1,"pos":0,"len":69}}}"#;
+    assert_eq!(merge.len(), 69);
+    let store = open(merge)?;
+    assert!(store.get("a".to_owned()).is_err());
+    let set = r#"{"Set":{"key":"a","value":"x","prev":{"gen":1,"pos":0,"len":65}}}"#;
+    assert_eq!(set.len(), 65);
+    let store = open(set)?;
+    assert!(store.get_versions("a".to_owned()).is_err());
+
+    // a batch that is not laid out the way the store writes them
+    assert!(open(r#"{"Batch":[{"Merge":{"key":"a","operand":"x"}}]}"#).is_err());
+    assert!(open(r#"{"Batch": [{"Set":{"key":"a","value":"x"}}]}"#).is_err());
+    Ok(())
+}
@@ -0,0 +1,165 @@
+//! Loom checks of `LiveGens::read_latest` and `LiveGens::retire`. Run them
+//! with
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --test loom
+//! ```
+//!
+//! Only `LiveGens` is checked. A small model store drives it, publishing
+//! new positions before it retires a generation and removes its file, and
+//! under loom a mutex stands in for its skip set. No `KvStore` code runs
+//! here; the threaded `conformance::concurrent` test covers that.
+#![cfg(loom)]
+
+use kvs::{KvsError, LiveGens, Result};
+use loom::sync::{Arc, Mutex};
+use loom::thread;
+use std::collections::BTreeMap;
+use std::io;
+
+/// The position of a value: its generation and its slot in the log file.
+type Pos = (u64, usize);
+
+/// A model store with the key "a", set to "v1", in generation 1.
+struct Store {
+    index: Mutex<BTreeMap<&'static str, Pos>>,
+    // the log files
+    files: Mutex<BTreeMap<u64, Vec<&'static str>>>,
+    live_gens: LiveGens,
+    // the writer lock, held to the current generation
+    current_gen: Mutex<u64>,
+}
+
+impl Store {
+    fn new() -> Store {
+        let live_gens = LiveGens::new();
+        live_gens.insert(1);
+        Store {
+            index: Mutex::new(vec![("a", (1, 0))].into_iter().collect()),
+            files: Mutex::new(vec![(1, vec!["v1"])].into_iter().collect()),
+            live_gens,
+            current_gen: Mutex::new(1),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<&'static str>> {
+        self.live_gens.read_latest(
+            || Ok(self.index.lock().unwrap().get(key).map(|&pos| (pos.0, pos))),
+            |(gen, slot)| match self.files.lock().unwrap().get(&gen) {
+                Some(file) => Ok(file[slot]),
+                None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+            },
+        )
+    }
+
+    fn set(&self, key: &'static str, value: &'static str) {
+        let current_gen = self.current_gen.lock().unwrap();
+        let slot = {
+            let mut files = self.files.lock().unwrap();
+            let file = files.get_mut(&*current_gen).unwrap();
+            file.push(value);
+            file.len() - 1
+        };
+        self.index.lock().unwrap().insert(key, (*current_gen, slot));
+    }
+
+    /// Moves the values of every generation into a new one, then starts
+    /// another for the writes, and retires the old generations.
+    fn compact(&self) -> Result<()> {
+        let mut current_gen = self.current_gen.lock().unwrap();
+        let compaction_gen = *current_gen + 1;
+        let selected: Vec<u64> = self.files.lock().unwrap().keys().cloned().collect();
+        *current_gen += 2;
+        self.files.lock().unwrap().insert(*current_gen, Vec::new());
+        self.live_gens.insert(*current_gen);
+
+        // the writer lock keeps the index from changing meanwhile
+        let moved: Vec<(&'static str, Pos)> = self
+            .index
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&key, &pos)| (key, pos))
+            .collect();
+        let mut file = Vec::new();
+        let mut new_positions = Vec::new();
+        for (key, (gen, slot)) in moved {
+            let value = self.files.lock().unwrap()[&gen][slot];
+            new_positions.push((key, (compaction_gen, file.len())));
+            file.push(value);
+        }
+        self.files.lock().unwrap().insert(compaction_gen, file);
+        self.live_gens.insert(compaction_gen);
+        // publish the new positions, one key at a time
+        for (key, pos) in new_positions {
+            self.index.lock().unwrap().insert(key, pos);
+        }
+        for gen in selected {
+            self.live_gens.retire(gen, || {
+                self.files.lock().unwrap().remove(&gen);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// `read_latest` racing a `retire` reads the value from either generation.
+#[test]
+fn live_gens_read_during_retire() {
+    loom::model(|| {
+        let store = Arc::new(Store::new());
+        let compaction = {
+            let store = Arc::clone(&store);
+            thread::spawn(move || store.compact().unwrap())
+        };
+        match store.get("a") {
+            Ok(value) => assert_eq!(value, Some("v1")),
+            Err(KvsError::IO(e)) => panic!("read a removed generation: {}", e),
+            Err(e) => panic!("{}", e),
+        }
+        compaction.join().unwrap();
+        assert_eq!(store.get("a").unwrap(), Some("v1"));
+    });
+}
+
+/// `read_latest` racing a new position and a `retire` never goes back to the
+/// old value once it has seen the new one.
+#[test]
+fn live_gens_read_during_publish_and_retire() {
+    loom::model(|| {
+        let store = Arc::new(Store::new());
+        let writes = {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                store.set("a", "v2");
+                store.compact().unwrap();
+            })
+        };
+        let first = store.get("a").unwrap();
+        let second = store.get("a").unwrap();
+        assert!(first == Some("v1") || first == Some("v2"));
+        if first == Some("v2") {
+            assert_eq!(second, Some("v2"), "read a stale value");
+        }
+        writes.join().unwrap();
+        assert_eq!(store.get("a").unwrap(), Some("v2"));
+    });
+}
+
+/// `read_latest` racing two rounds of `retire` still finds the value.
+#[test]
+fn live_gens_read_during_two_retires() {
+    loom::model(|| {
+        let store = Arc::new(Store::new());
+        let compactions = {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                store.compact().unwrap();
+                store.compact().unwrap();
+            })
+        };
+        assert_eq!(store.get("a").unwrap(), Some("v1"));
+        compactions.join().unwrap();
+    });
+}
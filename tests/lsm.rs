@@ -0,0 +1,126 @@
+use kvs::{KvsEngine, LsmKvsEngine, LsmOptions, Result};
+use rand::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::thread;
+use tempfile::TempDir;
+
+fn small_options() -> LsmOptions {
+    LsmOptions::default()
+        .memtable_size(1024)
+        .block_size(128)
+        .table_size(2048)
+        .level0_limit(2)
+        .level_base_size(4096)
+}
+
+// Random writes should read back like a `BTreeMap`, across flushes,
+// compactions into several levels and reopening.
+#[test]
+fn matches_model() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = LsmKvsEngine::open_with_options(temp_dir.path(), small_options())?;
+    let mut model = BTreeMap::new();
+    let mut rng = SmallRng::from_seed([7; 16]);
+
+    for round in 0..4 {
+        for i in 0..2000 {
+            let key = format!("key{}", rng.gen_range(0, 500));
+            if rng.gen_range(0, 4) == 0 {
+                let expected = model.remove(&key).is_some();
+                assert_eq!(engine.remove(key).is_ok(), expected);
+            } else {
+                let value = format!("value{}_{}", round, i);
+                engine.set(key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+        }
+        for i in 0..500 {
+            let key = format!("key{}", i);
+            assert_eq!(engine.get(key.clone())?, model.get(&key).cloned());
+        }
+        drop(engine);
+        engine = LsmKvsEngine::open_with_options(temp_dir.path(), small_options())?;
+    }
+
+    let counts = engine.table_counts();
+    assert!(counts.len() > 2, "expected several levels: {:?}", counts);
+    assert!(counts[0] <= 2);
+    for i in 0..500 {
+        let key = format!("key{}", i);
+        assert_eq!(engine.get(key.clone())?, model.get(&key).cloned());
+    }
+    Ok(())
+}
+
+// A write torn by a crash at the end of the log should be dropped on open.
+#[test]
+fn torn_wal_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    drop(engine);
+
+    let wal = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("wal".as_ref()))
+        .expect("no write-ahead log");
+    OpenOptions::new()
+        .append(true)
+        .open(&wal)?
+        .write_all(b"[\"key3\",\"val")?;
+
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    drop(engine);
+
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Writers filling memtables concurrently should each read their writes back
+// while others flush and compact, and find them all after reopening.
+#[test]
+fn concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = small_options().sync_writes(true);
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), options.clone())?;
+
+    let writers: Vec<_> = (0..4)
+        .map(|thread| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..300 {
+                    let key = format!("key{}_{}", thread, i);
+                    engine.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(engine.get(key)?, Some(format!("value{}", i)));
+                    if i % 3 == 0 {
+                        engine.remove(format!("key{}_{}", thread, i / 3))?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    assert!(engine.table_counts().iter().sum::<usize>() > 1);
+
+    drop(engine);
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), options)?;
+    for thread in 0..4 {
+        for i in 0..300 {
+            // every third write removed one of the first 100 keys
+            let expected = if i < 100 { None } else { Some(format!("value{}", i)) };
+            assert_eq!(engine.get(format!("key{}_{}", thread, i))?, expected);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use kvs::{KvsEngine, KvsError, MemoryKvsEngine, Result};
+use tempfile::TempDir;
+
+// Writes growing the engine past its size limit should be refused.
+#[test]
+fn size_limit() -> Result<()> {
+    let engine = MemoryKvsEngine::with_size_limit(20);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    match engine.set("key2".to_owned(), "a long value2".to_owned()) {
+        Err(KvsError::CapacityExceeded(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    // Shrinking an existing value or removing a key frees space
+    engine.set("key1".to_owned(), "v1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// The snapshot should be written when the last clone is dropped and
+// restored by the next engine.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("snapshot");
+
+    let engine = MemoryKvsEngine::with_snapshot(&path, None)?;
+    let clone = engine.clone();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    clone.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    drop(engine);
+    assert!(!path.exists());
+    drop(clone);
+    assert!(path.exists());
+
+    let engine = MemoryKvsEngine::with_snapshot(&path, None)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // An explicit snapshot can be taken at any time
+    let other = temp_dir.path().join("other");
+    engine.snapshot(&other)?;
+    let restored = MemoryKvsEngine::with_snapshot(&other, Some(1))?;
+    assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Closing the last clone should write the snapshot, and report a failure
+// to write it.
+#[test]
+fn close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("snapshot");
+
+    let engine = MemoryKvsEngine::with_snapshot(&path, None)?;
+    let clone = engine.clone();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    clone.close()?;
+    assert!(!path.exists());
+    engine.close()?;
+    let engine = MemoryKvsEngine::with_snapshot(&path, None)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.close()?;
+
+    // the directory of the snapshot is gone
+    let path = temp_dir.path().join("missing").join("snapshot");
+    let engine = MemoryKvsEngine::with_snapshot(&path, None)?;
+    assert!(engine.close().is_err());
+    Ok(())
+}
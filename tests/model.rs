@@ -0,0 +1,178 @@
+//! Model-based tests: random sequences of operations run on every engine and
+//! on a `BTreeMap`, which must give the same results. Proptest shrinks a
+//! failing sequence to a minimal one.
+
+use kvs::{
+    conformance, KvStore, KvStoreOptions, KvsEngine, KvsError, LsmKvsEngine, LsmOptions,
+    MemoryFileSystem, MemoryKvsEngine, Result, ShardedEngine, SledKvsEngine,
+};
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseResult;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tempfile::TempDir;
+
+// low enough that a few writes start a compaction
+const COMPACTION_THRESHOLD: u64 = 256;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Set(String, String),
+    Get(String),
+    Remove(String),
+    /// Drops the engine and opens it again.
+    Reopen,
+    Compact,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let key = (0..8u8).prop_map(|i| format!("key{}", i));
+    prop_oneof![
+        4 => (key.clone(), "[a-z]{0,64}").prop_map(|(key, value)| Op::Set(key, value)),
+        3 => key.clone().prop_map(Op::Get),
+        2 => key.prop_map(Op::Remove),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Compact),
+    ]
+}
+
+fn ops() -> impl Strategy<Value = Vec<Op>> {
+    prop::collection::vec(op(), 1..100)
+}
+
+fn ok<T>(res: Result<T>) -> std::result::Result<T, TestCaseError> {
+    res.map_err(|e| TestCaseError::fail(e.to_string()))
+}
+
+/// Runs `ops` on the engine opened by `open` in a new directory and on a
+/// model, checking that every result and the final contents agree.
+fn check_model<E, O, C>(ops: &[Op], open: O, compact: C) -> TestCaseResult
+where
+    E: KvsEngine,
+    O: Fn(&Path) -> Result<E>,
+    C: Fn(&E) -> Result<()>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = ok(open(temp_dir.path()))?;
+    let mut model = BTreeMap::new();
+    for op in ops {
+        match op {
+            Op::Set(key, value) => {
+                ok(engine.set(key.clone(), value.clone()))?;
+                model.insert(key.clone(), value.clone());
+            }
+            Op::Get(key) => {
+                let value = ok(engine.get(key.clone()))?;
+                prop_assert_eq!(value.as_ref(), model.get(key));
+            }
+            Op::Remove(key) => {
+                let removed = match engine.remove(key.clone()) {
+                    Ok(()) => true,
+                    Err(KvsError::KeyNotFound) => false,
+                    Err(e) => return Err(TestCaseError::fail(e.to_string())),
+                };
+                prop_assert_eq!(removed, model.remove(key).is_some());
+            }
+            Op::Reopen => {
+                drop(engine);
+                engine = ok(open(temp_dir.path()))?;
+            }
+            Op::Compact => ok(compact(&engine))?,
+        }
+    }
+    let contents: BTreeMap<_, _> = ok(engine.scan(..))?.into_iter().collect();
+    prop_assert_eq!(contents, model);
+    Ok(())
+}
+
+fn kv_store_options() -> KvStoreOptions {
+    KvStoreOptions::default().compaction_threshold(COMPACTION_THRESHOLD)
+}
+
+fn compact_kv_store(store: &KvStore) -> Result<()> {
+    store.compact()
+}
+
+/// For engines that compact on their own, or have nothing to compact.
+fn no_compaction<E>(_: &E) -> Result<()> {
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn kv_store(ops in ops()) {
+        check_model(
+            &ops,
+            |path| KvStore::open_with_options(path, kv_store_options()),
+            compact_kv_store,
+        )?;
+    }
+
+    #[test]
+    fn kv_store_persistent_index(ops in ops()) {
+        check_model(
+            &ops,
+            |path| KvStore::open_with_options(path, kv_store_options().index_memory_budget(256)),
+            compact_kv_store,
+        )?;
+    }
+
+    #[test]
+    fn kv_store_in_memory(ops in ops()) {
+        let fs = MemoryFileSystem::new();
+        check_model(
+            &ops,
+            |path| KvStore::open_with_options(path, kv_store_options().file_system(fs.clone())),
+            compact_kv_store,
+        )?;
+    }
+
+    #[test]
+    fn memory_engine(ops in ops()) {
+        check_model(
+            &ops,
+            |path| MemoryKvsEngine::with_snapshot(path.join("snapshot"), None),
+            no_compaction,
+        )?;
+    }
+
+    #[test]
+    fn lsm_engine(ops in ops()) {
+        let options = LsmOptions::default()
+            .memtable_size(256)
+            .block_size(64)
+            .table_size(512)
+            .level0_limit(2)
+            .level_base_size(1024);
+        check_model(
+            &ops,
+            |path| LsmKvsEngine::open_with_options(path, options.clone()),
+            no_compaction,
+        )?;
+    }
+
+    #[test]
+    fn sharded_kv_store(ops in ops()) {
+        check_model(
+            &ops,
+            |path| {
+                ShardedEngine::open(path, 4, |path| KvStore::open_with_options(path, kv_store_options()))
+            },
+            |engine| engine.shards().iter().try_for_each(KvStore::compact),
+        )?;
+    }
+}
+
+proptest! {
+    // sled is slow to open, so it gets fewer cases
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn sled_engine(ops in ops()) {
+        check_model(
+            &ops,
+            |path| Ok(SledKvsEngine::new(conformance::open_sled(path)?)),
+            no_compaction,
+        )?;
+    }
+}
@@ -0,0 +1,98 @@
+use kvs::{KvStore, KvsEngine, KvsError, MemoryKvsEngine, Result, ShardedEngine};
+use std::fs;
+use tempfile::TempDir;
+
+// Keys should spread across all shards, each in a directory of its own.
+#[test]
+fn spreads_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = ShardedEngine::open(temp_dir.path(), 4, |_| Ok(MemoryKvsEngine::new()))?;
+    for i in 0..1000 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for shard in engine.shards() {
+        let keys = shard.scan(..)?.len();
+        assert!(keys > 150 && keys < 350, "unbalanced shard with {} keys", keys);
+    }
+    for i in 0..4 {
+        assert!(temp_dir.path().join(format!("shard-{}", i)).is_dir());
+    }
+    Ok(())
+}
+
+// A directory should only be reopened with the shard count it was created with.
+#[test]
+fn shard_count_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = ShardedEngine::open(temp_dir.path(), 4, |path| KvStore::open(path))?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+
+    match ShardedEngine::open(temp_dir.path(), 3, |path| KvStore::open(path)) {
+        Err(KvsError::ShardCountMismatch(_)) => {}
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Opened with the wrong shard count"),
+    }
+    assert!(ShardedEngine::open(temp_dir.path(), 0, |path| KvStore::open(path)).is_err());
+
+    let engine = ShardedEngine::open(temp_dir.path(), 4, |path| KvStore::open(path))?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A directory holding an unsharded engine should not be split into shards,
+// as its keys would no longer be found.
+#[test]
+fn refuses_unsharded_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    for &shards in &[1, 4] {
+        match ShardedEngine::open(temp_dir.path(), shards, |path| KvStore::open(path)) {
+            Err(KvsError::ShardCountMismatch(_)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Sharded a directory holding an unsharded engine"),
+        }
+    }
+    assert!(!temp_dir.path().join("shards").exists());
+    assert!(!temp_dir.path().join("shard-0").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Keyspaces should end up in every shard or none, even if some shards fail.
+#[test]
+fn keyspaces_in_part() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = ShardedEngine::open(temp_dir.path(), 4, |path| KvStore::open(path))?;
+
+    // a keyspace only some shards have is created in the rest
+    engine.shards()[1].create_keyspace("users")?;
+    assert_eq!(engine.keyspaces()?, vec!["users".to_owned()]);
+    engine.create_keyspace("users")?;
+    for shard in engine.shards() {
+        assert_eq!(shard.keyspaces()?, vec!["users".to_owned()]);
+    }
+
+    // a failed creation is undone in the shards that succeeded
+    let blocked = temp_dir.path().join("shard-2").join("keyspaces");
+    fs::create_dir_all(&blocked)?;
+    fs::write(blocked.join("orders"), "in the way")?;
+    assert!(engine.create_keyspace("orders").is_err());
+    assert_eq!(engine.keyspaces()?, vec!["users".to_owned()]);
+    fs::remove_file(blocked.join("orders"))?;
+
+    // a drop left in part is finished by dropping again
+    engine.shards()[0].drop_keyspace("users")?;
+    assert_eq!(engine.keyspaces()?, vec!["users".to_owned()]);
+    engine.drop_keyspace("users")?;
+    assert!(engine.keyspaces()?.is_empty());
+    match engine.drop_keyspace("users") {
+        Err(KvsError::KeyspaceNotFound(_)) => {}
+        res => panic!("expected KeyspaceNotFound, got {:?}", res.map_err(|e| e.to_string())),
+    }
+    Ok(())
+}
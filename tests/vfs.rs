@@ -0,0 +1,158 @@
+use kvs::{DiskFileSystem, FileSystem, MemoryFileSystem, Result};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Checks the file operations the store relies on below `root`.
+fn check_file_system(fs: &dyn FileSystem, root: &Path) -> Result<()> {
+    let dir = root.join("a").join("b");
+    fs.create_dir_all(&dir)?;
+    assert!(fs.is_dir(&dir));
+    assert!(fs.create(&root.join("missing").join("file")).is_err());
+
+    let path = dir.join("file");
+    let mut file = fs.append(&path)?;
+    file.write_all(b"hello")?;
+    file.sync()?;
+    let mut file = fs.append(&path)?;
+    file.write_all(b" world")?;
+    assert_eq!(fs.read(&path)?, b"hello world");
+    assert!(fs.create_new(&path).is_err());
+
+    let mut file = fs.open(&path)?;
+    let mut buf = [0; 5];
+    file.read_exact(&mut buf[..2])?;
+    assert_eq!(file.read_at(&mut buf, 6)?, 5);
+    assert_eq!(&buf, b"world");
+    // reading at an offset leaves the position alone
+    if cfg!(unix) {
+        let mut rest = String::new();
+        file.read_to_string(&mut rest)?;
+        assert_eq!(rest, "llo world");
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    assert_eq!(content, "hello world");
+    assert!(file.write_all(b"!").is_err());
+
+    fs.append(&path)?.set_len(5)?;
+    assert_eq!(fs.read(&path)?, b"hello");
+    fs.create(&path)?.write_all(b"new")?;
+    assert_eq!(fs.read(&path)?, b"new");
+
+    let renamed = root.join("a").join("renamed");
+    fs.rename(&path, &renamed)?;
+    fs.sync_dir(&root.join("a"))?;
+    assert!(!fs.exists(&path));
+    assert!(fs.is_file(&renamed));
+    let mut listed = fs.list(&root.join("a"))?;
+    listed.sort();
+    assert_eq!(listed, vec![dir.clone(), renamed.clone()]);
+
+    fs.remove_file(&renamed)?;
+    assert!(fs.remove_file(&renamed).is_err());
+    assert!(fs.open(&renamed).is_err());
+    fs.create(&dir.join("file"))?;
+    fs.remove_dir_all(&root.join("a"))?;
+    assert!(!fs.exists(&dir.join("file")));
+    assert!(!fs.exists(&root.join("a")));
+    assert!(fs.list(&root.join("a")).is_err());
+    assert!(fs.available_space(root)? > 0);
+    Ok(())
+}
+
+#[test]
+fn disk_file_system() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_file_system(&DiskFileSystem, temp_dir.path())
+}
+
+#[test]
+fn memory_file_system() -> Result<()> {
+    let fs = MemoryFileSystem::new();
+    check_file_system(&fs, Path::new("/root"))?;
+    check_file_system(&fs, Path::new("relative"))?;
+
+    // handles opened before a removal still read the file
+    fs.create(Path::new("file"))?.write_all(b"kept")?;
+    let mut file = fs.open(Path::new("file"))?;
+    fs.remove_file(Path::new("file"))?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    assert_eq!(content, "kept");
+    Ok(())
+}
+
+#[test]
+fn memory_file_system_crash() -> Result<()> {
+    let fs = MemoryFileSystem::new();
+    let (synced, appended, rewritten) =
+        (Path::new("synced"), Path::new("appended"), Path::new("rewritten"));
+    let mut file = fs.create(synced)?;
+    file.write_all(b"safe")?;
+    file.sync()?;
+    let mut stale = fs.append(appended)?;
+    stale.write_all(b"abc")?;
+    stale.sync()?;
+    stale.write_all(b"defg")?;
+    let mut file = fs.create(rewritten)?;
+    file.write_all(b"old")?;
+    file.sync()?;
+    file.set_len(1)?;
+    file.write_all(b"new")?;
+    fs.sync_dir(Path::new(""))?;
+
+    fs.crash(|path, unsynced| {
+        if path == appended {
+            assert_eq!(unsynced, 4);
+        }
+        2
+    });
+    assert_eq!(fs.read(synced)?, b"safe");
+    assert_eq!(fs.read(appended)?, b"abcde");
+    // the synced content was overwritten, so none of the new one is kept
+    assert_eq!(fs.read(rewritten)?, b"old");
+
+    // a handle opened before the crash no longer reaches the file
+    stale.write_all(b"lost")?;
+    assert_eq!(fs.read(appended)?, b"abcde");
+    Ok(())
+}
+
+// Files are only created, renamed or removed for good once their directory
+// is synced.
+#[test]
+fn memory_file_system_crash_dir() -> Result<()> {
+    let fs = MemoryFileSystem::new();
+    let dir = Path::new("dir");
+    fs.create_dir_all(dir)?;
+    let (kept, renamed, created) = (dir.join("kept"), dir.join("renamed"), dir.join("created"));
+    let mut file = fs.create(&kept)?;
+    file.write_all(b"kept")?;
+    file.sync()?;
+    fs.sync_dir(dir)?;
+
+    fs.rename(&kept, &renamed)?;
+    let mut file = fs.create(&created)?;
+    file.write_all(b"created")?;
+    file.sync()?;
+    fs.crash(|_, unsynced| unsynced);
+    assert_eq!(fs.read(&kept)?, b"kept");
+    assert!(!fs.exists(&renamed));
+    assert!(!fs.exists(&created));
+
+    fs.rename(&kept, &renamed)?;
+    fs.sync_dir(dir)?;
+    fs.remove_file(&renamed)?;
+    fs.crash(|_, unsynced| unsynced);
+    assert!(!fs.exists(&kept));
+    assert_eq!(fs.read(&renamed)?, b"kept");
+
+    fs.remove_file(&renamed)?;
+    fs.sync_dir(dir)?;
+    fs.crash(|_, unsynced| unsynced);
+    assert!(fs.list(dir)?.is_empty());
+    assert!(fs.sync_dir(Path::new("missing")).is_err());
+    Ok(())
+}